@@ -0,0 +1,268 @@
+//! Memory access patterns for latency micro-benchmarks, e.g. to map out how latency changes as the
+//! working set outgrows each level of cache and the TLB.
+//!
+//! A pattern accesses a working set at the start of a `Region`, one _slot_ of `stride` bytes at a
+//! time. The slots are visited in order (`Strided`), at random (`Random`) or by following a random
+//! cycle of pointers stored in the slots themselves (`Chase`), so that each load depends on the
+//! previous one and neither the prefetchers nor out-of-order execution can hide the latency.
+//! `Sequential` visits every word of the working set in order.
+
+use std::{fmt, ptr};
+
+use rand::{seq::SliceRandom, Rng};
+
+use crate::{region::Region, timer::Stopwatch};
+
+/// The size of a word, which is the unit of every access.
+const WORD: usize = std::mem::size_of::<usize>();
+
+/// The order in which slots are accessed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessMode {
+    /// Every word of the working set, in order (the stride is ignored).
+    Sequential,
+
+    /// Every slot, in order.
+    Strided,
+
+    /// Uniformly random slots.
+    Random,
+
+    /// Dependent loads along a random cycle through all slots.
+    Chase,
+}
+
+impl AccessMode {
+    /// Parse a mode: `sequential`, `strided`, `random` or `chase`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "sequential" => Ok(AccessMode::Sequential),
+            "strided" => Ok(AccessMode::Strided),
+            "random" => Ok(AccessMode::Random),
+            "chase" => Ok(AccessMode::Chase),
+            _ => Err(format!(
+                "Not a valid access mode (expected sequential, strided, random or chase): {}",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for AccessMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            AccessMode::Sequential => "sequential",
+            AccessMode::Strided => "strided",
+            AccessMode::Random => "random",
+            AccessMode::Chase => "chase",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A clap validator for access modes.
+pub fn is_access_mode(arg: String) -> Result<(), String> {
+    AccessMode::parse(&arg).map(|_| ())
+}
+
+/// Whether accesses load or store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+
+    /// In `Chase` mode, each pointer is loaded and then stored back.
+    Write,
+}
+
+/// An access pattern over a working set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccessPattern {
+    pub mode: AccessMode,
+    pub kind: AccessKind,
+
+    /// The size of the working set in bytes.
+    pub working_set: usize,
+
+    /// The size of a slot in bytes: a multiple of the word size.
+    pub stride: usize,
+}
+
+impl AccessPattern {
+    /// Check that the stride and working set make sense together.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.stride == 0 || !self.stride.is_multiple_of(WORD) {
+            return Err(format!(
+                "The stride must be a non-zero multiple of {} bytes: {}",
+                WORD, self.stride
+            ));
+        }
+        if self.working_set < self.stride {
+            return Err(format!(
+                "The working set ({} bytes) must hold at least one stride ({} bytes)",
+                self.working_set, self.stride
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// The number of distinct locations accessed.
+    pub fn slots(&self) -> usize {
+        match self.mode {
+            AccessMode::Sequential => self.working_set / WORD,
+            _ => self.working_set / self.stride,
+        }
+    }
+
+    /// The distance between consecutive locations.
+    fn step(&self) -> usize {
+        match self.mode {
+            AccessMode::Sequential => WORD,
+            _ => self.stride,
+        }
+    }
+
+    /// Fill the working set and bring it into the caches and TLB: in `Chase` mode, link the slots
+    /// into a random cycle; otherwise, touch every slot once.
+    ///
+    /// # Panics
+    ///
+    /// If the region is smaller than the working set.
+    pub fn prepare(&self, region: &Region, rng: &mut impl Rng) {
+        assert!(region.len() >= self.working_set);
+        let base = region.as_ptr();
+
+        if self.mode == AccessMode::Chase {
+            let mut order: Vec<usize> = (0..self.slots()).collect();
+            order.shuffle(rng);
+
+            for (i, &slot) in order.iter().enumerate() {
+                let next = order[(i + 1) % order.len()];
+                unsafe {
+                    let next = base.add(next * self.stride);
+                    ptr::write_volatile(base.add(slot * self.stride) as *mut *mut u8, next);
+                }
+            }
+        } else {
+            for slot in 0..self.slots() {
+                unsafe {
+                    ptr::write_volatile(base.add(slot * self.step()) as *mut usize, slot);
+                }
+            }
+        }
+    }
+
+    /// Make `n` accesses, timing each with `stopwatch`. `sample(i, cycles)` is called after the
+    /// `i`-th access, outside of the timed section.
+    ///
+    /// The region must have been `prepare`d with this pattern.
+    pub fn run(
+        &self,
+        region: &Region,
+        n: usize,
+        stopwatch: &Stopwatch,
+        rng: &mut impl Rng,
+        mut sample: impl FnMut(usize, u64),
+    ) {
+        assert!(region.len() >= self.working_set);
+        let base = region.as_ptr();
+        let slots = self.slots();
+        let step = self.step();
+
+        let access = |addr: *mut u8, i: usize| unsafe {
+            match self.kind {
+                AccessKind::Read => {
+                    ptr::read_volatile(addr as *const usize);
+                }
+                AccessKind::Write => ptr::write_volatile(addr as *mut usize, i),
+            }
+        };
+
+        match self.mode {
+            AccessMode::Sequential | AccessMode::Strided => {
+                for i in 0..n {
+                    let addr = unsafe { base.add((i % slots) * step) };
+
+                    let start = stopwatch.start();
+                    access(addr, i);
+                    let cycles = stopwatch.stop(start);
+
+                    sample(i, cycles);
+                }
+            }
+
+            AccessMode::Random => {
+                for i in 0..n {
+                    let addr = unsafe { base.add(rng.gen_range(0, slots) * step) };
+
+                    let start = stopwatch.start();
+                    access(addr, i);
+                    let cycles = stopwatch.stop(start);
+
+                    sample(i, cycles);
+                }
+            }
+
+            AccessMode::Chase => {
+                let mut p = base as *mut *mut u8;
+                for i in 0..n {
+                    let start = stopwatch.start();
+                    let next = unsafe { ptr::read_volatile(p) };
+                    if self.kind == AccessKind::Write {
+                        unsafe { ptr::write_volatile(p, next) };
+                    }
+                    let cycles = stopwatch.stop(start);
+
+                    p = next as *mut *mut u8;
+                    sample(i, cycles);
+                }
+            }
+        }
+    }
+}
+
+/// Parse a size in bytes, with an optional binary suffix: `4096`, `32k`, `2m` or `4g`.
+pub fn parse_size(s: &str) -> Result<usize, String> {
+    let lower = s.to_lowercase();
+    let (digits, shift) = match lower.as_bytes().last() {
+        Some(b'k') => (&lower[..lower.len() - 1], 10),
+        Some(b'm') => (&lower[..lower.len() - 1], 20),
+        Some(b'g') => (&lower[..lower.len() - 1], 30),
+        _ => (&lower[..], 0),
+    };
+
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| format!("Not a valid size: {}", s))
+}
+
+/// A clap validator for sizes.
+pub fn is_size(arg: String) -> Result<(), String> {
+    parse_size(&arg).map(|_| ())
+}
+
+/// Parse a range of sizes, `MIN..MAX`, and return the sizes of a sweep over it: `MIN`, doubling
+/// until `MAX`, and `MAX` itself.
+pub fn parse_sweep(s: &str) -> Result<Vec<usize>, String> {
+    let (min, max) = s
+        .split_once("..")
+        .ok_or_else(|| format!("Not a valid sweep (expected MIN..MAX): {}", s))?;
+    let (min, max) = (parse_size(min)?, parse_size(max)?);
+    if min == 0 || min > max {
+        return Err(format!("Not a valid sweep (need 0 < MIN <= MAX): {}", s));
+    }
+
+    let mut sizes: Vec<usize> = std::iter::successors(Some(min), |&size| size.checked_mul(2))
+        .take_while(|&size| size < max)
+        .collect();
+    sizes.push(max);
+
+    Ok(sizes)
+}
+
+/// A clap validator for sweeps.
+pub fn is_sweep(arg: String) -> Result<(), String> {
+    parse_sweep(&arg).map(|_| ())
+}
@@ -0,0 +1,100 @@
+//! Report how fragmented physical memory is: the unusable free space index and the fragmentation
+//! index of every zone, for every order or for a given order. The output is in the same style as
+//! `/sys/kernel/debug/extfrag/{unusable_index,extfrag_index}`.
+//!
+//! With `--pagetypeinfo`, the indices are computed separately for each migrate type from
+//! `/proc/pagetypeinfo`, which usually requires root.
+
+use clap::clap_app;
+
+use paperexp::{
+    buddyinfo::{self, FreeAreas},
+    output::{is_format, Format, Output, Record},
+};
+
+/// Computes an index for a zone and order.
+type Index = fn(&FreeAreas, usize) -> f64;
+
+fn is_int(arg: String) -> Result<(), String> {
+    arg.to_string()
+        .parse::<usize>()
+        .map_err(|_| "Not a valid usize".to_owned())
+        .map(|_| ())
+}
+
+fn main() {
+    let matches = clap_app! { fragmentation =>
+        (@arg ORDER: --order +takes_value {is_int}
+         "Only report the indices for allocations of this order (default: all orders).")
+        (@arg PAGETYPEINFO: --pagetypeinfo
+         "Report the indices for each migrate type (from /proc/pagetypeinfo).")
+        (@arg FORMAT: --format +takes_value {is_format}
+         "The output format: `text` (default), `jsonl` or `csv`.")
+    }
+    .get_matches();
+
+    let order = matches
+        .value_of("ORDER")
+        .map(|o| o.parse::<usize>().unwrap());
+
+    let mut out = Output::stdout(Format::from_arg(matches.value_of("FORMAT")));
+
+    let zones = if matches.is_present("PAGETYPEINFO") {
+        buddyinfo::pagetypeinfo().map(|info| info.free)
+    } else {
+        buddyinfo::buddyinfo()
+    };
+    let zones = zones.unwrap_or_else(|e| {
+        eprintln!("unable to read free areas: {}", e);
+        std::process::exit(1);
+    });
+
+    let indices: [(&str, &str, Index); 2] = [
+        (
+            "unusable_index",
+            "unusable_index",
+            FreeAreas::unusable_index,
+        ),
+        (
+            "extfrag_index",
+            "fragmentation_index",
+            FreeAreas::fragmentation_index,
+        ),
+    ];
+
+    for &(title, metric, index) in indices.iter() {
+        out.text(format_args!("{}:", title)).unwrap();
+
+        for zone in zones.iter() {
+            let phase = match zone.migrate_type {
+                Some(ref migrate_type) => {
+                    format!("node{}.{}.{}", zone.node, zone.zone, migrate_type)
+                }
+                None => format!("node{}.{}", zone.node, zone.zone),
+            };
+
+            let orders = match order {
+                Some(order) => order..order + 1,
+                None => 0..zone.free.len(),
+            };
+
+            let mut line = format!("Node {}, zone {:>8}", zone.node, zone.zone);
+            if let Some(ref migrate_type) = zone.migrate_type {
+                line += &format!(", type {:>12}", migrate_type);
+            }
+
+            let mut records = vec![];
+            for order in orders {
+                let value = index(zone, order);
+                line += &format!(" {:.3}", value);
+                records.push((order, value));
+            }
+
+            let records: Vec<_> = records
+                .into_iter()
+                .map(|(order, value)| Record::new(&phase, metric, value).iteration(order))
+                .collect();
+            out.emit(format_args!("{}", line), &records).unwrap();
+        }
+    }
+}
@@ -1,17 +1,48 @@
 //! Grabs a bunch of memory and sits on it. This is not really a benchmark but more of just a
-//! utility.
+//! utility, e.g. to put memory pressure on the machine while something else runs.
+//!
+//! The initial SIZE pages are mapped as one region, and the hog grows by mapping more regions of at
+//! most `--chunk` pages each. It shrinks from the end, by unmapping memory or, for anonymous memory,
+//! by releasing it with `MADV_DONTNEED` or `MADV_FREE` (in which case it is touched again when the
+//! hog grows). It can be driven by commands, one per line, from stdin or a Unix socket:
+//!
+//! - `grow <pages>`, `shrink <pages> [munmap|dontneed|free]` and `resize <pages> [...]`
+//! - `touch`: write to every page again, e.g. to fault back in pages the kernel reclaimed.
+//! - `rss`: report the current size and RSS.
+//! - `quit`
+//!
+//! Socket clients get a line back for each command. The hog can also grow and shrink on `SIGUSR1`
+//! and `SIGUSR2`, or follow a schedule. It reports its size and RSS after every change.
+//!
+//! Unlike the benchmarks, there is no `--summary`: the hog times nothing, so there is nothing to
+//! summarize.
 
-use std::ptr;
-use std::time::Duration;
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, RecvTimeoutError, Sender},
+    },
+    time::{Duration, Instant},
+};
 
 use bmk_linux::resultarray::PAGE_SIZE;
 
 use clap::clap_app;
 
-use libc::{
-    mmap as libc_mmap, MAP_ANONYMOUS, MAP_FAILED, MAP_POPULATE, MAP_PRIVATE, PROT_READ, PROT_WRITE,
+use paperexp::{
+    clock,
+    output::{is_format, ClockSource, Format, Output, Record, Units},
+    pagemap::PageInspector,
+    region::{self, is_backing, is_huge_pages, Backing, HugePages, Region, RegionBuilder},
 };
 
+/// How often the main loop checks for signals and the schedule.
+const POLL: Duration = Duration::from_millis(100);
+
+const READY_PATH: &str = "/tmp/hog_ready";
+
 fn is_int(arg: String) -> Result<(), String> {
     arg.to_string()
         .parse::<usize>()
@@ -19,9 +50,400 @@ fn is_int(arg: String) -> Result<(), String> {
         .map(|_| ())
 }
 
+fn is_release(arg: String) -> Result<(), String> {
+    Release::parse(&arg).map(|_| ())
+}
+
+fn is_schedule(arg: String) -> Result<(), String> {
+    Schedule::parse(&arg).map(|_| ())
+}
+
+/// How to give memory back when shrinking.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum Release {
+    /// Unmap the chunks.
+    #[default]
+    Munmap,
+
+    /// `MADV_DONTNEED`: the pages are freed immediately.
+    DontNeed,
+
+    /// `MADV_FREE`: the kernel frees the pages lazily, when it is under pressure.
+    Free,
+}
+
+impl Release {
+    fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "munmap" => Ok(Release::Munmap),
+            "dontneed" => Ok(Release::DontNeed),
+            "free" => Ok(Release::Free),
+            _ => Err(format!(
+                "Not a valid release (expected munmap, dontneed or free): {}",
+                s
+            )),
+        }
+    }
+
+    /// Check that this works with memory with the given backing. Only `munmap` gives back shared
+    /// memory (`memfd` or a file): `MADV_DONTNEED` just drops the mappings, leaving the pages in the
+    /// page cache, and `MADV_FREE` fails outright.
+    fn check(self, backing: &Backing) -> Result<Self, String> {
+        if self != Release::Munmap && *backing != Backing::Anonymous {
+            Err("MADV_DONTNEED and MADV_FREE only release anonymous memory (--backing anon)".into())
+        } else {
+            Ok(self)
+        }
+    }
+}
+
+/// A command from stdin or the socket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Command {
+    Grow(usize),
+    Shrink(usize, Option<Release>),
+    Resize(usize, Option<Release>),
+    Touch,
+    Rss,
+    Quit,
+}
+
+impl Command {
+    fn parse(s: &str) -> Result<Self, String> {
+        let bad = || {
+            format!(
+                "Not a valid command (expected grow <pages>, shrink <pages> [<release>], \
+                 resize <pages> [<release>], touch, rss or quit): {}",
+                s
+            )
+        };
+        let pages = |n: Option<&str>| n.and_then(|n| n.parse().ok()).ok_or_else(bad);
+
+        let mut words = s.split_whitespace();
+        let command = match words.next().ok_or_else(bad)? {
+            "grow" => Command::Grow(pages(words.next())?),
+            "shrink" => Command::Shrink(
+                pages(words.next())?,
+                words.next().map(Release::parse).transpose()?,
+            ),
+            "resize" => Command::Resize(
+                pages(words.next())?,
+                words.next().map(Release::parse).transpose()?,
+            ),
+            "touch" => Command::Touch,
+            "rss" => Command::Rss,
+            "quit" => Command::Quit,
+            _ => return Err(bad()),
+        };
+
+        if words.next().is_some() {
+            return Err(bad());
+        }
+        Ok(command)
+    }
+}
+
+/// A size to follow over time, in pages.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Schedule {
+    /// Go linearly from `from` to `to` over `period`, then stay there.
+    Ramp {
+        from: usize,
+        to: usize,
+        period: Duration,
+    },
+
+    /// Go linearly from `min` to `max` over `period`, then drop back to `min` and repeat.
+    Sawtooth {
+        min: usize,
+        max: usize,
+        period: Duration,
+    },
+
+    /// Alternate between `min` and `max`, spending `period` at each.
+    Step {
+        min: usize,
+        max: usize,
+        period: Duration,
+    },
+}
+
+impl Schedule {
+    /// Parse a schedule: `ramp:<from>:<to>:<period>`, `sawtooth:<min>:<max>:<period>` or
+    /// `step:<min>:<max>:<period>`, with sizes in pages and a period with units (e.g. `30s`).
+    fn parse(s: &str) -> Result<Self, String> {
+        let bad = || {
+            format!(
+                "Not a valid schedule (expected ramp:<from>:<to>:<period>, \
+                 sawtooth:<min>:<max>:<period> or step:<min>:<max>:<period>): {}",
+                s
+            )
+        };
+
+        let parts: Vec<&str> = s.split(':').collect();
+        let (kind, a, b, period) = match parts.as_slice() {
+            [kind, a, b, period] => (
+                *kind,
+                a.parse().map_err(|_| bad())?,
+                b.parse().map_err(|_| bad())?,
+                clock::parse_duration(period)?,
+            ),
+            _ => return Err(bad()),
+        };
+        if period.is_zero() {
+            return Err(bad());
+        }
+
+        match kind {
+            "ramp" => Ok(Schedule::Ramp {
+                from: a,
+                to: b,
+                period,
+            }),
+            "sawtooth" => Ok(Schedule::Sawtooth {
+                min: a,
+                max: b,
+                period,
+            }),
+            "step" => Ok(Schedule::Step {
+                min: a,
+                max: b,
+                period,
+            }),
+            _ => Err(bad()),
+        }
+    }
+
+    /// The size, in pages, `elapsed` after the start.
+    fn target(&self, elapsed: Duration) -> usize {
+        let lerp =
+            |a: usize, b: usize, frac: f64| (a as f64 + (b as f64 - a as f64) * frac) as usize;
+
+        match *self {
+            Schedule::Ramp { from, to, period } => lerp(
+                from,
+                to,
+                (elapsed.as_secs_f64() / period.as_secs_f64()).min(1.),
+            ),
+            Schedule::Sawtooth { min, max, period } => lerp(
+                min,
+                max,
+                elapsed.as_secs_f64() % period.as_secs_f64() / period.as_secs_f64(),
+            ),
+            Schedule::Step { min, max, period } => {
+                if (elapsed.as_nanos() / period.as_nanos()).is_multiple_of(2) {
+                    min
+                } else {
+                    max
+                }
+            }
+        }
+    }
+}
+
+/// The memory being hogged: the initial region, followed by any regions mapped to grow.
+struct Hog {
+    huge_pages: HugePages,
+    backing: Backing,
+
+    /// The most memory mapped at a time when growing, in bytes.
+    chunk_len: usize,
+
+    regions: Vec<Region>,
+
+    /// The number of bytes in use, counting from the start of the first region. The memory past
+    /// that has been released with `madvise`.
+    len: usize,
+}
+
+impl Hog {
+    /// Map and populate the initial `pages`.
+    fn new(
+        pages: usize,
+        huge_pages: HugePages,
+        backing: Backing,
+        chunk_len: usize,
+    ) -> Result<Self, String> {
+        let mut hog = Hog {
+            huge_pages,
+            backing,
+            chunk_len,
+            regions: vec![],
+            len: 0,
+        };
+        let region = hog.map(pages * PAGE_SIZE)?;
+        hog.len = region.len();
+        hog.regions.push(region);
+        Ok(hog)
+    }
+
+    /// Map and populate a new region of `len` bytes.
+    fn map(&self, len: usize) -> Result<Region, String> {
+        RegionBuilder::new(len)
+            .huge_pages(self.huge_pages)
+            .backing(self.backing.clone())
+            .populate(true)
+            .map()
+            .map_err(|e| e.to_string())
+    }
+
+    /// The current size, in pages.
+    fn pages(&self) -> usize {
+        self.len / PAGE_SIZE
+    }
+
+    /// The size of `pages`, in bytes, rounded up to the page size of the regions.
+    fn bytes(&self, pages: usize) -> usize {
+        (pages * PAGE_SIZE).next_multiple_of(self.huge_pages.page_size())
+    }
+
+    /// The total length of the regions, including memory that has been released.
+    fn mapped(&self) -> usize {
+        self.regions.iter().map(|region| region.len()).sum()
+    }
+
+    /// Call `f(region, offset, len)` for the part of each region that is in `[start, end)`
+    /// (counting from the start of the first region).
+    fn each(
+        &self,
+        start: usize,
+        end: usize,
+        mut f: impl FnMut(&Region, usize, usize) -> Result<(), String>,
+    ) -> Result<(), String> {
+        let mut base = 0;
+        for region in self.regions.iter() {
+            let (lo, hi) = (start.max(base), end.min(base + region.len()));
+            if lo < hi {
+                f(region, lo - base, hi - lo)?;
+            }
+            base += region.len();
+        }
+        Ok(())
+    }
+
+    /// Grow or shrink to `pages`.
+    fn resize(&mut self, pages: usize, release: Release) -> Result<(), String> {
+        let target = self.bytes(pages);
+
+        if target > self.len {
+            // Fault released memory back in before mapping more.
+            let end = target.min(self.mapped());
+            self.each(self.len, end, |region, offset, len| {
+                region.touch(offset, len);
+                Ok(())
+            })?;
+            self.len = end;
+
+            while self.len < target {
+                let region = self.map((target - self.len).min(self.chunk_len))?;
+                self.len += region.len();
+                self.regions.push(region);
+            }
+        } else if target < self.len {
+            match release {
+                // Unmap everything past the target, including memory released earlier.
+                Release::Munmap => {
+                    let mut start = self.mapped();
+                    while let Some(region) = self.regions.last_mut() {
+                        start -= region.len();
+                        if start < target {
+                            region.truncate(target - start).map_err(|e| e.to_string())?;
+                            break;
+                        }
+                        self.regions.pop();
+                    }
+                }
+                Release::DontNeed | Release::Free => {
+                    let advice = if release == Release::DontNeed {
+                        libc::MADV_DONTNEED
+                    } else {
+                        libc::MADV_FREE
+                    };
+                    self.each(target, self.len, |region, offset, len| {
+                        region
+                            .advise(offset, len, advice)
+                            .map_err(|e| e.to_string())
+                    })?;
+                }
+            }
+            self.len = target;
+        }
+
+        Ok(())
+    }
+
+    /// Carry out `command` (anything but `Quit`), releasing memory with `release` unless the command
+    /// says otherwise.
+    fn apply(&mut self, command: Command, release: Release) -> Result<(), String> {
+        match command {
+            Command::Grow(pages) => self.resize(self.pages() + pages, release),
+            Command::Shrink(pages, r) => {
+                let release = r.unwrap_or(release).check(&self.backing)?;
+                self.resize(self.pages().saturating_sub(pages), release)
+            }
+            Command::Resize(pages, r) => {
+                let release = r.unwrap_or(release).check(&self.backing)?;
+                self.resize(pages, release)
+            }
+            Command::Touch => self.each(0, self.len, |region, offset, len| {
+                region.touch(offset, len);
+                Ok(())
+            }),
+            Command::Rss | Command::Quit => Ok(()),
+        }
+    }
+}
+
+/// A command line, and where to send the reply, if anywhere.
+struct Request {
+    line: String,
+    reply: Option<Sender<String>>,
+}
+
+/// The number of `SIGUSR1`s and `SIGUSR2`s received and not yet handled.
+static SIGNALS: [AtomicUsize; 2] = [AtomicUsize::new(0), AtomicUsize::new(0)];
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    let i = if signal == libc::SIGUSR1 { 0 } else { 1 };
+    SIGNALS[i].fetch_add(1, Ordering::SeqCst);
+}
+
 fn main() {
     let matches = clap_app! { hog =>
         (@arg SIZE: +required {is_int} "The number of pages to hog")
+        (@arg AUDIT: --audit
+         "Report how the memory is backed, page by page, from /proc/self/pagemap (see \
+          `page_audit`).")
+        (@arg USAGE: --usage
+         "Report how much of the memory is resident and backed by huge pages, from \
+          /proc/self/smaps.")
+        (@arg HUGE: --huge +takes_value {is_huge_pages}
+         "How to use huge pages: `default` (the system's THP settings), `thp` (MADV_HUGEPAGE), \
+          `nothp` (MADV_NOHUGEPAGE), `hugetlb:2m` or `hugetlb:1g`.")
+        (@arg BACKING: --backing +takes_value {is_backing}
+         "What backs the memory: `anon` (default), `memfd` or `file:<path>` (e.g. on tmpfs or \
+          hugetlbfs; a directory gets a new unlinked file).")
+        (@arg CHUNK: --chunk +takes_value {is_int}
+         "The most pages mapped at a time when growing (default: 512).")
+        (@arg RELEASE: --release +takes_value {is_release}
+         "How to give memory back when shrinking: `munmap` (default), `dontneed` (MADV_DONTNEED) \
+          or `free` (MADV_FREE). `dontneed` and `free` only work with `--backing anon`.")
+        (@arg STDIN: --stdin
+         "Read commands from stdin: `grow <pages>`, `shrink <pages> [<release>]`, \
+          `resize <pages> [<release>]`, `touch`, `rss` or `quit`.")
+        (@arg SOCKET: --socket +takes_value
+         "Accept commands (as for --stdin) on a Unix socket at the given path, replying to each \
+          with the new size and RSS.")
+        (@arg SIGNALS: --signals +takes_value {is_int}
+         "Grow by the given number of pages on SIGUSR1, and shrink by it on SIGUSR2.")
+        (@arg SCHEDULE: --schedule +takes_value {is_schedule}
+         "Follow a schedule, with sizes in pages, starting from SIZE: `ramp:<from>:<to>:<period>`, \
+          `sawtooth:<min>:<max>:<period>` or `step:<min>:<max>:<period>` (e.g. \
+          `step:1000:100000:30s`).")
+        (@arg INTERVAL: --interval +takes_value {is_int}
+         "How often to resize to follow the schedule, in milliseconds (default: 1000).")
+        (@arg FORMAT: --format +takes_value {is_format}
+         "The output format: `text` (default), `jsonl` or `csv`.")
     }
     .get_matches();
 
@@ -32,29 +454,233 @@ fn main() {
         .to_string()
         .parse::<usize>()
         .unwrap();
+    let release = matches
+        .value_of("RELEASE")
+        .map(|r| Release::parse(r).unwrap())
+        .unwrap_or_default();
+    let signal_step = matches
+        .value_of("SIGNALS")
+        .map(|n| n.parse::<usize>().unwrap());
+    let schedule = matches
+        .value_of("SCHEDULE")
+        .map(|s| Schedule::parse(s).unwrap());
+    let interval = Duration::from_millis(
+        matches
+            .value_of("INTERVAL")
+            .map_or(1000, |i| i.parse().unwrap()),
+    );
+
+    let mut out = Output::stdout(Format::from_arg(matches.value_of("FORMAT")));
 
     // Mmap memory for the experiment
-    let _ = unsafe {
-        let addr = libc_mmap(
-            ptr::null_mut(),
-            npages * PAGE_SIZE,
-            PROT_READ | PROT_WRITE,
-            MAP_PRIVATE | MAP_ANONYMOUS | MAP_POPULATE,
-            -1,
-            0,
-        );
+    let huge_pages = HugePages::from_arg(matches.value_of("HUGE"));
+    let backing = Backing::from_arg(matches.value_of("BACKING"));
+    let chunk_len = (matches
+        .value_of("CHUNK")
+        .map_or(512, |c| c.parse::<usize>().unwrap().max(1))
+        * PAGE_SIZE)
+        .next_multiple_of(huge_pages.page_size());
+    if let Err(e) = release.check(&backing) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    let mut hog = Hog::new(npages, huge_pages, backing, chunk_len).expect("Unable to mmap");
 
-        if addr == MAP_FAILED {
-            panic!("Unable to mmap");
-        }
+    // How much of the memory ended up huge?
+    let region = &hog.regions[0];
+    if matches.is_present("USAGE") {
+        region
+            .usage()
+            .expect("unable to read smaps")
+            .report(&mut out, "region", region.len())
+            .unwrap();
+    }
 
-        addr as *mut u8
-    };
+    // Where did the memory land?
+    if matches.is_present("AUDIT") {
+        PageInspector::open(None)
+            .and_then(|inspector| inspector.summarize(region.as_ptr() as usize, region.len()))
+            .expect("unable to audit pages")
+            .report(&mut out, "audit")
+            .unwrap();
+    }
+
+    let begin = Instant::now();
+    report(&mut out, &hog, "start", begin).unwrap();
+    out.flush().unwrap();
+
+    // Listen for commands. The sender is kept, so that the channel stays open without any.
+    let (tx, rx) = mpsc::channel();
+
+    if matches.is_present("STDIN") {
+        let tx = tx.clone();
+        std::thread::spawn(move || {
+            for line in std::io::stdin().lock().lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(_) => break,
+                };
+                if tx.send(Request { line, reply: None }).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    if let Some(path) = matches.value_of("SOCKET") {
+        // Remove a socket left over from a previous run.
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path).expect("unable to bind socket");
+        let tx = tx.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let tx = tx.clone();
+                std::thread::spawn(move || serve(stream, tx));
+            }
+        });
+    }
+
+    if signal_step.is_some() {
+        for &signal in [libc::SIGUSR1, libc::SIGUSR2].iter() {
+            let handler = on_signal as extern "C" fn(libc::c_int);
+            if unsafe { libc::signal(signal, handler as libc::sighandler_t) } == libc::SIG_ERR {
+                panic!("unable to set signal handler");
+            }
+        }
+    }
 
     // Notify the world that we are ready.
-    let _ = std::fs::File::create("/tmp/hog_ready").expect("unable to notify");
+    let _ = std::fs::File::create(READY_PATH).expect("unable to notify");
+
+    ///////////////////////////////////////////////////////////////////////////
+    // Hog
+    ///////////////////////////////////////////////////////////////////////////
+
+    let mut next_step = begin;
 
     loop {
-        std::thread::sleep(Duration::from_secs(1));
+        match rx.recv_timeout(POLL) {
+            Ok(Request { line, reply }) => {
+                let command = Command::parse(&line);
+                let response = command
+                    .clone()
+                    .and_then(|command| hog.apply(command, release))
+                    .map(|()| report(&mut out, &hog, line.trim(), begin).unwrap());
+                out.flush().unwrap();
+
+                match (reply, response) {
+                    (Some(reply), response) => {
+                        let _ = reply.send(response.unwrap_or_else(|e| format!("error: {}", e)));
+                    }
+                    (None, Err(e)) => eprintln!("error: {}", e),
+                    (None, Ok(_)) => {}
+                }
+
+                if command == Ok(Command::Quit) {
+                    break;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => unreachable!(),
+        }
+
+        if let Some(step) = signal_step {
+            for (i, &(name, grow)) in [("SIGUSR1", true), ("SIGUSR2", false)].iter().enumerate() {
+                for _ in 0..SIGNALS[i].swap(0, Ordering::SeqCst) {
+                    let command = if grow {
+                        Command::Grow(step)
+                    } else {
+                        Command::Shrink(step, None)
+                    };
+                    match hog.apply(command, release) {
+                        Ok(()) => {
+                            report(&mut out, &hog, name, begin).unwrap();
+                        }
+                        Err(e) => eprintln!("error: {}", e),
+                    }
+                }
+            }
+            out.flush().unwrap();
+        }
+
+        if let Some(schedule) = schedule {
+            if Instant::now() >= next_step {
+                let target = schedule.target(begin.elapsed());
+                if hog.bytes(target) != hog.len {
+                    match hog.resize(target, release) {
+                        Ok(()) => {
+                            report(&mut out, &hog, "schedule", begin).unwrap();
+                        }
+                        Err(e) => eprintln!("error: {}", e),
+                    }
+                    out.flush().unwrap();
+                }
+                next_step += interval;
+            }
+        }
+    }
+
+    let _ = std::fs::remove_file(READY_PATH);
+    if let Some(path) = matches.value_of("SOCKET") {
+        let _ = std::fs::remove_file(path);
     }
 }
+
+/// Serve commands from one socket client until it hangs up.
+fn serve(stream: UnixStream, tx: Sender<Request>) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(_) => return,
+    };
+
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+
+        let (reply, response) = mpsc::channel();
+        if tx
+            .send(Request {
+                line,
+                reply: Some(reply),
+            })
+            .is_err()
+        {
+            break;
+        }
+        let response = match response.recv() {
+            Ok(response) => response,
+            Err(_) => break,
+        };
+        if writeln!(writer, "{}", response).is_err() {
+            break;
+        }
+    }
+}
+
+/// Report the size and RSS after `what` (a command, signal or the schedule), and return them as a
+/// reply for socket clients.
+fn report(out: &mut Output, hog: &Hog, what: &str, begin: Instant) -> std::io::Result<String> {
+    let rss = match region::rss_kbs() {
+        Ok(rss) => rss,
+        Err(e) => {
+            eprintln!("unable to read RSS: {}", e);
+            0
+        }
+    };
+    let elapsed = begin.elapsed().as_nanos() as u64;
+
+    out.emit(
+        format_args!("{}: {} pages, RSS: {} kB", what, hog.pages(), rss),
+        &[
+            Record::new("hog", "elapsed", elapsed)
+                .clock(ClockSource::Monotonic)
+                .units(Units::Nanoseconds),
+            Record::new("hog", "size", hog.pages()).units(Units::Pages),
+            Record::new("hog", "rss", rss).units(Units::Kilobytes),
+        ],
+    )?;
+
+    Ok(format!("size {} rss {}", hog.pages(), rss))
+}
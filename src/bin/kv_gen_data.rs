@@ -0,0 +1,301 @@
+//! Sits in a loop doing `put` operations on the given memcached or redis instance. The keys are
+//! unique, and the values are large. By default, values are all zeros, but `--payload` and
+//! `--size_dist` can be used to choose their contents and sizes.
+//!
+//! After the store is filled, `--ops` operations can be run with a configurable mix of reads,
+//! updates, inserts and deletes (`--mix`) on keys chosen from a configurable distribution
+//! (`--keys`), as in YCSB. Then, `--gets` keys can be read back. With `--latency` (or `--summary`,
+//! as in the other benchmarks), the latency of every operation is recorded and percentiles are
+//! reported at the end of each phase. Latencies go into a `Histogram`, so memory does not grow with
+//! `--ops`, but unlike elsewhere outliers cannot be trimmed.
+//!
+//! The defaults reproduce the old `memcached_gen_data` and `redis_gen_data` tools:
+//! - `kv_gen_data memcached <IP:PORT> <SIZE>` uses 523800B values and retries failed `put`s until
+//!   the connection breaks.
+//! - `kv_gen_data redis <URL> <SIZE>` uses 512KB values and reconnects and retries once.
+//!
+//! NOTE: This should be run from a machine that has a high-bandwidth, low-latency connection with
+//! the test machine.
+//!
+//! NOTE: The server should be started and configured already (e.g. `memcached -M -m 50000` for
+//! 50GB).
+
+use std::time::Duration;
+
+use clap::clap_app;
+
+use paperexp::{
+    calibration::Calibration,
+    clock::{self, TscClock},
+    hypervisor::{is_backend, Backend, Hypervisor},
+    keys::{is_key_distribution, is_op_mix, KeyDistribution, OpMix},
+    kv::{is_store, Driver, KvBackend, MemcachedBackend, RedisBackend},
+    output::{is_format, Format, Output},
+    payload::{is_pattern, is_size_distribution, Pattern, PayloadGenerator, SizeDistribution},
+    sampler::{is_sources, Sampler, Source},
+};
+
+fn is_addr(arg: String) -> Result<(), String> {
+    use std::net::ToSocketAddrs;
+
+    arg.to_socket_addrs()
+        .map_err(|_| "Not a valid IP:Port".to_owned())
+        .map(|_| ())
+}
+
+fn is_int(arg: String) -> Result<(), String> {
+    arg.to_string()
+        .parse::<usize>()
+        .map_err(|_| "Not a valid usize".to_owned())
+        .map(|_| ())
+}
+
+fn is_positive(arg: String) -> Result<(), String> {
+    match arg.parse::<usize>() {
+        Ok(0) => Err("Must be positive".to_owned()),
+        Ok(_) => Ok(()),
+        Err(_) => Err("Not a valid usize".to_owned()),
+    }
+}
+
+fn run<B: KvBackend>(
+    backend: Result<B, B::Error>,
+    driver: &Driver,
+    payload: &mut PayloadGenerator,
+    out: &mut Output,
+) -> Result<(), B::Error> {
+    let mut backend = backend?;
+    driver.fill(&mut backend, payload, out)?;
+    if driver.nops > 0 {
+        driver.run(&mut backend, payload, out)?;
+    }
+    if driver.ngets > 0 {
+        driver.get(&mut backend, out)?;
+    }
+    Ok(())
+}
+
+fn main() {
+    let matches = clap_app! { kv_gen_data =>
+        (@arg STORE: +required {is_store}
+         "The kind of kv-store: `memcached` or `redis`")
+        (@arg ADDR: +required
+         "The address of the kv-store: IP:PORT for memcached; redis://<IP>:<PORT> or \
+          unix:<UDS path> for redis")
+        (@arg SIZE: +required {is_int}
+         "The amount of data to put (in GB)")
+        (@arg HYPERCALL: -h --hyperv
+         "Pass this flag to use the hypercall")
+        (@arg PAGE_TABLES: -p --page_tables
+         "Pass this flag to measure page table overhead instead of latency")
+        (@arg RDTSC: --rdtsc
+         "Pass this flag to use `rdtsc` as the clock source. Clock ticks are converted to \
+          seconds with the TSC frequency, which is detected automatically (see --freq). The TSC \
+          should be invariant (constant_tsc and nonstop_tsc).")
+        (@arg FREQ: -f --freq +takes_value {is_int}
+         "Use the given TSC frequency (an integer in MHz) instead of detecting it. Implies \
+          --rdtsc.")
+        (@arg PFTIME: --pftime +takes_value {is_int}
+         "If present, does a hypercall toet the PF_TIME to the given value.")
+        (@arg VAL_SIZE: --val_size +takes_value {is_positive}
+         "The size of each value in bytes (default: 523800 for memcached, 524288 for redis).")
+        (@arg PAYLOAD: --payload +takes_value {is_pattern}
+         "What to fill values with: `zeros` (default), `counter`, `random`, `text:<ratio>` \
+          (compressible by about <ratio>) or `file:<path>` (replay the bytes of a file).")
+        (@arg SIZE_DIST: --size_dist +takes_value {is_size_distribution}
+         "The distribution of value sizes: `fixed` (default, --val_size bytes), \
+          `uniform:<min>:<max>` or `zipf:<min>:<max>:<theta>`.")
+        (@arg SEED: --seed +takes_value {is_int}
+         "The seed for random payloads, sizes, operations and keys (default: 0).")
+        (@arg OPS: --ops +takes_value {is_int}
+         "The number of operations to run after the fill (default: 0).")
+        (@arg MIX: --mix +takes_value {is_op_mix}
+         "The relative frequencies of operations after the fill, e.g. \
+          `read=0.95,update=0.05` (default: `read=1`). Ops are read, update, insert, delete.")
+        (@arg KEYS: --keys +takes_value {is_key_distribution}
+         "How keys are chosen after the fill and for --gets: `uniform` (default), `sequential`, \
+          `zipf[:<theta>]`, `hotspot:<hot_fraction>:<hot_ops>` or `latest[:<theta>]`.")
+        (@arg GETS: --gets +takes_value {is_int}
+         "The number of `get`s to do at the end, with keys chosen according to --keys \
+          (default: 0).")
+        (@arg LATENCY: --latency visible_alias[summary]
+         "Record the latency of every operation and report percentiles after each phase.")
+        (@arg PRINT_INTERVAL: --print_interval +takes_value {is_positive}
+         "Print a measurement every this many `put`s (default: 100).")
+        (@arg RETRIES: --retries +takes_value {is_int}
+         "The number of times to retry a failed `put` (default: unlimited for memcached, 1 \
+          for redis).")
+        (@arg SAMPLE: --sample +takes_value {is_sources}
+         "Sample the given comma-separated list of things in the background: `compaction`, \
+          `page_tables`, `vmstat[:<prefix>]`, `thp`, `buddyinfo`, `extfrag[:<order>]` or \
+          `rss[:<pid>]`.")
+        (@arg SAMPLE_INTERVAL: --sample_interval +takes_value {is_int} requires[SAMPLE]
+         "The interval between samples in milliseconds (default: 1000).")
+        (@arg SAMPLE_FILE: --sample_file +takes_value requires[SAMPLE]
+         "The location to write samples to (default: stdout, alongside the other output).")
+        (@arg FORMAT: --format +takes_value {is_format}
+         "The output format: `text` (default), `jsonl` or `csv`.")
+        (@arg BACKEND: --backend +takes_value {is_backend}
+         "The hypervisor backend to use for hypercalls: `vmcall` (default, requires a 0sim \
+          guest) or `sim` (simulated in-process).")
+        (@arg CALIBRATION: --calibration +takes_value
+         "Check the calibration saved by time_calibrate at the given path (by default, \
+          /tmp/time_calibrate.state) before measuring, and exit if it is missing, did not \
+          converge or predates the last reboot.")
+    }
+    .get_matches();
+
+    let store = matches.value_of("STORE").unwrap();
+
+    // Get the kv-store addr. Only memcached addresses can be checked by clap, since redis ones
+    // depend on the store.
+    let addr = matches.value_of("ADDR").unwrap();
+    if store == "memcached" {
+        if let Err(e) = is_addr(addr.to_owned()) {
+            clap::Error::value_validation_auto(format!(
+                "The argument '{}' isn't a valid value for '<ADDR>': {}",
+                addr, e
+            ))
+            .exit();
+        }
+    }
+
+    // Get the amount of data to put
+    let size = matches
+        .value_of("SIZE")
+        .unwrap()
+        .to_string()
+        .parse::<usize>()
+        .unwrap()
+        << 30;
+
+    let (default_val_size, default_retries) = match store {
+        "memcached" => (MemcachedBackend::DEFAULT_VAL_SIZE, None),
+        "redis" => (RedisBackend::DEFAULT_VAL_SIZE, Some(1)),
+        _ => unreachable!(),
+    };
+
+    let val_size = matches
+        .value_of("VAL_SIZE")
+        .map(|v| v.parse::<usize>().unwrap())
+        .unwrap_or(default_val_size);
+
+    let mut payload = PayloadGenerator::new(
+        matches
+            .value_of("PAYLOAD")
+            .map(|p| Pattern::parse(p).unwrap())
+            .unwrap_or(Pattern::Zeros),
+        matches
+            .value_of("SIZE_DIST")
+            .map(|d| SizeDistribution::parse(d, val_size).unwrap())
+            .unwrap_or(SizeDistribution::Fixed(val_size)),
+        matches
+            .value_of("SEED")
+            .map(|s| s.parse().unwrap())
+            .unwrap_or(0),
+    )
+    .expect("unable to create payload generator");
+
+    // Check if we are to account for hypervisor
+    let use_hypercall = matches.is_present("HYPERCALL");
+
+    // Check if we are to measure page table overhead instead
+    let page_tables = matches.is_present("PAGE_TABLES");
+    assert!(!use_hypercall || !page_tables);
+
+    // Choose how to do hypercalls.
+    let hv = Backend::from_arg(matches.value_of("BACKEND"));
+
+    // Set the PF time.
+    if let Some(pf_time) = matches.value_of("PFTIME") {
+        let pf_time = pf_time.to_string().parse::<u64>().unwrap();
+        hv.pf_time(pf_time);
+    }
+
+    let format = Format::from_arg(matches.value_of("FORMAT"));
+    let mut out = Output::stdout(format);
+
+    // Make sure that the hypervisor was calibrated, if requested.
+    if let Some(state) = matches.value_of("CALIBRATION") {
+        match Calibration::check(state) {
+            Ok(calibration) => calibration.report(&mut out).unwrap(),
+            Err(problem) => {
+                eprintln!("{}", problem);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let mut driver = Driver::new(size, &payload);
+    driver.page_tables = page_tables;
+    if let Some(mhz) = matches.value_of("FREQ") {
+        clock::set_tsc(TscClock::from_mhz(mhz.parse().unwrap()));
+    }
+    driver.rdtsc = matches.is_present("RDTSC") || matches.is_present("FREQ");
+    if driver.rdtsc {
+        clock::tsc().report(&mut out).unwrap();
+    }
+    driver.hv = if use_hypercall { Some(&hv) } else { None };
+    driver.max_retries = matches
+        .value_of("RETRIES")
+        .map(|r| r.parse().unwrap())
+        .or(default_retries);
+    if let Some(nops) = matches.value_of("OPS") {
+        driver.nops = nops.parse().unwrap();
+    }
+    if let Some(ngets) = matches.value_of("GETS") {
+        driver.ngets = ngets.parse().unwrap();
+    }
+    driver.latency = matches.is_present("LATENCY");
+    if let Some(mix) = matches.value_of("MIX") {
+        driver.mix = OpMix::parse(mix).unwrap();
+    }
+    if let Some(keys) = matches.value_of("KEYS") {
+        driver.keys = KeyDistribution::parse(keys).unwrap();
+    }
+    if let Some(seed) = matches.value_of("SEED") {
+        driver.seed = seed.parse().unwrap();
+    }
+    if let Some(interval) = matches.value_of("PRINT_INTERVAL") {
+        driver.print_interval = interval.parse().unwrap();
+    }
+
+    // Start sampling in the background, if requested.
+    let sampler = matches.value_of("SAMPLE").map(|sources| {
+        Sampler::start(
+            Source::parse_list(sources).unwrap(),
+            Duration::from_millis(
+                matches
+                    .value_of("SAMPLE_INTERVAL")
+                    .map(|i| i.parse().unwrap())
+                    .unwrap_or(1000),
+            ),
+            match matches.value_of("SAMPLE_FILE") {
+                Some(path) => Output::create(format, path).unwrap(),
+                None => out.share(),
+            },
+        )
+    });
+
+    let result = match store {
+        "memcached" => run(
+            MemcachedBackend::connect(addr),
+            &driver,
+            &mut payload,
+            &mut out,
+        )
+        .map_err(|e| format!("{:?}", e)),
+        "redis" => run(RedisBackend::connect(addr), &driver, &mut payload, &mut out)
+            .map_err(|e| format!("{:?}", e)),
+        _ => unreachable!(),
+    };
+
+    if let Some(sampler) = sampler {
+        sampler.stop();
+    }
+
+    match result {
+        Ok(()) => {}
+        Err(e) => panic!("Error: {}", e),
+    }
+}
@@ -1,17 +1,37 @@
 //! Measure time to access memory either with a local or nonlocal access pattern.
 //!
-//! NOTE: all measurements are done with `rdtsc`, which reports cycle counts.
+//! `-l` repeatedly writes one word of each of 8 pages, and `-n` writes one word of random pages of a
+//! 4GB region. `--pattern` instead picks the pattern from `paperexp::access` with a configurable
+//! working set, stride and kind of access, and `--sweep` repeats the measurement for a range of
+//! working set sizes, e.g. to find the reach of each level of cache and of the TLB.
+//!
+//! Each thread records its latencies into a preallocated buffer, and the buffers are only written
+//! out once all threads are done, so that threads do not contend on stdout while measuring. With
+//! several threads, each thread's latencies are reported as a separate phase. `--summary` adds
+//! summary statistics for each thread and for all threads combined.
+//!
+//! NOTE: all measurements are done with `rdtsc`, which reports cycle counts. `--timer` chooses how
+//! the reads are serialized, and `--subtract_overhead` removes the cost of the reads themselves.
+
+use std::sync::{Arc, Barrier};
 
-use bmk_linux::timing::rdtsc;
+use bmk_linux::resultarray::{ResultArray, PAGE_SIZE};
 
 use clap::clap_app;
 
-use libc::{
-    mmap as libc_mmap, MAP_ANONYMOUS, MAP_FAILED, MAP_POPULATE, MAP_PRIVATE, PROT_READ, PROT_WRITE,
+use paperexp::{
+    access::{
+        is_access_mode, is_size, is_sweep, parse_size, parse_sweep, AccessKind, AccessMode,
+        AccessPattern,
+    },
+    clock,
+    output::{is_format, ClockSource, Format, Output, Record, Units},
+    region::{is_backing, is_huge_pages, Backing, HugePages, RegionBuilder, RegionUsage},
+    stats::{is_trim, Summary, Trim},
+    timer::{is_timer, Stopwatch},
+    topology::{is_placement, CpuSet, Placement, Topology},
 };
 
-use rand::Rng;
-
 fn is_usize(arg: String) -> Result<(), String> {
     arg.to_string()
         .parse::<usize>()
@@ -27,120 +47,311 @@ fn main() {
             (@attributes +required)
             (@arg local: -l "Access memory with strong locality")
             (@arg nonlocal: -n "Access memory with poor locality")
+            (@arg PATTERN: --pattern +takes_value {is_access_mode}
+             "Access memory with the given pattern: `sequential` (every word in order), \
+              `strided` (every STRIDE bytes in order), `random` (random multiples of STRIDE) or \
+              `chase` (dependent loads along a random cycle with STRIDE-byte steps).")
         )
         (@arg N: +required {is_usize}
-         "The number of iterations (preferably divisible by 8).")
+         "The number of iterations (preferably divisible by 8), for each working set size.")
+        (@arg SIZE: --size +takes_value {is_size} conflicts_with[SWEEP]
+         "The size of the working set, e.g. `32k` or `4g` (default: 32k with -l, 4g otherwise).")
+        (@arg SWEEP: --sweep +takes_value {is_sweep}
+         "Measure working sets of sizes MIN..MAX (e.g. `4k..1g`), doubling each time.")
+        (@arg STRIDE: --stride +takes_value {is_size}
+         "The distance between accessed locations in bytes (default: 4k).")
+        (@arg READ: --read "Load from memory instead of storing to it.")
         (@arg MULTITHREAD: -t --threads +takes_value {is_usize}
          "(Optional) If passed with a value > 1, the bmk runs in multithreaded mode with the given \
          number of threads. Each thread gets it's own region of memory.")
+        (@arg PLACEMENT: --placement +takes_value {is_placement}
+         "Which CPUs to pin threads to: `cpus` (default; every usable CPU), `cores` (one per \
+          physical core) or `node:<n>` (the CPUs of NUMA node n).")
+        (@arg USAGE: --usage
+         "Report how much of the memory is resident and backed by huge pages, from \
+          /proc/self/smaps.")
+        (@arg HUGE: --huge +takes_value {is_huge_pages}
+         "How to use huge pages: `default` (the system's THP settings), `thp` (MADV_HUGEPAGE), \
+          `nothp` (MADV_NOHUGEPAGE), `hugetlb:2m` or `hugetlb:1g`.")
+        (@arg BACKING: --backing +takes_value {is_backing}
+         "What backs the memory: `anon` (default), `memfd` or `file:<path>` (e.g. on tmpfs or \
+          hugetlbfs; a directory gets a new unlinked file).")
+        (@arg TIMER: --timer +takes_value {is_timer}
+         "How to read the TSC around each access: `rdtsc` (default; not serialized), `lfence` \
+          (`lfence; rdtsc; lfence`), `rdtscp` (`rdtscp; lfence`) or `cpuid` (`cpuid; rdtsc` and \
+          `rdtscp; cpuid`).")
+        (@arg SUBTRACT: --subtract_overhead
+         "Subtract the overhead of the timer, measured at startup, from each latency.")
+        (@arg SUMMARY: --summary
+         "Also report summary statistics of the latencies of each thread and of all threads.")
+        (@arg TRIM: --trim +takes_value {is_trim} requires[SUMMARY]
+         "Remove outliers before summarizing: `none` (default), `pct:<p>` (p% from each end) or \
+          `iqr[:<k>]` (more than k interquartile ranges beyond the quartiles; k defaults to 1.5).")
+        (@arg NS: --ns
+         "Report rdtsc measurements in nanoseconds instead of cycles, using the TSC frequency \
+          (detected automatically).")
+        (@arg FORMAT: --format +takes_value {is_format}
+         "The output format: `text` (default), `jsonl` or `csv`.")
     }
     .get_matches();
 
     let is_local = matches.is_present("local");
 
+    // What to access, and how.
+    let (phase, mode) = if is_local {
+        ("local".to_owned(), AccessMode::Strided)
+    } else if matches.is_present("nonlocal") {
+        ("nonlocal".to_owned(), AccessMode::Random)
+    } else {
+        let mode = AccessMode::parse(matches.value_of("PATTERN").unwrap()).unwrap();
+        (mode.to_string(), mode)
+    };
+    let working_sets = if let Some(sweep) = matches.value_of("SWEEP") {
+        parse_sweep(sweep).unwrap()
+    } else if let Some(size) = matches.value_of("SIZE") {
+        vec![parse_size(size).unwrap()]
+    } else if is_local {
+        vec![8 << 12]
+    } else {
+        vec![4 << 30]
+    };
+    let patterns: Vec<_> = working_sets
+        .iter()
+        .map(|&working_set| AccessPattern {
+            mode,
+            kind: if matches.is_present("READ") {
+                AccessKind::Read
+            } else {
+                AccessKind::Write
+            },
+            working_set,
+            stride: matches
+                .value_of("STRIDE")
+                .map(|s| parse_size(s).unwrap())
+                .unwrap_or(1 << 12),
+        })
+        .collect();
+    for pattern in patterns.iter() {
+        if let Err(e) = pattern.validate() {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+
     let threads = matches
         .value_of("MULTITHREAD")
         .map(|value| value.parse().unwrap());
 
     let n = matches.value_of("N").unwrap().parse().unwrap();
 
-    let ncpus = get_num_cpus();
+    // How each thread maps its memory: enough for the largest working set.
+    let builder = RegionBuilder::new(*working_sets.iter().max().unwrap())
+        .huge_pages(HugePages::from_arg(matches.value_of("HUGE")))
+        .backing(Backing::from_arg(matches.value_of("BACKING")))
+        .populate(true);
 
-    if let Some(threads) = threads {
-        let mut handles = vec![];
+    // Which CPU each thread runs on. Threads wrap around if there are more threads than CPUs.
+    let cpus = Topology::read()
+        .and_then(|topology| {
+            topology.place(
+                Placement::from_arg(matches.value_of("PLACEMENT")),
+                threads.unwrap_or(1),
+            )
+        })
+        .expect("unable to read CPU topology");
+    let usage = matches.is_present("USAGE");
 
-        for i in 0..threads {
-            handles.push(std::thread::spawn(move || do_work(is_local, i % ncpus, n)));
-        }
+    // How to print results.
+    let mut out = Output::stdout(Format::from_arg(matches.value_of("FORMAT")));
 
-        for handle in handles.into_iter() {
-            handle.join().unwrap();
-        }
-    } else {
-        // Single threaded
-        do_work(is_local, 0, n);
+    // Convert cycles to time, if requested.
+    if matches.is_present("NS") {
+        let tsc = clock::tsc();
+        tsc.report(&mut out).unwrap();
+        out.convert_cycles(tsc);
     }
-}
 
-/// Actually do the work of the benchmark. Pin the work to the given cpu core.
-fn do_work(is_local: bool, core: usize, n: usize) {
-    // CPU pinning
-    paperexp::set_cpu(core);
+    // How to time each access.
+    let stopwatch = Stopwatch::from_args(matches.value_of("TIMER"), matches.is_present("SUBTRACT"));
+    stopwatch.report_if_chosen(&mut out).unwrap();
 
-    // Mmap memory for the experiment
-    let mapped = unsafe {
-        let addr = libc_mmap(
-            std::ptr::null_mut(),
-            4 << 30,
-            PROT_READ | PROT_WRITE,
-            MAP_PRIVATE | MAP_ANONYMOUS | MAP_POPULATE,
-            -1,
-            0,
-        );
+    // The latencies measured by each thread with each pattern, where the thread ran and the usage
+    // of its region.
+    let results: Vec<(CpuSet, Work)> = if let Some(threads) = threads {
+        let cpus: Vec<CpuSet> = cpus.into_iter().take(threads).collect();
+        let barrier = Arc::new(Barrier::new(cpus.len()));
+        let mut handles = vec![];
 
-        if addr == MAP_FAILED {
-            panic!("Unable to mmap");
+        for cpu in cpus {
+            let barrier = Arc::clone(&barrier);
+            let builder = builder.clone();
+            let patterns = patterns.clone();
+            handles.push(std::thread::spawn(move || {
+                let work = do_work(
+                    &patterns,
+                    cpu.clone(),
+                    n,
+                    stopwatch,
+                    builder,
+                    usage,
+                    &barrier,
+                );
+                (cpu, work)
+            }));
         }
 
-        addr as *mut u8
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect()
+    } else {
+        // Single threaded
+        let cpu = cpus[0].clone();
+        let work = do_work(
+            &patterns,
+            cpu.clone(),
+            n,
+            stopwatch,
+            builder,
+            usage,
+            &Barrier::new(1),
+        );
+        vec![(cpu, work)]
     };
 
-    // Warmup phase: 1 word of the first 8 pages.
-    for i in 0..8 {
-        unsafe {
-            *mapped.offset((i << 12) as isize) = 7;
+    // Write everything out now that measurement is over.
+    for (_, work) in results.iter() {
+        if let Some((usage, len)) = work.usage {
+            usage.report(&mut out, "region", len).unwrap();
         }
     }
 
-    if is_local {
-        // Touch these warm cache lines a lot and time it
-        for _ in 0..(n / 8) {
-            for i in 0..8 {
-                let start = rdtsc();
-                unsafe {
-                    *mapped.offset((i << 12) as isize) = 8;
-                }
-                let end = rdtsc();
+    let summary = matches.is_present("SUMMARY");
+    let trim = Trim::from_arg(matches.value_of("TRIM"));
+
+    for (p, pattern) in patterns.iter().enumerate() {
+        // With a sweep, each working set gets its own phase.
+        let phase = if patterns.len() > 1 {
+            out.text(format_args!("Working set: {} bytes", pattern.working_set))
+                .unwrap();
+            format!("{}.{}", phase, pattern.working_set)
+        } else {
+            phase.clone()
+        };
+
+        // With several threads, so does each thread.
+        let thread_phase = |t: usize| match threads {
+            Some(_) => format!("{}.{}", phase, t),
+            None => phase.clone(),
+        };
 
-                println!("{}", end - start);
+        for (t, (cpu, work)) in results.iter().enumerate() {
+            let thread_phase = thread_phase(t);
+
+            if threads.is_some() {
+                out.emit(
+                    format_args!("Thread {} (CPU {}):", t, cpu),
+                    &[
+                        Record::new(&thread_phase, "thread", t),
+                        Record::new(&thread_phase, "cpu", cpu.first().unwrap_or(0)),
+                    ],
+                )
+                .unwrap();
             }
-        }
-    } else {
-        let mut rng = rand::thread_rng();
 
-        // Do something that has terrible performance
-        // - lots of cache and TLB misses
-        // - random behavior to avoid prefetchers
-        for _ in 0..n {
-            let i: isize = rng.gen_range(0, (4 << 30) >> 12);
+            for (sample, &cycles) in work.latencies[p].iter().enumerate() {
+                out.emit(
+                    format_args!("{}", cycles),
+                    &[Record::new(&thread_phase, "latency", cycles)
+                        .iteration(sample)
+                        .clock(ClockSource::Rdtsc)
+                        .units(Units::Cycles)],
+                )
+                .unwrap();
+            }
+        }
 
-            let start = rdtsc();
-            unsafe {
-                *mapped.offset(i << 12) = 9;
+        if summary {
+            if threads.is_some() {
+                for (t, (_, work)) in results.iter().enumerate() {
+                    Summary::new(work.latencies[p].iter().map(|&l| l as f64), trim)
+                        .report(
+                            &mut out,
+                            &thread_phase(t),
+                            ClockSource::Rdtsc,
+                            Units::Cycles,
+                        )
+                        .unwrap();
+                }
             }
-            let end = rdtsc();
 
-            println!("{}", end - start);
+            Summary::new(
+                results
+                    .iter()
+                    .flat_map(|(_, work)| work.latencies[p].iter().map(|&l| l as f64)),
+                trim,
+            )
+            .report(&mut out, &phase, ClockSource::Rdtsc, Units::Cycles)
+            .unwrap();
         }
     }
 }
 
-fn get_num_cpus() -> usize {
-    let re = regex::Regex::new(r#"CPU\(s\):\s+(\d+)"#).unwrap();
+/// What one thread measured.
+struct Work {
+    /// The latencies measured with each pattern.
+    latencies: Vec<Vec<u64>>,
 
-    let out = std::process::Command::new("lscpu").output().unwrap();
-    let out = std::str::from_utf8(&out.stdout).unwrap();
+    /// The usage of the thread's region and its length, if requested.
+    usage: Option<(RegionUsage, usize)>,
+}
 
-    let cpus = re
-        .captures_iter(out)
-        .next()
-        .unwrap()
-        .get(1)
-        .unwrap()
-        .as_str()
-        .to_owned()
-        .parse::<usize>()
-        .unwrap();
+/// Actually do the work of the benchmark: `n` accesses with each pattern, timed with `stopwatch`.
+/// Pin the work to the given CPUs, and read the usage of the region if `usage` is set. No thread
+/// starts measuring until all of them have waited on `barrier`, i.e. finished setting up.
+fn do_work(
+    patterns: &[AccessPattern],
+    cpus: CpuSet,
+    n: usize,
+    stopwatch: Stopwatch,
+    builder: RegionBuilder,
+    usage: bool,
+    barrier: &Barrier,
+) -> Work {
+    // CPU pinning
+    paperexp::set_cpu(cpus).expect("unable to pin thread");
+
+    // Mmap memory for the experiment
+    let region = builder.map().expect("Unable to mmap");
+
+    // How much of the memory ended up huge?
+    let usage = if usage {
+        Some((region.usage().expect("unable to read smaps"), region.len()))
+    } else {
+        None
+    };
+
+    let mut rng = rand::thread_rng();
+
+    barrier.wait();
+
+    let latencies = patterns
+        .iter()
+        .map(|pattern| {
+            // Results array, allocated up front so that recording does not fault.
+            let per_page = PAGE_SIZE / std::mem::size_of::<u64>();
+            let mut results = ResultArray::new(n.max(1).next_multiple_of(per_page));
+
+            // Warmup phase: touch the whole working set.
+            pattern.prepare(&region, &mut rng);
+
+            pattern.run(&region, n, &stopwatch, &mut rng, |_, cycles| {
+                results.push(cycles)
+            });
+
+            results.iter().cloned().collect()
+        })
+        .collect();
 
-    cpus
+    Work { latencies, usage }
 }
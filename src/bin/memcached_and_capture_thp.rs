@@ -1,10 +1,16 @@
-//! Sits in a loop doing `put` operations on the given memcached instance. The keys are unique, but
-//! the values are large, all-zero values.
+//! Sits in a loop doing `put` operations on the given memcached instance. The keys are unique, and
+//! the values are large. By default, values are all zeros, but `--payload` and `--size_dist` can
+//! be used to choose their contents and sizes.
 //!
-//! We do N insertions, followed by N/3 deletions, followed by N/2 more insertions.
+//! By default, we do N insertions, followed by N/3 deletions, followed by N/2 more insertions. The
+//! first N/3 keys are deleted in order, but `--keys` can choose the deleted keys from another
+//! distribution. Alternatively, `--script` runs a workload described by a script (see
+//! `paperexp::script`), and a summary of each phase is printed to stdout. `--summary` adds summary
+//! statistics of the latencies of each phase.
 //!
-//! In the meantime, every N seconds, it executes syscall 335 to get THP compaction stats, where N
-//! is a command line arg. The results are printed to stdout.
+//! In the meantime, every N seconds, it samples THP compaction stats, where N is a command line
+//! arg. `--sample` can choose other things to sample (see `paperexp::sampler`). The samples are
+//! printed to stdout or `--sample_file`.
 //!
 //! NOTE: This should be run from a machine that has a high-bandwidth, low-latency connection with
 //! the test machine.
@@ -12,8 +18,6 @@
 //! NOTE: The server should be started with e.g. `memcached -m 50000` for 50GB.
 
 use std::{
-    fs::OpenOptions,
-    io::{BufWriter, Write},
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
@@ -21,16 +25,18 @@ use std::{
     time::Duration,
 };
 
-use bmk_linux::timing::rdtsc;
-
 use clap::clap_app;
 
-use memcache::Client;
-
-use paperexp::CompactInstrumentationStats;
-
-/// The TTL of the key/value pairs
-const EXPIRATION: u32 = 1_000_000; // A really long time
+use paperexp::{
+    clock,
+    keys::{is_key_distribution, KeyDistribution},
+    kv::MemcachedBackend,
+    output::{is_format, Format, Output},
+    payload::{is_pattern, is_size_distribution, Pattern, PayloadGenerator, SizeDistribution},
+    sampler::{is_sources, Sampler, Source},
+    script::{Amount, Expr, Script, Step, StepOp},
+    stats::{is_trim, Trim},
+};
 
 /// The order of magnitude of the size of the values
 const VAL_ORDER: usize = 19; // 20 seems to give a "too large" error
@@ -38,9 +44,6 @@ const VAL_ORDER: usize = 19; // 20 seems to give a "too large" error
 /// 2^`VAL_ORDER`
 const VAL_SIZE: usize = 1 << VAL_ORDER;
 
-/// A big array that constitutes the values to be `put`
-const ZEROS: &[u8] = &[0; VAL_SIZE];
-
 fn is_addr(arg: String) -> Result<(), String> {
     use std::net::ToSocketAddrs;
 
@@ -56,25 +59,42 @@ fn is_int(arg: String) -> Result<(), String> {
         .map(|_| ())
 }
 
-macro_rules! try_again {
-    ($e:expr) => {{
-        if let Err(e) = $e {
-            println!("unexpected error: {:?}", e);
-            if let Err(e) = $e {
-                println!("unexpected error: {:?}", e);
-                return;
-            }
-        }
-    }};
-}
-
 fn run() {
     let matches = clap_app! { time_mmap_touch =>
         (@arg MEMCACHED: +required {is_addr} "The IP:PORT of the memcached instance")
         (@arg SIZE: +required {is_int} "The amount of data to put (in GB)")
-        (@arg INTERVAL: +required {is_int} "The interval at which to read compaction stats")
+        (@arg INTERVAL: +required {is_int} "The interval at which to sample (in seconds)")
         (@arg OUTFILE: +required "The location to write memcached performance measurements to")
         (@arg CONTINUAL: --continual_compaction "Continually trigger compaction")
+        (@arg PAYLOAD: --payload +takes_value {is_pattern}
+         "What to fill values with: `zeros` (default), `counter`, `random`, `text:<ratio>` \
+          (compressible by about <ratio>) or `file:<path>` (replay the bytes of a file).")
+        (@arg SIZE_DIST: --size_dist +takes_value {is_size_distribution}
+         "The distribution of value sizes: `fixed` (default, 512KB), `uniform:<min>:<max>` or \
+          `zipf:<min>:<max>:<theta>`.")
+        (@arg SEED: --seed +takes_value {is_int}
+         "The seed for random payloads, sizes and keys (default: 0).")
+        (@arg KEYS: --keys +takes_value {is_key_distribution}
+         "How to choose the keys to delete: `sequential` (default), `uniform`, \
+          `zipf[:<theta>]`, `hotspot:<hot_fraction>:<hot_ops>` or `latest[:<theta>]`.")
+        (@arg SCRIPT: --script +takes_value conflicts_with[KEYS]
+         "A file describing the phases of the workload to run instead of the default one.")
+        (@arg SAMPLE: --sample +takes_value {is_sources}
+         "A comma-separated list of things to sample every INTERVAL: `compaction` (default), \
+          `page_tables`, `vmstat[:<prefix>]`, `thp`, `buddyinfo`, `extfrag[:<order>]` or \
+          `rss[:<pid>]`.")
+        (@arg SAMPLE_FILE: --sample_file +takes_value
+         "The location to write samples to (default: stdout, alongside the other output).")
+        (@arg SUMMARY: --summary
+         "Also report summary statistics of the latencies of each phase.")
+        (@arg TRIM: --trim +takes_value {is_trim} requires[SUMMARY]
+         "Remove outliers before summarizing: `none` (default), `pct:<p>` (p% from each end) or \
+          `iqr[:<k>]` (more than k interquartile ranges beyond the quartiles; k defaults to 1.5).")
+        (@arg NS: --ns
+         "Report rdtsc measurements in nanoseconds instead of cycles, using the TSC frequency \
+          (detected automatically).")
+        (@arg FORMAT: --format +takes_value {is_format}
+         "The output format of both stdout and OUTFILE: `text` (default), `jsonl` or `csv`.")
     }
     .get_matches();
 
@@ -90,11 +110,70 @@ fn run() {
         .unwrap()
         << 30;
 
+    let seed = matches
+        .value_of("SEED")
+        .map(|s| s.parse().unwrap())
+        .unwrap_or(0);
+
+    // What to put
+    let mut payload = PayloadGenerator::new(
+        matches
+            .value_of("PAYLOAD")
+            .map(|p| Pattern::parse(p).unwrap())
+            .unwrap_or(Pattern::Zeros),
+        matches
+            .value_of("SIZE_DIST")
+            .map(|d| SizeDistribution::parse(d, VAL_SIZE).unwrap())
+            .unwrap_or(SizeDistribution::Fixed(VAL_SIZE)),
+        seed,
+    )
+    .expect("unable to create payload generator");
+
+    // What to do
+    let mut script = match matches.value_of("SCRIPT") {
+        Some(path) => Script::from_file(path).unwrap_or_else(|e| {
+            eprintln!("unable to read script: {}", e);
+            std::process::exit(1);
+        }),
+
+        // N inserts, N/3 deletes, N/2 inserts of new keys.
+        None => Script {
+            steps: vec![
+                Step::new(StepOp::Insert),
+                Step {
+                    dist: matches
+                        .value_of("KEYS")
+                        .map(|k| KeyDistribution::parse(k).unwrap())
+                        .unwrap_or(KeyDistribution::Sequential),
+                    amount: Amount::Count(Expr::parse("N/3").unwrap()),
+                    ..Step::new(StepOp::Delete)
+                },
+                Step {
+                    name: "reinsert".into(),
+                    keys: (Expr::n(), Expr::parse("N+N/2").unwrap()),
+                    ..Step::new(StepOp::Insert)
+                },
+            ],
+            text_summaries: false,
+            summary: None,
+        },
+    };
+
+    // Summarize latencies, if requested.
+    if matches.is_present("SUMMARY") {
+        script.summary = Some(Trim::from_arg(matches.value_of("TRIM")));
+    }
+
     // Total number of `put`s required
-    let nputs = size / VAL_SIZE;
+    let nputs = (size as f64 / payload.mean_size()) as usize;
+
+    if let Err(e) = script.check(nputs) {
+        eprintln!("invalid script: {}", e);
+        std::process::exit(1);
+    }
 
     // Connect to the kv-store
-    let mut client = Client::new(format!("memcache://{}", addr).as_str()).unwrap();
+    let mut client = MemcachedBackend::connect(addr).unwrap();
 
     // Interval to poll
     let interval = matches
@@ -108,35 +187,24 @@ fn run() {
 
     let continual_compaction = matches.is_present("CONTINUAL");
 
-    // Start a thread that does stuff
-    let stop_flag = Arc::new(AtomicBool::new(false));
-
-    let measure_thread = {
-        let stop_flag = Arc::clone(&stop_flag);
+    let format = Format::from_arg(matches.value_of("FORMAT"));
 
-        std::thread::spawn(move || {
-            let mut prev = 0;
-            loop {
-                // Sleep for a while
-                std::thread::sleep(Duration::from_secs(interval));
-
-                // Take a measurement
-                let CompactInstrumentationStats { ops, undos } =
-                    paperexp::thp_compact_instrumentation();
-
-                // once the flag is set, wait to stabilize...
-                if stop_flag.load(Ordering::Relaxed) {
-                    if ops == prev {
-                        break;
-                    }
-                }
+    let mut out = Output::stdout(format);
 
-                prev = ops;
+    // Start sampling
+    let sampler = Sampler::start(
+        matches
+            .value_of("SAMPLE")
+            .map(|s| Source::parse_list(s).unwrap())
+            .unwrap_or_else(|| vec![Source::Compaction]),
+        Duration::from_secs(interval),
+        match matches.value_of("SAMPLE_FILE") {
+            Some(path) => Output::create(format, path).unwrap(),
+            None => out.share(),
+        },
+    );
 
-                println!("{} {}", ops, undos);
-            }
-        })
-    };
+    let stop_flag = Arc::new(AtomicBool::new(false));
 
     // A thread that triggers continual compaction
     let compact_thread = if continual_compaction {
@@ -145,7 +213,14 @@ fn run() {
         Some(std::thread::spawn(move || {
             loop {
                 // Take a measurement
-                paperexp::trigger_compaction(512).expect("trigger compaction failed");
+                match paperexp::trigger_compaction(512) {
+                    Ok(()) => {}
+                    Err(e) if e.is_missing() => {
+                        eprintln!("unable to trigger compaction: {}", e);
+                        break;
+                    }
+                    Err(e) => panic!("trigger compaction failed: {}", e),
+                }
 
                 // once the flag is set, exit
                 if stop_flag.load(Ordering::Relaxed) {
@@ -158,54 +233,32 @@ fn run() {
     };
 
     // Open a file for the latency measurements
-    let memcached_latency_file = OpenOptions::new()
-        .write(true)
-        .truncate(true)
-        .create(true)
-        .open(memcached_latency_file)
-        .unwrap();
-    let mut memcached_latency_file = BufWriter::new(memcached_latency_file);
-
-    // Do the work.
-    for i in 0..nputs {
-        let start = rdtsc();
-
-        // `put`
-        try_again!(client.set(&format!("{}", i), ZEROS, EXPIRATION));
-
-        write!(memcached_latency_file, "{}\n", rdtsc() - start).unwrap();
-    }
-
-    println!("NEXT!");
-    writeln!(memcached_latency_file, "NEXT!").unwrap();
-
-    // delete a third of previously inserted keys (they are random because memcached is a hashmap).
-    for i in 0..nputs / 3 {
-        let start = rdtsc();
-
-        try_again!(client.delete(&format!("{}", i)));
-
-        write!(memcached_latency_file, "{}\n", rdtsc() - start).unwrap();
+    let mut memcached_latency_file = Output::create(format, memcached_latency_file).unwrap();
+
+    // Convert cycles to time, if requested.
+    if matches.is_present("NS") {
+        let tsc = clock::tsc();
+        tsc.report(&mut out).unwrap();
+        out.convert_cycles(tsc);
+        memcached_latency_file.convert_cycles(tsc);
     }
 
-    println!("NEXT!");
-    writeln!(memcached_latency_file, "NEXT!").unwrap();
-
-    // insert more keys
-    for i in nputs..(nputs + nputs / 2) {
-        let start = rdtsc();
-
-        // `put`
-        try_again!(client.set(&format!("{}", i), ZEROS, EXPIRATION));
-
-        write!(memcached_latency_file, "{}\n", rdtsc() - start).unwrap();
+    // Do the work.
+    if let Err(e) = script.run(
+        &mut client,
+        &mut payload,
+        nputs,
+        seed,
+        &mut out,
+        &mut memcached_latency_file,
+    ) {
+        eprintln!("unexpected error: {:?}", e);
+        return;
     }
 
-    println!("DONE!");
-
     stop_flag.store(true, Ordering::Relaxed);
 
-    measure_thread.join().unwrap();
+    sampler.stop();
     if let Some(compact_thread) = compact_thread {
         compact_thread.join().unwrap();
     }
@@ -0,0 +1,103 @@
+//! Audit where a process's memory landed: for each mapping of the process (or a given virtual
+//! range), count the pages that are present, swapped, THP-backed, hugetlb-backed, zero pages,
+//! KSM-merged or shared. This is useful for checking e.g. how much of a memcached or redis server's
+//! heap ended up in huge pages after a run.
+//!
+//! NOTE: Physical page information (everything except presence and swapping) requires root.
+
+use clap::clap_app;
+
+use paperexp::{
+    output::{is_format, Format, Output},
+    pagemap::{maps, PageInspector, PageSummary},
+};
+
+fn is_pid(arg: String) -> Result<(), String> {
+    arg.parse::<u32>()
+        .map_err(|_| "Not a valid pid".to_owned())
+        .map(|_| ())
+}
+
+/// Parse a `<start>-<end>` range of hex addresses.
+fn parse_range(arg: &str) -> Option<(usize, usize)> {
+    let (start, end) = arg.split_once('-')?;
+    let hex = |s: &str| usize::from_str_radix(s.trim_start_matches("0x"), 16).ok();
+    match (hex(start)?, hex(end)?) {
+        (start, end) if start < end => Some((start, end)),
+        _ => None,
+    }
+}
+
+fn is_range(arg: String) -> Result<(), String> {
+    parse_range(&arg)
+        .map(|_| ())
+        .ok_or_else(|| "Not a valid range (expected <start>-<end> in hex)".to_owned())
+}
+
+fn main() {
+    let matches = clap_app! { page_audit =>
+        (@arg PID: +required {is_pid} "The process to audit")
+        (@arg RANGE: --range +takes_value {is_range}
+         "Only audit the given range of virtual addresses, as <start>-<end> in hex.")
+        (@arg MAPPINGS: --mappings
+         "Report a summary for each mapping as well as the total.")
+        (@arg FORMAT: --format +takes_value {is_format}
+         "The output format: `text` (default), `jsonl` or `csv`.")
+    }
+    .get_matches();
+
+    let pid = matches.value_of("PID").unwrap().parse().unwrap();
+
+    let mut out = Output::stdout(Format::from_arg(matches.value_of("FORMAT")));
+
+    let inspector = PageInspector::open(Some(pid)).unwrap_or_else(|e| {
+        eprintln!("unable to open pagemap: {}", e);
+        std::process::exit(1);
+    });
+    if !inspector.has_physical() {
+        eprintln!("/proc/kpageflags is not readable; only presence and swapping are reported");
+    }
+
+    // Which ranges to audit
+    let ranges: Vec<(String, usize, usize)> = match matches.value_of("RANGE") {
+        Some(range) => {
+            let (start, end) = parse_range(range).unwrap();
+            vec![(format!("{:x}-{:x}", start, end), start, end)]
+        }
+
+        // The vsyscall page is not in the pagemap.
+        None => maps(Some(pid))
+            .expect("unable to read mappings")
+            .into_iter()
+            .filter(|m| m.path.as_deref() != Some("[vsyscall]"))
+            .map(|m| {
+                let name = match m.path {
+                    Some(ref path) => format!("{:x} {}", m.start, path),
+                    None => format!("{:x} anon", m.start),
+                };
+                (name, m.start, m.end)
+            })
+            .collect(),
+    };
+
+    let mut total = PageSummary::default();
+
+    for (name, start, end) in ranges {
+        let summary = match inspector.summarize(start, end - start) {
+            Ok(summary) => summary,
+
+            // The process may have changed its mappings under us.
+            Err(e) => {
+                eprintln!("unable to audit {}: {}", name, e);
+                continue;
+            }
+        };
+
+        if matches.is_present("MAPPINGS") {
+            summary.report(&mut out, &name).unwrap();
+        }
+        total.merge(&summary);
+    }
+
+    total.report(&mut out, "total").unwrap();
+}
@@ -1,44 +1,148 @@
-//! Touch the given number of pages. Record the total time taken, peridically record elapsed time.
-//! Fill the pages with the requested pattern.
+//! Calibrate the 0sim hypervisor so that hypercalls appear to take no time to the guest, and save
+//! the result to a state file that other experiments can check (see `paperexp::calibration`).
 //!
-//! NOTE: all measurements are done with `rdtsc`, which reports cycle counts.
+//! Exits with status 1 if calibration does not converge before the timeout.
+//!
+//! NOTE: all measurements are done with `rdtsc`, which reports cycle counts. `--timer` chooses how
+//! the reads are serialized, and `--subtract_overhead` removes the cost of the reads themselves.
+
+use clap::clap_app;
+
+use std::time::Duration;
 
-use bmk_linux::timing::rdtsc;
+use paperexp::{
+    calibration::{is_controller, Calibration, CalibrationConfig, Controller, DEFAULT_STATE_PATH},
+    clock,
+    hypervisor::{is_backend, Backend},
+    output::{is_format, ClockSource, Format, Output, Record, Units},
+    stats::{is_trim, Summary, Trim},
+    timer::{is_timer, Stopwatch},
+};
 
-use paperexp::{vmcall_calibrate, vmcall_nop};
+fn is_int(arg: String) -> Result<(), String> {
+    arg.to_string()
+        .parse::<i64>()
+        .map_err(|_| "Not a valid integer".to_owned())
+        .map(|_| ())
+}
 
-use std::fs::OpenOptions;
-use std::io::Write;
+fn is_usize(arg: String) -> Result<(), String> {
+    arg.to_string()
+        .parse::<usize>()
+        .map_err(|_| "Not a valid usize".to_owned())
+        .map(|_| ())
+}
+
+fn is_positive(arg: String) -> Result<(), String> {
+    match arg.parse::<usize>() {
+        Ok(0) => Err("Must be positive".to_owned()),
+        Ok(_) => Ok(()),
+        Err(_) => Err("Not a valid usize".to_owned()),
+    }
+}
 
 fn main() {
-    const ACC: i64 = 100000;
-    const EPSILON: i64 = 50;
-    const NUM_BELOW_EP: usize = 50;
-
-    let mut devnull = OpenOptions::new().write(true).open("/dev/null").unwrap();
-
-    let mut tries = NUM_BELOW_EP;
-
-    loop {
-        let mut sum: i64 = 0;
-        for _ in 0..ACC {
-            let start = rdtsc() as i64;
-            vmcall_nop();
-            sum += rdtsc() as i64 - start;
-            writeln!(devnull, "").unwrap();
-        }
-
-        let avg = sum / ACC;
-        println!("avg {}", avg);
-        let too_low = avg > 0;
-        if avg.abs() > EPSILON {
-            vmcall_calibrate(too_low);
-        } else {
-            if tries > 0 {
-                tries -= 1;
-            } else {
-                break;
-            }
-        }
+    let matches = clap_app! { time_calibrate =>
+        (@arg ACC: --acc +takes_value {is_positive}
+         "The number of hypercalls timed in each round (default: 100000).")
+        (@arg EPSILON: --epsilon +takes_value {is_int}
+         "How close to zero, in cycles, the average of a round must be (default: 50).")
+        (@arg NUM_BELOW_EP: --num_below_ep +takes_value {is_usize}
+         "How many rounds in a row must be within EPSILON (default: 50).")
+        (@arg TIMEOUT: --timeout +takes_value {is_usize}
+         "Give up after this many seconds (default: 600).")
+        (@arg CONTROLLER: --controller +takes_value {is_controller}
+         "How to adjust the offset after each round: `bisect` (default; double the adjustment \
+          until the average changes sign, then bisect), `step` (one cycle per round) or \
+          `pi[:<kp>,<ki>]` (proportional-integral; gains default to 0.5 and 0.05).")
+        (@arg STATE: --state +takes_value
+         "Where to save the offset and residual (default: /tmp/time_calibrate.state).")
+        (@arg NS: --ns
+         "Report rdtsc measurements in nanoseconds instead of cycles, using the TSC frequency \
+          (detected automatically).")
+        (@arg FORMAT: --format +takes_value {is_format}
+         "The output format: `text` (default), `jsonl` or `csv`.")
+        (@arg TIMER: --timer +takes_value {is_timer}
+         "How to read the TSC around each hypercall: `rdtsc` (default; not serialized), `lfence` \
+          (`lfence; rdtsc; lfence`), `rdtscp` (`rdtscp; lfence`) or `cpuid` (`cpuid; rdtsc` and \
+          `rdtscp; cpuid`).")
+        (@arg SUBTRACT: --subtract_overhead
+         "Subtract the overhead of the timer, measured at startup, from each measurement.")
+        (@arg SUMMARY: --summary
+         "Also report summary statistics of the average of each round.")
+        (@arg TRIM: --trim +takes_value {is_trim} requires[SUMMARY]
+         "Remove outliers before summarizing: `none` (default), `pct:<p>` (p% from each end) or \
+          `iqr[:<k>]` (more than k interquartile ranges beyond the quartiles; k defaults to 1.5).")
+        (@arg BACKEND: --backend +takes_value {is_backend}
+         "The hypervisor backend to use for hypercalls: `vmcall` (default, requires a 0sim \
+          guest) or `sim` (simulated in-process).")
+    }
+    .get_matches();
+
+    // Choose how to do hypercalls.
+    let hv = Backend::from_arg(matches.value_of("BACKEND"));
+
+    // How to print results
+    let mut out = Output::stdout(Format::from_arg(matches.value_of("FORMAT")));
+
+    // Convert cycles to time, if requested.
+    if matches.is_present("NS") {
+        let tsc = clock::tsc();
+        tsc.report(&mut out).unwrap();
+        out.convert_cycles(tsc);
+    }
+
+    // How to time each hypercall.
+    let stopwatch = Stopwatch::from_args(matches.value_of("TIMER"), matches.is_present("SUBTRACT"));
+    stopwatch.report_if_chosen(&mut out).unwrap();
+
+    // How to calibrate.
+    let defaults = CalibrationConfig::default();
+    let config = CalibrationConfig {
+        acc: matches
+            .value_of("ACC")
+            .map_or(defaults.acc, |acc| acc.parse().unwrap()),
+        epsilon: matches
+            .value_of("EPSILON")
+            .map_or(defaults.epsilon, |epsilon| epsilon.parse().unwrap()),
+        num_below_ep: matches
+            .value_of("NUM_BELOW_EP")
+            .map_or(defaults.num_below_ep, |n| n.parse().unwrap()),
+        timeout: matches
+            .value_of("TIMEOUT")
+            .map_or(defaults.timeout, |secs| {
+                Duration::from_secs(secs.parse().unwrap())
+            }),
+        controller: Controller::from_arg(matches.value_of("CONTROLLER")),
+    };
+
+    let mut avgs = vec![];
+
+    let calibration = Calibration::run(&hv, &stopwatch, &config, |round, avg| {
+        avgs.push(avg as f64);
+        out.emit(
+            format_args!("avg {}", avg),
+            &[Record::new("calibrate", "avg", avg)
+                .iteration(round)
+                .clock(ClockSource::Rdtsc)
+                .units(Units::Cycles)],
+        )
+        .unwrap();
+    })
+    .expect("unable to calibrate");
+
+    calibration.report(&mut out).unwrap();
+    calibration
+        .save(matches.value_of("STATE").unwrap_or(DEFAULT_STATE_PATH))
+        .expect("unable to save calibration");
+
+    if matches.is_present("SUMMARY") {
+        Summary::new(avgs, Trim::from_arg(matches.value_of("TRIM")))
+            .report(&mut out, "calibrate", ClockSource::Rdtsc, Units::Cycles)
+            .unwrap();
+    }
+
+    if !calibration.converged {
+        std::process::exit(1);
     }
 }
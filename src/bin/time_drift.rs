@@ -0,0 +1,125 @@
+//! Track the drift between the guest TSC, `CLOCK_MONOTONIC` and the host elapsed time, e.g. to
+//! check that 0sim's time virtualization stays consistent over an hours-long experiment.
+//!
+//! Samples the clocks every INTERVAL, printing how far they have drifted apart since the first
+//! sample, and periodically reports the skew and offset fitted so far (see `paperexp::drift`).
+//! Runs until DURATION has elapsed, or forever.
+//!
+//! NOTE: guest measurements are done with `rdtsc`, which reports cycle counts.
+
+use std::time::{Duration, Instant};
+
+use clap::clap_app;
+
+use paperexp::{
+    clock,
+    drift::DriftTracker,
+    hypervisor::{is_backend, Backend},
+    output::{is_format, ClockSource, Format, Output, Record, Units},
+};
+
+fn is_usize(arg: String) -> Result<(), String> {
+    arg.to_string()
+        .parse::<usize>()
+        .map_err(|_| "Not a valid usize".to_owned())
+        .map(|_| ())
+}
+
+fn main() {
+    let matches = clap_app! { time_drift =>
+        (about: "Tracks the drift between the guest's clocks and the host's elapsed time")
+        (@arg INTERVAL: --interval +takes_value {is_usize}
+         "The interval between samples in milliseconds (default: 1000).")
+        (@arg DURATION: --duration +takes_value {is_usize}
+         "How long to run for in seconds (default: forever).")
+        (@arg REPORT_EVERY: --report_every +takes_value {is_usize}
+         "Report the fitted skew and offset every this many samples (default: 60), as well as at \
+          the end.")
+        (@arg CPU: --cpu +takes_value {is_usize}
+         "Pin to the given CPU, so that the TSC is always read on the same one.")
+        (@arg NS: --ns
+         "Report rdtsc measurements in nanoseconds instead of cycles, using the TSC frequency \
+          (detected automatically).")
+        (@arg FORMAT: --format +takes_value {is_format}
+         "The output format: `text` (default), `jsonl` or `csv`.")
+        (@arg BACKEND: --backend +takes_value {is_backend}
+         "The hypervisor backend to use for hypercalls: `vmcall` (default, requires a 0sim \
+          guest) or `sim` (simulated in-process).")
+    }
+    .get_matches();
+
+    let interval = Duration::from_millis(
+        matches
+            .value_of("INTERVAL")
+            .map_or(1000, |i| i.parse().unwrap()),
+    );
+    let duration = matches
+        .value_of("DURATION")
+        .map(|d| Duration::from_secs(d.parse().unwrap()));
+    let report_every = matches
+        .value_of("REPORT_EVERY")
+        .map_or(60, |n| n.parse::<usize>().unwrap().max(1));
+
+    // Choose how to do hypercalls.
+    let hv = Backend::from_arg(matches.value_of("BACKEND"));
+
+    // CPU pinning
+    if let Some(cpu) = matches.value_of("CPU") {
+        paperexp::set_cpu(cpu.parse::<usize>().unwrap()).expect("unable to pin thread");
+    }
+
+    // How to print results
+    let mut out = Output::stdout(Format::from_arg(matches.value_of("FORMAT")));
+
+    // The drift of CLOCK_MONOTONIC is always computed with the TSC frequency, so always report it.
+    let tsc = clock::tsc();
+    tsc.report(&mut out).unwrap();
+    if matches.is_present("NS") {
+        out.convert_cycles(tsc);
+    }
+
+    ///////////////////////////////////////////////////////////////////////////
+    // Start the experiment
+    ///////////////////////////////////////////////////////////////////////////
+
+    let mut tracker = DriftTracker::new(&hv);
+    let start = Instant::now();
+
+    for i in 0.. {
+        let drift = tracker.sample();
+        out.emit(
+            format_args!(
+                "{} elapsed: {} cycles, host drift: {} cycles, monotonic drift: {} ns",
+                i, drift.elapsed, drift.host, drift.monotonic_ns
+            ),
+            &[
+                Record::new("drift", "elapsed", drift.elapsed)
+                    .iteration(i)
+                    .clock(ClockSource::Rdtsc)
+                    .units(Units::Cycles),
+                Record::new("drift", "host_drift", drift.host)
+                    .iteration(i)
+                    .clock(ClockSource::Hypercall)
+                    .units(Units::Cycles),
+                Record::new("drift", "monotonic_drift", drift.monotonic_ns)
+                    .iteration(i)
+                    .clock(ClockSource::Monotonic)
+                    .units(Units::Nanoseconds),
+            ],
+        )
+        .unwrap();
+
+        if duration.is_some_and(|duration| start.elapsed() + interval > duration) {
+            break;
+        }
+
+        if (i + 1) % report_every == 0 {
+            tracker.report(&mut out, "fit").unwrap();
+            out.flush().unwrap();
+        }
+
+        std::thread::sleep(interval);
+    }
+
+    tracker.report(&mut out, "fit").unwrap();
+}
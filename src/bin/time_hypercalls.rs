@@ -0,0 +1,269 @@
+//! Measure the cost of each 0sim hypercall, compared with a plain syscall and with the timer
+//! itself (an empty `rdtsc` pair).
+//!
+//! Each call is timed `N` times on one CPU, and its full distribution is reported. Note that the
+//! `calibrate` hypercall alternates between nudging the offset up and down, so that it ends where
+//! it started, and that `pf_time` sets the PF_TIME (to `--pftime`, or 0). Since that overwrites the
+//! simulator's PF_TIME, `pf_time` is only timed by default if `--pftime` is given.
+//!
+//! NOTE: all measurements are done with `rdtsc`, which reports cycle counts.
+
+use bmk_linux::timing::MemoizedTimingData;
+
+use clap::clap_app;
+
+use paperexp::{
+    clock,
+    hypervisor::{is_backend, Backend, Hypervisor},
+    output::{is_format, ClockSource, Format, Output, Record, Units},
+    stats::report_measurements,
+    timer::{is_timer, Stopwatch},
+    topology,
+};
+
+/// The number of untimed calls made before timing each call.
+const WARMUP: usize = 1000;
+
+fn is_usize(arg: String) -> Result<(), String> {
+    arg.to_string()
+        .parse::<usize>()
+        .map_err(|_| "Not a valid usize".to_owned())
+        .map(|_| ())
+}
+
+fn is_calls(arg: String) -> Result<(), String> {
+    arg.split(',')
+        .try_for_each(|call| Call::parse(call).map(|_| ()))
+}
+
+/// The things that can be timed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Call {
+    HostElapsed,
+    Nop,
+    Calibrate,
+    PfTime,
+
+    /// Baseline: `getppid`, which glibc does not cache.
+    Syscall,
+
+    /// Baseline: nothing at all, i.e. the timer itself.
+    Rdtsc,
+}
+
+impl Call {
+    const ALL: &'static [Call] = &[
+        Call::HostElapsed,
+        Call::Nop,
+        Call::Calibrate,
+        Call::PfTime,
+        Call::Syscall,
+        Call::Rdtsc,
+    ];
+
+    fn parse(s: &str) -> Result<Self, String> {
+        Call::ALL
+            .iter()
+            .find(|call| call.name() == s)
+            .cloned()
+            .ok_or_else(|| {
+                format!(
+                    "Not a valid call (expected host_elapsed, nop, calibrate, pf_time, syscall or \
+                     rdtsc): {}",
+                    s
+                )
+            })
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Call::HostElapsed => "host_elapsed",
+            Call::Nop => "nop",
+            Call::Calibrate => "calibrate",
+            Call::PfTime => "pf_time",
+            Call::Syscall => "syscall",
+            Call::Rdtsc => "rdtsc",
+        }
+    }
+}
+
+fn main() {
+    let matches = clap_app! { time_hypercalls =>
+        (about: "Measures the cost of each hypercall, compared with a syscall and an rdtsc pair")
+        (@arg N: +required {is_usize} "The number of times to time each call.")
+        (@arg CALLS: --calls +takes_value {is_calls}
+         "A comma-separated list of the calls to time: `host_elapsed`, `nop`, `calibrate`, \
+          `pf_time`, `syscall` and `rdtsc` (default: all of them, except `pf_time` unless \
+          --pftime is given).")
+        (@arg CPU: --cpu +takes_value {is_usize}
+         "The CPU to run on (default: the first one this process may use).")
+        (@arg PFTIME: --pftime +takes_value {is_usize}
+         "The PF_TIME to set with each `pf_time` hypercall (default: 0).")
+        (@arg TIMER: --timer +takes_value {is_timer}
+         "How to read the TSC around each call: `rdtsc` (default; not serialized), `lfence` \
+          (`lfence; rdtsc; lfence`), `rdtscp` (`rdtscp; lfence`) or `cpuid` (`cpuid; rdtsc` and \
+          `rdtscp; cpuid`).")
+        (@arg SUBTRACT: --subtract_overhead
+         "Subtract the overhead of the timer, measured at startup, from each measurement.")
+        (@arg NS: --ns
+         "Report rdtsc measurements in nanoseconds instead of cycles, using the TSC frequency \
+          (detected automatically).")
+        (@arg FORMAT: --format +takes_value {is_format}
+         "The output format: `text` (default), `jsonl` or `csv`.")
+        (@arg BACKEND: --backend +takes_value {is_backend}
+         "The hypervisor backend to use for hypercalls: `vmcall` (default, requires a 0sim \
+          guest) or `sim` (simulated in-process).")
+    }
+    .get_matches();
+
+    let n = matches.value_of("N").unwrap().parse().unwrap();
+    let calls: Vec<Call> = matches.value_of("CALLS").map_or_else(
+        || {
+            Call::ALL
+                .iter()
+                .cloned()
+                .filter(|&call| call != Call::PfTime || matches.is_present("PFTIME"))
+                .collect()
+        },
+        |calls| calls.split(',').map(|c| Call::parse(c).unwrap()).collect(),
+    );
+    let pf_time = matches
+        .value_of("PFTIME")
+        .map_or(0, |pf_time| pf_time.parse().unwrap());
+
+    // Choose how to do hypercalls.
+    let hv = Backend::from_arg(matches.value_of("BACKEND"));
+
+    // CPU pinning
+    let cpu = matches.value_of("CPU").map_or_else(
+        || {
+            topology::affinity()
+                .expect("unable to read CPU affinity")
+                .first()
+                .unwrap()
+        },
+        |cpu| cpu.parse().unwrap(),
+    );
+    paperexp::set_cpu(cpu).expect("unable to pin thread");
+
+    // How to print results
+    let mut out = Output::stdout(Format::from_arg(matches.value_of("FORMAT")));
+
+    // Convert cycles to time, if requested.
+    if matches.is_present("NS") {
+        let tsc = clock::tsc();
+        tsc.report(&mut out).unwrap();
+        out.convert_cycles(tsc);
+    }
+
+    // How to time each call.
+    let stopwatch = Stopwatch::from_args(matches.value_of("TIMER"), matches.is_present("SUBTRACT"));
+    stopwatch.report_if_chosen(&mut out).unwrap();
+
+    out.emit(
+        format_args!("CPU: {}", cpu),
+        &[Record::new("setup", "cpu", cpu)],
+    )
+    .unwrap();
+
+    ///////////////////////////////////////////////////////////////////////////
+    // Start the experiment
+    ///////////////////////////////////////////////////////////////////////////
+
+    let results: Vec<(Call, Vec<u64>)> = calls
+        .iter()
+        .map(|&call| (call, time_call(call, n, &hv, &stopwatch, pf_time)))
+        .collect();
+
+    for (call, measurements) in results.iter() {
+        report_measurements(
+            &mut out,
+            call.name(),
+            ClockSource::Rdtsc,
+            Units::Cycles,
+            measurements,
+        )
+        .unwrap();
+    }
+
+    // Compare the medians with the baselines, if they were measured.
+    // (`MemoizedTimingData` remembers the first data it is given, so each call needs its own.)
+    let median = |call| {
+        results
+            .iter()
+            .find(|(c, _)| *c == call)
+            .filter(|(_, measurements)| !measurements.is_empty())
+            .map(|(_, measurements)| MemoizedTimingData::new().percentile(measurements, 50))
+    };
+    let timer = median(Call::Rdtsc);
+    let syscall = median(Call::Syscall);
+
+    for &call in calls.iter() {
+        if call == Call::Rdtsc || call == Call::Syscall {
+            continue;
+        }
+        let median = match median(call) {
+            Some(median) => median,
+            None => continue,
+        };
+
+        if let Some(timer) = timer {
+            out.emit(
+                format_args!(
+                    "{}: {} cycles more than the timer alone",
+                    call.name(),
+                    median - timer
+                ),
+                &[Record::new(call.name(), "p50_over_timer", median - timer)
+                    .clock(ClockSource::Rdtsc)
+                    .units(Units::Cycles)],
+            )
+            .unwrap();
+        }
+        if let Some(syscall) = syscall {
+            out.emit(
+                format_args!("{}: {}% of a syscall", call.name(), median / syscall * 100.),
+                &[
+                    Record::new(call.name(), "p50_vs_syscall", median / syscall * 100.)
+                        .units(Units::Percent),
+                ],
+            )
+            .unwrap();
+        }
+    }
+}
+
+/// Time `n` calls, after a warmup.
+fn time_call(call: Call, n: usize, hv: &Backend, stopwatch: &Stopwatch, pf_time: u64) -> Vec<u64> {
+    let mut measurements = vec![0; n];
+
+    // Alternate the direction of `calibrate`, so that the offset ends where it started.
+    let once = |i: usize| match call {
+        Call::HostElapsed => {
+            hv.host_elapsed();
+        }
+        Call::Nop => hv.nop(),
+        Call::Calibrate => hv.calibrate(i.is_multiple_of(2)),
+        Call::PfTime => hv.pf_time(pf_time),
+        Call::Syscall => unsafe {
+            libc::syscall(libc::SYS_getppid);
+        },
+        Call::Rdtsc => {}
+    };
+
+    for i in 0..WARMUP {
+        once(i);
+    }
+
+    for (i, measurement) in measurements.iter_mut().enumerate() {
+        let start = stopwatch.start();
+        once(i);
+        *measurement = stopwatch.stop(start);
+    }
+
+    if call == Call::Calibrate && n % 2 == 1 {
+        hv.calibrate(false);
+    }
+
+    measurements
+}
@@ -7,6 +7,12 @@ use clap::clap_app;
 
 use bmk_linux::timing::rdtsc;
 
+use paperexp::{
+    clock,
+    output::{is_format, ClockSource, Format, Output, Record, Units},
+    stats::{is_trim, Summary, Trim},
+};
+
 fn is_int(arg: String) -> Result<(), String> {
     arg.to_string()
         .parse::<usize>()
@@ -17,6 +23,16 @@ fn is_int(arg: String) -> Result<(), String> {
 fn main() {
     let matches = clap_app! { time_loop =>
         (@arg N: +required {is_int} "The number of iterations")
+        (@arg SUMMARY: --summary
+         "Also report summary statistics of the time between consecutive timestamps.")
+        (@arg TRIM: --trim +takes_value {is_trim} requires[SUMMARY]
+         "Remove outliers before summarizing: `none` (default), `pct:<p>` (p% from each end) or \
+          `iqr[:<k>]` (more than k interquartile ranges beyond the quartiles; k defaults to 1.5).")
+        (@arg NS: --ns
+         "Report rdtsc measurements in nanoseconds instead of cycles, using the TSC frequency \
+          (detected automatically).")
+        (@arg FORMAT: --format +takes_value {is_format}
+         "The output format: `text` (default), `jsonl` or `csv`.")
     }
     .get_matches();
 
@@ -28,6 +44,16 @@ fn main() {
         .parse::<usize>()
         .unwrap();
 
+    // How to print results
+    let mut out = Output::stdout(Format::from_arg(matches.value_of("FORMAT")));
+
+    // Convert cycles to time, if requested.
+    if matches.is_present("NS") {
+        let tsc = clock::tsc();
+        tsc.report(&mut out).unwrap();
+        out.convert_cycles(tsc);
+    }
+
     // Results array
     let mut results = Vec::with_capacity(n);
 
@@ -41,7 +67,23 @@ fn main() {
     }
 
     // Print results and final time stamp
-    for ts in results.iter() {
-        println!("{}", ts);
+    for (i, &ts) in results.iter().enumerate() {
+        out.emit(
+            format_args!("{}", ts),
+            &[Record::new("loop", "timestamp", ts)
+                .iteration(i)
+                .clock(ClockSource::Rdtsc)
+                .units(Units::Cycles)],
+        )
+        .unwrap();
+    }
+
+    if matches.is_present("SUMMARY") {
+        Summary::new(
+            results.windows(2).map(|ts| (ts[1] - ts[0]) as f64),
+            Trim::from_arg(matches.value_of("TRIM")),
+        )
+        .report(&mut out, "loop", ClockSource::Rdtsc, Units::Cycles)
+        .unwrap();
     }
 }
@@ -1,9 +1,30 @@
 //! Touch the given number of pages. Record the total time taken, peridically record elapsed time.
 //! Fill the pages with the requested pattern.
 //!
+//! With `--faults`, the latency of every k-th touch is also recorded, along with what kind of fault
+//! it caused: minor or major (from `getrusage`), on a huge or base page (from `/proc/self/pagemap`
+//! and `/proc/kpageflags`, which require root). The latency distribution of each kind is reported at
+//! the end. The extra `getrusage` calls slow down the loop, so they also inflate the periodic
+//! timestamps.
+//!
 //! NOTE: all measurements are done with `rdtsc`, which reports cycle counts.
+//!
+//! `--sample` can sample kernel and process metrics in the background while pages are touched (see
+//! `paperexp::sampler`).
+//!
+//! With `--threads`, the pages are divided between several threads, each pinned to its own CPU,
+//! and each thread's timestamps are reported separately, so that contention in the kernel (e.g. on
+//! the page table lock) shows up as threads slowing each other down. `--numa` places the memory on
+//! NUMA nodes.
 
-use std::ptr;
+use std::{
+    collections::BTreeMap,
+    iter::StepBy,
+    ops::Range,
+    ptr,
+    sync::{Arc, Barrier},
+    time::Duration,
+};
 
 use bmk_linux::{
     resultarray::{ResultArray, PAGE_SIZE},
@@ -12,16 +33,186 @@ use bmk_linux::{
 
 use clap::clap_app;
 
-use libc::{
-    mmap as libc_mmap, MAP_ANONYMOUS, MAP_FAILED, MAP_POPULATE, MAP_PRIVATE, PROT_READ, PROT_WRITE,
+use paperexp::{
+    calibration::Calibration,
+    clock,
+    hypervisor::{is_backend, Backend, Hypervisor},
+    output::{is_format, ClockSource, Format, Output, Record, Units},
+    pagemap::{KPageFlagsFile, PageInspector, Pagemap},
+    region::{
+        is_backing, is_huge_pages, is_mem_policy, Backing, HugePages, MemPolicy, RegionBuilder,
+    },
+    sampler::{is_sources, Sampler, Source},
+    set_cpu,
+    stats::{is_trim, Histogram, Summary, Trim},
+    topology::{is_placement, CpuSet, Placement, Topology},
 };
 
 /// Either all zeros or counter values
+#[derive(Clone, Copy)]
 enum Pattern {
     Zeros,
     Counter,
 }
 
+/// How pages are divided among threads.
+#[derive(Clone, Copy)]
+enum Assignment {
+    /// Thread `t` of `n` touches pages `t`, `t + n`, `t + 2n`, ...
+    Interleaved,
+
+    /// Each thread touches a contiguous `1/n`-th of the region.
+    Partitioned,
+}
+
+impl Assignment {
+    /// The pages touched by thread `t` of `n`.
+    fn pages(self, t: usize, n: usize, npages: usize) -> StepBy<Range<usize>> {
+        match self {
+            Assignment::Interleaved => (t..npages).step_by(n),
+            Assignment::Partitioned => (npages * t / n..npages * (t + 1) / n).step_by(1),
+        }
+    }
+}
+
+/// One timed touch in `--faults` mode. The size is a power of two so that `ResultArray`s of these
+/// can fill whole pages.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Touch {
+    cycles: u64,
+
+    /// The index of the touched page.
+    page: u32,
+
+    /// The kind of fault (`NO_FAULT`, `MINOR_FAULT` or `MAJOR_FAULT`).
+    fault: u32,
+}
+
+const NO_FAULT: u32 = 0;
+const MINOR_FAULT: u32 = 1;
+const MAJOR_FAULT: u32 = 2;
+
+/// The number of (minor, major) page faults taken by this thread so far.
+fn faults() -> (libc::c_long, libc::c_long) {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    unsafe {
+        libc::getrusage(libc::RUSAGE_THREAD, &mut usage);
+    }
+    (usage.ru_minflt, usage.ru_majflt)
+}
+
+/// What one thread measured.
+struct Measurements {
+    first: u64,
+    last: u64,
+
+    /// Timestamps of every `freq`-th touch of this thread.
+    timestamps: Vec<u64>,
+    freq: usize,
+
+    /// Timed touches, in `--faults` mode.
+    touches: Vec<Touch>,
+}
+
+/// The parameters shared by all touching threads.
+#[derive(Clone, Copy)]
+struct Params {
+    /// The address of the region (as an integer, so that it can be sent to threads).
+    mapped: usize,
+    pattern: Pattern,
+
+    /// How many timestamps each thread can record.
+    nstats: usize,
+
+    /// Time every k-th touch, in `--faults` mode.
+    fault_freq: Option<usize>,
+}
+
+/// Touch the given pages, recording timestamps and (in `--faults` mode) the latency of touches.
+/// The results are kept in preallocated arrays while touching and copied out afterwards.
+fn touch(params: Params, pages: StepBy<Range<usize>>) -> Measurements {
+    let mapped = params.mapped as *mut u8;
+    let npages = pages.len();
+
+    // Frequency of recording stats (measure every freq-th operation).
+    let freq = if npages < params.nstats {
+        1
+    } else {
+        // We need to round up to account for a possible remainder.
+        npages / params.nstats + 1
+    };
+
+    // Results array
+    let mut results = ResultArray::new(params.nstats);
+
+    // Per-touch latencies, if requested. Allocated up front so that recording does not fault.
+    let mut touches = params.fault_freq.map(|k| {
+        let n = npages.div_ceil(k);
+        let per_page = PAGE_SIZE / std::mem::size_of::<Touch>();
+        ResultArray::<Touch>::new(n.max(1).next_multiple_of(per_page))
+    });
+
+    // The value to fill memory with
+    let mut val = 0;
+
+    // Get initial timestamp
+    let first = rdtsc();
+
+    // Touch all memory
+    for (i, page) in pages.enumerate() {
+        match (params.fault_freq, touches.as_mut()) {
+            (Some(k), Some(touches)) if i % k == 0 => {
+                let (minor, major) = faults();
+                let start = rdtsc();
+                unsafe {
+                    ptr::write_volatile(mapped.add(page * PAGE_SIZE), val);
+                }
+                let cycles = rdtsc() - start;
+                let (minor_after, major_after) = faults();
+
+                touches.push(Touch {
+                    cycles,
+                    page: page as u32,
+                    fault: if major_after > major {
+                        MAJOR_FAULT
+                    } else if minor_after > minor {
+                        MINOR_FAULT
+                    } else {
+                        NO_FAULT
+                    },
+                });
+            }
+
+            _ => unsafe {
+                *mapped.add(page * PAGE_SIZE) = val;
+            },
+        }
+
+        // Maybe take a measurement
+        if i % freq == 0 {
+            results.push(rdtsc());
+        }
+
+        // Update val
+        val = match params.pattern {
+            Pattern::Zeros => val,
+            Pattern::Counter => val.wrapping_add(1),
+        };
+    }
+
+    // Final time stamp
+    let last = rdtsc();
+
+    Measurements {
+        first,
+        last,
+        timestamps: results.iter().cloned().collect(),
+        freq,
+        touches: touches.map_or_else(Vec::new, |t| t.iter().cloned().collect()),
+    }
+}
+
 fn is_int(arg: String) -> Result<(), String> {
     arg.to_string()
         .parse::<usize>()
@@ -40,8 +231,65 @@ fn main() {
             (@arg zeros: -z "Fill pages with zeros")
             (@arg counter: -c "Fill pages with counter values")
         )
+        (@arg FAULTS: --faults +takes_value {is_int}
+         "Record the latency and fault kind of every FAULTS-th touch (1 means every touch) and \
+          report the latency distribution of each kind of fault.")
+        (@arg THREADS: --threads +takes_value {is_int}
+         "Touch the pages from this many threads, each pinned to its own CPU (default: 1, \
+          unpinned). Each thread's timestamps are reported separately.")
+        (@arg ASSIGN: --assign +takes_value possible_values(&["interleaved", "partitioned"])
+         requires[THREADS]
+         "How to divide pages between threads: `partitioned` (default; contiguous chunks) or \
+          `interleaved` (thread t of n touches every n-th page, starting at t).")
+        (@arg PLACEMENT: --placement +takes_value {is_placement} requires[THREADS]
+         "Which CPUs to pin threads to: `cpus` (default; every usable CPU), `cores` (one per \
+          physical core) or `node:<n>` (the CPUs of NUMA node n).")
+        (@arg NUMA: --numa +takes_value {is_mem_policy}
+         "Place the memory on NUMA nodes with mbind: `default`, `preferred:<node>`, \
+          `bind:<nodes>` or `interleave:<nodes>`, where <nodes> is a list like `0,2-3`.")
+        (@arg NUMA_THREAD: --numa_thread requires[NUMA]
+         "Set the NUMA policy for each touching thread with set_mempolicy instead of for the \
+          region with mbind.")
+        (@arg SUMMARY: --summary
+         "Also report summary statistics of the time per touch between consecutive timestamps, \
+          for each thread and for all threads.")
+        (@arg TRIM: --trim +takes_value {is_trim} requires[SUMMARY]
+         "Remove outliers before summarizing: `none` (default), `pct:<p>` (p% from each end) or \
+          `iqr[:<k>]` (more than k interquartile ranges beyond the quartiles; k defaults to 1.5).")
         (@arg STATS_GB: --stats_gb {is_int} +takes_value
          "Amount of memory used to store stats (in GB).")
+        (@arg AUDIT: --audit
+         "Report how the memory is backed, page by page, from /proc/self/pagemap (see \
+          `page_audit`).")
+        (@arg USAGE: --usage
+         "Report how much of the memory is resident and backed by huge pages, from \
+          /proc/self/smaps.")
+        (@arg HUGE: --huge +takes_value {is_huge_pages}
+         "How to use huge pages: `default` (the system's THP settings), `thp` (MADV_HUGEPAGE), \
+          `nothp` (MADV_NOHUGEPAGE), `hugetlb:2m` or `hugetlb:1g`.")
+        (@arg BACKING: --backing +takes_value {is_backing}
+         "What backs the memory: `anon` (default), `memfd` or `file:<path>` (e.g. on tmpfs or \
+          hugetlbfs; a directory gets a new unlinked file).")
+        (@arg SAMPLE: --sample +takes_value {is_sources}
+         "Sample the given comma-separated list of things in the background: `compaction`, \
+          `page_tables`, `vmstat[:<prefix>]`, `thp`, `buddyinfo`, `extfrag[:<order>]` or \
+          `rss[:<pid>]`.")
+        (@arg SAMPLE_INTERVAL: --sample_interval +takes_value {is_int} requires[SAMPLE]
+         "The interval between samples in milliseconds (default: 1000).")
+        (@arg SAMPLE_FILE: --sample_file +takes_value requires[SAMPLE]
+         "The location to write samples to (default: stdout, alongside the other output).")
+        (@arg NS: --ns
+         "Report rdtsc measurements in nanoseconds instead of cycles, using the TSC frequency \
+          (detected automatically).")
+        (@arg FORMAT: --format +takes_value {is_format}
+         "The output format: `text` (default), `jsonl` or `csv`.")
+        (@arg BACKEND: --backend +takes_value {is_backend}
+         "The hypervisor backend to use for hypercalls: `vmcall` (default, requires a 0sim \
+          guest) or `sim` (simulated in-process).")
+        (@arg CALIBRATION: --calibration +takes_value
+         "Check the calibration saved by time_calibrate at the given path (by default, \
+          /tmp/time_calibrate.state) before measuring, and exit if it is missing, did not \
+          converge or predates the last reboot.")
     }
     .get_matches();
 
@@ -61,19 +309,22 @@ fn main() {
         .unwrap();
     let npages = npages - (stats_gb << 18); // subtract out space for stats
 
-    // How many times to record stats (each measurement is 8B, 1GB total)?
-    let nstats = (stats_gb << 30) / 8;
-
-    // Frequency of recording stats (measure every freq-th operation).
-    let freq = if npages < nstats {
-        1
-    } else {
-        // We need to round up to account for a possible remainder.
-        npages / nstats + 1
+    // How many threads, and how to divide the pages between them?
+    let nthreads = matches
+        .value_of("THREADS")
+        .map(|n| n.parse::<usize>().unwrap().max(1))
+        .unwrap_or(1);
+    let placement = Placement::from_arg(matches.value_of("PLACEMENT"));
+    let assignment = match matches.value_of("ASSIGN") {
+        Some("interleaved") => Assignment::Interleaved,
+        Some("partitioned") | None => Assignment::Partitioned,
+        Some(_) => unreachable!(),
     };
 
-    // Results array
-    let mut results = ResultArray::new(nstats);
+    // How many times to record stats (each measurement is 8B, 1GB total)? The memory is split
+    // evenly between threads.
+    let per_page = PAGE_SIZE / std::mem::size_of::<u64>();
+    let nstats = ((stats_gb << 30) / 8 / nthreads / per_page).max(1) * per_page;
 
     // What pattern to use?
     let pattern = if matches.is_present("zeros") {
@@ -84,71 +335,265 @@ fn main() {
         unreachable!()
     };
 
+    // How to print results
+    let format = Format::from_arg(matches.value_of("FORMAT"));
+    let mut out = Output::stdout(format);
+
+    // Convert cycles to time, if requested.
+    if matches.is_present("NS") {
+        let tsc = clock::tsc();
+        tsc.report(&mut out).unwrap();
+        out.convert_cycles(tsc);
+    }
+
     // Should we prefault?
     let prefault = matches.is_present("PREFAULT");
 
+    // Choose how to do hypercalls.
+    let hv = Backend::from_arg(matches.value_of("BACKEND"));
+
+    // Make sure that the hypervisor was calibrated, if requested.
+    if let Some(state) = matches.value_of("CALIBRATION") {
+        match Calibration::check(state) {
+            Ok(calibration) => calibration.report(&mut out).unwrap(),
+            Err(problem) => {
+                eprintln!("{}", problem);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // Where to put the memory.
+    let mem_policy = matches
+        .value_of("NUMA")
+        .map(|p| MemPolicy::parse(p).unwrap());
+    let thread_policy = matches.is_present("NUMA_THREAD");
+
     ///////////////////////////////////////////////////////////////////////////
     // Start the experiment
     ///////////////////////////////////////////////////////////////////////////
 
     // Mmap memory for the experiment
-    let mapped = unsafe {
-        let addr = libc_mmap(
-            ptr::null_mut(),
-            npages * PAGE_SIZE,
-            PROT_READ | PROT_WRITE,
-            if prefault {
-                MAP_PRIVATE | MAP_ANONYMOUS | MAP_POPULATE
-            } else {
-                MAP_PRIVATE | MAP_ANONYMOUS
-            },
-            -1,
-            0,
-        );
+    let region = RegionBuilder::new(npages * PAGE_SIZE)
+        .huge_pages(HugePages::from_arg(matches.value_of("HUGE")))
+        .backing(Backing::from_arg(matches.value_of("BACKING")))
+        .mem_policy(mem_policy.clone().filter(|_| !thread_policy))
+        .populate(prefault)
+        .map()
+        .expect("Unable to mmap");
+    let mapped = region.as_ptr();
 
-        if addr == MAP_FAILED {
-            panic!("Unable to mmap");
-        }
-
-        addr as *mut u8
+    let params = Params {
+        mapped: mapped as usize,
+        pattern,
+        nstats,
+        fault_freq: matches
+            .value_of("FAULTS")
+            .map(|k| k.parse::<usize>().unwrap().max(1)),
     };
 
-    // The value to fill memory with
-    let mut val = 0;
-
     // Set the PF time.
     if let Some(pf_time) = matches.value_of("PFTIME") {
         let pf_time = pf_time.to_string().parse::<u64>().unwrap();
-        paperexp::vmcall_pf_time(pf_time);
+        hv.pf_time(pf_time);
     }
 
-    // Get initial timestamp
-    let first = rdtsc();
+    // Start sampling in the background, if requested.
+    let sampler = matches.value_of("SAMPLE").map(|sources| {
+        Sampler::start(
+            Source::parse_list(sources).unwrap(),
+            Duration::from_millis(
+                matches
+                    .value_of("SAMPLE_INTERVAL")
+                    .map(|i| i.parse().unwrap())
+                    .unwrap_or(1000),
+            ),
+            match matches.value_of("SAMPLE_FILE") {
+                Some(path) => Output::create(format, path).unwrap(),
+                None => out.share(),
+            },
+        )
+    });
 
-    // Touch all memory
-    for i in 0..npages {
-        unsafe {
-            *mapped.offset((i * PAGE_SIZE) as isize) = val;
+    // Touch all memory. A single thread runs unpinned on the main thread, as it always has.
+    // Otherwise, each thread is pinned to its own CPU according to the placement (wrapping around
+    // if there are more threads than CPUs), and they all start together.
+    let measurements: Vec<(Option<CpuSet>, Measurements)> = if nthreads == 1 && !thread_policy {
+        vec![(
+            None,
+            touch(params, Assignment::Partitioned.pages(0, 1, npages)),
+        )]
+    } else {
+        let cores = Topology::read()
+            .and_then(|topology| topology.place(placement, nthreads))
+            .expect("unable to place threads");
+        let barrier = Arc::new(Barrier::new(nthreads));
+
+        let threads: Vec<_> = (0..nthreads)
+            .zip(cores)
+            .map(|(t, core)| {
+                let barrier = Arc::clone(&barrier);
+                let mem_policy = mem_policy.clone().filter(|_| thread_policy);
+                std::thread::spawn(move || {
+                    set_cpu(core.clone()).expect("unable to pin thread");
+                    if let Some(policy) = mem_policy {
+                        policy
+                            .set_for_thread()
+                            .expect("unable to set memory policy");
+                    }
+                    barrier.wait();
+                    (
+                        Some(core),
+                        touch(params, assignment.pages(t, nthreads, npages)),
+                    )
+                })
+            })
+            .collect();
+
+        threads.into_iter().map(|t| t.join().unwrap()).collect()
+    };
+
+    if let Some(sampler) = sampler {
+        sampler.stop();
+    }
+
+    // Print results
+    for (t, (core, m)) in measurements.iter().enumerate() {
+        let phase = match core {
+            None => "touch".to_owned(),
+            Some(_) => format!("touch.{}", t),
+        };
+        let record = |metric, ts| {
+            Record::new(&phase, metric, ts)
+                .clock(ClockSource::Rdtsc)
+                .units(Units::Cycles)
+        };
+
+        if let Some(core) = core {
+            out.text(format_args!("Thread {} (CPU {}):", t, core))
+                .unwrap();
         }
+        out.emit(
+            format_args!("First: {}", m.first),
+            &[record("first", m.first)],
+        )
+        .unwrap();
+        out.emit(format_args!("Last: {}", m.last), &[record("last", m.last)])
+            .unwrap();
+    }
 
-        // Maybe take a measurement
-        if i % freq == 0 {
-            results.push(rdtsc());
+    // How much of the memory ended up huge?
+    if matches.is_present("USAGE") {
+        region
+            .usage()
+            .expect("unable to read smaps")
+            .report(&mut out, "region", region.len())
+            .unwrap();
+    }
+
+    // Where did the memory land?
+    if matches.is_present("AUDIT") {
+        PageInspector::open(None)
+            .and_then(|inspector| inspector.summarize(region.as_ptr() as usize, region.len()))
+            .expect("unable to audit pages")
+            .report(&mut out, "audit")
+            .unwrap();
+    }
+
+    for (t, (core, m)) in measurements.iter().enumerate() {
+        let phase = match core {
+            None => "touch".to_owned(),
+            Some(_) => format!("touch.{}", t),
+        };
+
+        if core.is_some() {
+            out.text(format_args!("Thread {}:", t)).unwrap();
+        }
+        for (i, &ts) in m.timestamps.iter().enumerate() {
+            out.emit(
+                format_args!("{}", ts),
+                &[Record::new(&phase, "timestamp", ts)
+                    .iteration(i * m.freq)
+                    .clock(ClockSource::Rdtsc)
+                    .units(Units::Cycles)],
+            )
+            .unwrap();
         }
+    }
 
-        // Update val
-        val = match pattern {
-            Pattern::Zeros => val,
-            Pattern::Counter => val + 1,
+    // Time per touch between timestamps, for each thread and overall
+    if matches.is_present("SUMMARY") {
+        let trim = Trim::from_arg(matches.value_of("TRIM"));
+        let per_touch = |m: &Measurements| -> Vec<f64> {
+            m.timestamps
+                .windows(2)
+                .map(|ts| (ts[1] - ts[0]) as f64 / m.freq as f64)
+                .collect()
         };
+
+        if measurements[0].0.is_some() {
+            for (t, (_, m)) in measurements.iter().enumerate() {
+                Summary::new(per_touch(m), trim)
+                    .report(
+                        &mut out,
+                        &format!("touch.{}", t),
+                        ClockSource::Rdtsc,
+                        Units::Cycles,
+                    )
+                    .unwrap();
+            }
+        }
+
+        Summary::new(measurements.iter().flat_map(|(_, m)| per_touch(m)), trim)
+            .report(&mut out, "touch", ClockSource::Rdtsc, Units::Cycles)
+            .unwrap();
     }
 
-    // Print results and final time stamp
-    let last = rdtsc();
-    println!("First: {}", first);
-    println!("Last: {}", last);
+    // Latency distribution by fault kind, over all threads
+    if params.fault_freq.is_some() {
+        report_faults(
+            measurements.iter().flat_map(|(_, m)| m.touches.iter()),
+            mapped,
+            &mut out,
+        );
+    }
+}
+
+/// Classify each touch by fault kind and page size, and report the latency distribution of each.
+fn report_faults<'a>(touches: impl Iterator<Item = &'a Touch>, mapped: *mut u8, out: &mut Output) {
+    // Page sizes need the PFN, so they are only available as root.
+    let pages = Pagemap::open(None).and_then(|pagemap| Ok((pagemap, KPageFlagsFile::open()?)));
+    if let Err(ref e) = pages {
+        eprintln!("unable to tell huge and base pages apart: {}", e);
+    }
+
+    let mut hists: BTreeMap<String, Histogram> = BTreeMap::new();
+
+    for touch in touches {
+        let fault = match touch.fault {
+            MAJOR_FAULT => "major",
+            MINOR_FAULT => "minor",
+            _ => "none",
+        };
+
+        let vaddr = mapped as usize + touch.page as usize * PAGE_SIZE;
+        let size = match pages {
+            Ok((ref pagemap, ref kpageflags)) => match pagemap.entry(vaddr).unwrap().pfn() {
+                Some(pfn) if kpageflags.flags(pfn).unwrap().huge() => "huge",
+                Some(_) => "base",
+                None => "unknown",
+            },
+            Err(_) => "unknown",
+        };
+
+        hists
+            .entry(format!("fault.{}.{}", fault, size))
+            .or_default()
+            .record(touch.cycles);
+    }
 
-    for ts in results.iter() {
-        println!("{}", ts);
+    for (phase, hist) in hists.iter() {
+        hist.report(out, phase, ClockSource::Rdtsc, Units::Cycles)
+            .unwrap();
     }
 }
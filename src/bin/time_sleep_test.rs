@@ -1,87 +1,514 @@
-use std::{thread::sleep, time::Duration};
+//! Measure how long blocking and wakeup primitives take, e.g. to see how the simulator's time
+//! virtualization distorts each wakeup path.
+//!
+//! The timed benchmarks (`sleep`, `nanosleep`, `clock_nanosleep`, `epoll` and `timerfd`) block for
+//! each of the given durations in turn, and report each as its own phase (e.g. `nanosleep.10us`)
+//! if there are several. The round-trip benchmarks (`futex`, `condvar` and `pipe`) bounce between
+//! two threads pinned according to `--placement`, and time a wakeup there and back.
+//!
+//! `sleep`, `nop` and `lock` print the same summary lines as they always have; the other benchmarks
+//! report their full distributions. `--summary` replaces either with `stats::Summary`, which adds a
+//! confidence interval and can trim outliers.
+//!
+//! NOTE: all measurements are done with `rdtsc`, which reports cycle counts.
 
-use bmk_linux::timing::{rdtsc, MemoizedTimingData};
+use std::{
+    ffi::c_void,
+    io, ptr,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread::sleep,
+    time::Duration,
+};
+
+use bmk_linux::timing::MemoizedTimingData;
+
+use clap::clap_app;
+
+use paperexp::{
+    clock::{self, is_durations, parse_duration},
+    output::{is_format, ClockSource, Format, Output, Record, Units},
+    stats::{is_trim, report_measurements, Summary, Trim},
+    timer::{is_timer, Stopwatch},
+    topology::{is_placement, CpuSet, Placement, Topology},
+};
+
+fn is_usize(arg: String) -> Result<(), String> {
+    arg.to_string()
+        .parse::<usize>()
+        .map_err(|_| "Not a valid usize".to_owned())
+        .map(|_| ())
+}
 
 fn main() {
-    let measurements = match std::env::args().skip(1).next().as_ref().map(|s| s.as_str()) {
-        Some("sleep") => sleep_ms(),
-        Some("nop") => sleep_nop(),
-        Some("lock") => sleep_lock(),
-        Some(other) => panic!("unexpected value \"{}\"", other),
-        None => panic!("expected bmk name"),
+    let matches = clap_app! { time_sleep_test =>
+        (@arg BMK: +required possible_value[
+            sleep nop lock nanosleep clock_nanosleep futex condvar yield pipe epoll timerfd
+         ]
+         "The benchmark to run")
+        (@arg N: -n --iterations +takes_value {is_usize}
+         "The number of iterations for each duration (default: 100000000 for nop and lock, 10000 \
+          otherwise).")
+        (@arg DURATIONS: --durations +takes_value {is_durations}
+         "A comma-separated list of durations to block for, e.g. `1us,10us,1ms` (default: 10ms for \
+          sleep, 1ms,10ms for epoll, and 1us,10us,100us,1ms otherwise). epoll only takes whole \
+          milliseconds.")
+        (@arg PLACEMENT: --placement +takes_value {is_placement}
+         "Which CPUs to pin the two threads of futex, condvar and pipe to: `cpus` (default), \
+          `cores` (one per physical core) or `node:<n>` (the CPUs of NUMA node n).")
+        (@arg TIMER: --timer +takes_value {is_timer}
+         "How to read the TSC around each operation: `rdtsc` (default; not serialized), `lfence` \
+          (`lfence; rdtsc; lfence`), `rdtscp` (`rdtscp; lfence`) or `cpuid` (`cpuid; rdtsc` and \
+          `rdtscp; cpuid`).")
+        (@arg SUBTRACT: --subtract_overhead
+         "Subtract the overhead of the timer, measured at startup, from each measurement.")
+        (@arg SUMMARY: --summary
+         "Report summary statistics of each phase, with a confidence interval for the mean, instead \
+          of the default report.")
+        (@arg TRIM: --trim +takes_value {is_trim} requires[SUMMARY]
+         "Remove outliers before summarizing: `none` (default), `pct:<p>` (p% from each end) or \
+          `iqr[:<k>]` (more than k interquartile ranges beyond the quartiles; k defaults to 1.5).")
+        (@arg NS: --ns
+         "Report rdtsc measurements in nanoseconds instead of cycles, using the TSC frequency \
+          (detected automatically).")
+        (@arg FORMAT: --format +takes_value {is_format}
+         "The output format: `text` (default), `jsonl` or `csv`.")
+    }
+    .get_matches();
+
+    let bmk = matches.value_of("BMK").unwrap();
+
+    let n = matches.value_of("N").map_or_else(
+        || match bmk {
+            "nop" | "lock" => 100_000_000,
+            _ => 10_000,
+        },
+        |n| n.parse().unwrap(),
+    );
+
+    // Keep the durations as given, to name phases with.
+    let durations: Vec<(&str, Duration)> = matches
+        .value_of("DURATIONS")
+        .unwrap_or(match bmk {
+            "sleep" => "10ms",
+            "epoll" => "1ms,10ms",
+            _ => "1us,10us,100us,1ms",
+        })
+        .split(',')
+        .map(|d| (d, parse_duration(d).unwrap()))
+        .collect();
+    if bmk == "epoll"
+        && durations
+            .iter()
+            .any(|(_, d)| d.subsec_nanos() % 1_000_000 != 0)
+    {
+        eprintln!("epoll timeouts must be whole milliseconds");
+        std::process::exit(1);
+    }
+
+    // Where the two threads of the round-trip benchmarks run. The others don't need the topology,
+    // so it is only read for those.
+    let cpus = || {
+        Topology::read()
+            .and_then(|topology| {
+                topology.place(Placement::from_arg(matches.value_of("PLACEMENT")), 2)
+            })
+            .expect("unable to read CPU topology")
     };
 
-    let mut md = MemoizedTimingData::new();
+    // How to time each operation.
+    let stopwatch = Stopwatch::from_args(matches.value_of("TIMER"), matches.is_present("SUBTRACT"));
 
-    let avg = md.avg(&measurements);
-    let sd = md.sd(&measurements);
+    ///////////////////////////////////////////////////////////////////////////
+    // Start the experiment
+    ///////////////////////////////////////////////////////////////////////////
 
-    println!("avg: {}", avg);
-    println!("sd: {} ({}%)", sd, sd / avg * 100.);
-    println!("50%: {}", md.percentile(&measurements, 50));
-    println!("75%: {}", md.percentile(&measurements, 75));
-    println!("90%: {}", md.percentile(&measurements, 90));
-    println!("99%: {}", md.percentile(&measurements, 99));
-    println!("99.9%: {}", md.permicrotile(&measurements, 999_000));
-    println!("99.99%: {}", md.permicrotile(&measurements, 999_900));
-    println!("99.999%: {}", md.permicrotile(&measurements, 999_990));
-    println!("99.9999%: {}", md.permicrotile(&measurements, 999_999));
-    println!("max: {}", md.max(&measurements));
+    // The measurements of each phase.
+    let results: Vec<(String, Vec<u64>)> = match bmk {
+        "nop" => vec![(bmk.to_owned(), time_nop(n, &stopwatch))],
+        "lock" => vec![(bmk.to_owned(), time_lock(n, &stopwatch))],
+        "yield" => vec![(bmk.to_owned(), time_yield(n, &stopwatch))],
+        "futex" => vec![(bmk.to_owned(), time_futex(n, &stopwatch, &cpus()))],
+        "condvar" => vec![(bmk.to_owned(), time_condvar(n, &stopwatch, &cpus()))],
+        "pipe" => vec![(bmk.to_owned(), time_pipe(n, &stopwatch, &cpus()))],
+        _ => durations
+            .iter()
+            .map(|&(name, duration)| {
+                let measurements = match bmk {
+                    "sleep" => time_sleep(n, &stopwatch, duration),
+                    "nanosleep" => time_nanosleep(n, &stopwatch, duration),
+                    "clock_nanosleep" => time_clock_nanosleep(n, &stopwatch, duration),
+                    "epoll" => time_epoll(n, &stopwatch, duration),
+                    "timerfd" => time_timerfd(n, &stopwatch, duration),
+                    _ => unreachable!(),
+                };
+
+                // With several durations, each gets its own phase.
+                let phase = if durations.len() > 1 {
+                    format!("{}.{}", bmk, name)
+                } else {
+                    bmk.to_owned()
+                };
+                (phase, measurements)
+            })
+            .collect(),
+    };
+
+    // How to print results
+    let mut out = Output::stdout(Format::from_arg(matches.value_of("FORMAT")));
+
+    // Convert cycles to time, if requested.
+    if matches.is_present("NS") {
+        let tsc = clock::tsc();
+        tsc.report(&mut out).unwrap();
+        out.convert_cycles(tsc);
+    }
+
+    stopwatch.report_if_chosen(&mut out).unwrap();
+
+    let summary = matches.is_present("SUMMARY");
+    let trim = Trim::from_arg(matches.value_of("TRIM"));
+
+    for (phase, measurements) in results.iter() {
+        match bmk {
+            _ if summary => Summary::new(measurements.iter().map(|&m| m as f64), trim)
+                .report(&mut out, phase, ClockSource::Rdtsc, Units::Cycles)
+                .unwrap(),
+
+            // The original benchmarks keep their original output.
+            "sleep" | "nop" | "lock" => {
+                if results.len() > 1 {
+                    out.text(format_args!("{}:", phase)).unwrap();
+                }
+                report_percentiles(&mut out, phase, measurements).unwrap();
+            }
+            _ => report_measurements(
+                &mut out,
+                phase,
+                ClockSource::Rdtsc,
+                Units::Cycles,
+                measurements,
+            )
+            .unwrap(),
+        }
+    }
 }
 
-fn sleep_ms() -> Vec<u64> {
-    const N: usize = 10000;
-    const MS: u64 = 10;
+/// Write the average, standard deviation, percentiles and maximum of `measurements`.
+fn report_percentiles(out: &mut Output, phase: &str, measurements: &[u64]) -> io::Result<()> {
+    let mut md = MemoizedTimingData::new();
 
-    let mut measurements = vec![0; N];
+    let avg = md.avg(measurements);
+    let sd = md.sd(measurements);
 
-    for measurement in measurements.iter_mut() {
-        let start = rdtsc();
+    let record = |metric, value: f64| {
+        Record::new(phase, metric, value)
+            .clock(ClockSource::Rdtsc)
+            .units(Units::Cycles)
+    };
+
+    out.emit(format_args!("avg: {}", avg), &[record("avg", avg)])?;
+    out.emit(
+        format_args!("sd: {} ({}%)", sd, sd / avg * 100.),
+        &[
+            record("sd", sd),
+            Record::new(phase, "sd_relative", sd / avg * 100.).units(Units::Percent),
+        ],
+    )?;
+
+    for &(name, metric, percentile) in &[
+        ("50%", "p50", 50),
+        ("75%", "p75", 75),
+        ("90%", "p90", 90),
+        ("99%", "p99", 99),
+    ] {
+        let value = md.percentile(measurements, percentile);
+        out.emit(
+            format_args!("{}: {}", name, value),
+            &[record(metric, value)],
+        )?;
+    }
+
+    for &(name, metric, permicrotile) in &[
+        ("99.9%", "p99.9", 999_000),
+        ("99.99%", "p99.99", 999_900),
+        ("99.999%", "p99.999", 999_990),
+        ("99.9999%", "p99.9999", 999_999),
+    ] {
+        let value = md.permicrotile(measurements, permicrotile);
+        out.emit(
+            format_args!("{}: {}", name, value),
+            &[record(metric, value)],
+        )?;
+    }
+
+    let max = md.max(measurements);
+    out.emit(format_args!("max: {}", max), &[record("max", max)])
+}
+
+/// Time `n` runs of `f`.
+fn time_each(n: usize, stopwatch: &Stopwatch, mut f: impl FnMut()) -> Vec<u64> {
+    let mut measurements = vec![0; n];
 
-        sleep(Duration::from_millis(MS));
+    for measurement in measurements.iter_mut() {
+        let start = stopwatch.start();
 
-        let elapsed = rdtsc() - start;
+        f();
 
-        *measurement = elapsed;
+        *measurement = stopwatch.stop(start);
     }
 
     measurements
 }
 
-fn sleep_nop() -> Vec<u64> {
-    const N: usize = 100_000_000;
+/// The timer alone.
+fn time_nop(n: usize, stopwatch: &Stopwatch) -> Vec<u64> {
+    time_each(n, stopwatch, || {})
+}
 
-    let mut measurements = vec![0; N];
+/// An uncontended lock and unlock.
+fn time_lock(n: usize, stopwatch: &Stopwatch) -> Vec<u64> {
+    let lock = Mutex::new(());
 
-    for measurement in measurements.iter_mut() {
-        let start = rdtsc();
+    time_each(n, stopwatch, || drop(lock.lock().unwrap()))
+}
 
-        let elapsed = rdtsc() - start;
+fn time_yield(n: usize, stopwatch: &Stopwatch) -> Vec<u64> {
+    time_each(n, stopwatch, || unsafe {
+        libc::sched_yield();
+    })
+}
 
-        *measurement = elapsed;
+/// `std::thread::sleep`.
+fn time_sleep(n: usize, stopwatch: &Stopwatch, duration: Duration) -> Vec<u64> {
+    time_each(n, stopwatch, || sleep(duration))
+}
+
+fn timespec(duration: Duration) -> libc::timespec {
+    libc::timespec {
+        tv_sec: duration.as_secs() as libc::time_t,
+        tv_nsec: duration.subsec_nanos() as libc::c_long,
     }
+}
+
+/// `nanosleep` for a relative duration.
+fn time_nanosleep(n: usize, stopwatch: &Stopwatch, duration: Duration) -> Vec<u64> {
+    let ts = timespec(duration);
+
+    time_each(n, stopwatch, || unsafe {
+        libc::nanosleep(&ts, ptr::null_mut());
+    })
+}
 
+/// `clock_nanosleep` until an absolute deadline `duration` from now on `CLOCK_MONOTONIC`.
+fn time_clock_nanosleep(n: usize, stopwatch: &Stopwatch, duration: Duration) -> Vec<u64> {
+    time_each(n, stopwatch, || unsafe {
+        let deadline = timespec(Duration::from_nanos(clock::monotonic_ns()) + duration);
+        libc::clock_nanosleep(
+            libc::CLOCK_MONOTONIC,
+            libc::TIMER_ABSTIME,
+            &deadline,
+            ptr::null_mut(),
+        );
+    })
+}
+
+/// `epoll_wait` on an empty epoll set, until it times out.
+fn time_epoll(n: usize, stopwatch: &Stopwatch, duration: Duration) -> Vec<u64> {
+    let epfd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+    assert!(epfd >= 0, "epoll_create1 failed");
+
+    let timeout = duration.as_millis() as libc::c_int;
+    let mut event = libc::epoll_event { events: 0, u64: 0 };
+    let measurements = time_each(n, stopwatch, || unsafe {
+        libc::epoll_wait(epfd, &mut event, 1, timeout);
+    });
+
+    unsafe { libc::close(epfd) };
     measurements
 }
 
-fn sleep_lock() -> Vec<u64> {
-    const N: usize = 100_000_000;
+/// Arm a one-shot `timerfd` and `read` it until it expires.
+fn time_timerfd(n: usize, stopwatch: &Stopwatch, duration: Duration) -> Vec<u64> {
+    let tfd = unsafe { libc::timerfd_create(libc::CLOCK_MONOTONIC, libc::TFD_CLOEXEC) };
+    assert!(tfd >= 0, "timerfd_create failed");
 
-    let mut measurements = vec![0; N];
+    let spec = libc::itimerspec {
+        it_interval: timespec(Duration::from_secs(0)),
+        it_value: timespec(duration),
+    };
+    let mut expirations = 0u64;
+    let measurements = time_each(n, stopwatch, || unsafe {
+        libc::timerfd_settime(tfd, 0, &spec, ptr::null_mut());
+        libc::read(tfd, &mut expirations as *mut u64 as *mut c_void, 8);
+    });
 
-    let lock = std::sync::Mutex::new(());
+    unsafe { libc::close(tfd) };
+    measurements
+}
 
-    for measurement in measurements.iter_mut() {
-        let start = rdtsc();
+/// Run `ping` on the first CPU, which is timed, and `pong` on the second, and return the
+/// measurements of `ping`.
+fn round_trips(
+    cpus: &[CpuSet],
+    ping: impl FnOnce() -> Vec<u64> + Send + 'static,
+    pong: impl FnOnce() + Send + 'static,
+) -> Vec<u64> {
+    let (ping_cpu, pong_cpu) = (cpus[0].clone(), cpus[1].clone());
 
-        {
-            let _ = lock.lock().unwrap();
-        }
+    let pong = std::thread::spawn(move || {
+        paperexp::set_cpu(pong_cpu).expect("unable to pin thread");
+        pong()
+    });
+    let ping = std::thread::spawn(move || {
+        paperexp::set_cpu(ping_cpu).expect("unable to pin thread");
+        ping()
+    });
 
-        let elapsed = rdtsc() - start;
+    let measurements = ping.join().unwrap();
+    pong.join().unwrap();
+    measurements
+}
 
-        *measurement = elapsed;
+fn futex_wait(word: &AtomicU32, val: u32) {
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            word.as_ptr(),
+            libc::FUTEX_WAIT | libc::FUTEX_PRIVATE_FLAG,
+            val,
+            ptr::null::<libc::timespec>(),
+        );
     }
+}
 
+fn futex_wake(word: &AtomicU32) {
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            word.as_ptr(),
+            libc::FUTEX_WAKE | libc::FUTEX_PRIVATE_FLAG,
+            1,
+        );
+    }
+}
+
+/// Wait until `word` has the value `val`.
+fn futex_wait_for(word: &AtomicU32, val: u32) {
+    loop {
+        let current = word.load(Ordering::Acquire);
+        if current == val {
+            return;
+        }
+        futex_wait(word, current);
+    }
+}
+
+/// Each iteration wakes the other thread with a futex, and waits to be woken back.
+fn time_futex(n: usize, stopwatch: &Stopwatch, cpus: &[CpuSet]) -> Vec<u64> {
+    let words = Arc::new((AtomicU32::new(0), AtomicU32::new(0)));
+    let stopwatch = *stopwatch;
+
+    let ping_words = Arc::clone(&words);
+    let ping = move || {
+        let (ping, pong) = &*ping_words;
+        let mut i = 0;
+        time_each(n, &stopwatch, || {
+            i += 1;
+            ping.store(i, Ordering::Release);
+            futex_wake(ping);
+            futex_wait_for(pong, i);
+        })
+    };
+
+    let pong = move || {
+        let (ping, pong) = &*words;
+        for i in 1..=n as u32 {
+            futex_wait_for(ping, i);
+            pong.store(i, Ordering::Release);
+            futex_wake(pong);
+        }
+    };
+
+    round_trips(cpus, ping, pong)
+}
+
+/// Each iteration notifies the other thread through a condition variable, and waits to be notified
+/// back.
+fn time_condvar(n: usize, stopwatch: &Stopwatch, cpus: &[CpuSet]) -> Vec<u64> {
+    // The number of the last ping and the last pong.
+    let state = Arc::new((Mutex::new((0usize, 0usize)), Condvar::new()));
+    let stopwatch = *stopwatch;
+
+    let ping_state = Arc::clone(&state);
+    let ping = move || {
+        let (lock, cvar) = &*ping_state;
+        let mut i = 0;
+        time_each(n, &stopwatch, || {
+            i += 1;
+            let mut turns = lock.lock().unwrap();
+            turns.0 = i;
+            cvar.notify_all();
+            while turns.1 != i {
+                turns = cvar.wait(turns).unwrap();
+            }
+        })
+    };
+
+    let pong = move || {
+        let (lock, cvar) = &*state;
+        for i in 1..=n {
+            let mut turns = lock.lock().unwrap();
+            while turns.0 != i {
+                turns = cvar.wait(turns).unwrap();
+            }
+            turns.1 = i;
+            cvar.notify_all();
+        }
+    };
+
+    round_trips(cpus, ping, pong)
+}
+
+/// A pair of pipes, as (read end, write end) each.
+fn pipe() -> (libc::c_int, libc::c_int) {
+    let mut fds = [0; 2];
+    let res = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) };
+    assert_eq!(res, 0, "pipe2 failed");
+    (fds[0], fds[1])
+}
+
+/// Each iteration writes a byte to the other thread through one pipe, and reads it back through
+/// another.
+fn time_pipe(n: usize, stopwatch: &Stopwatch, cpus: &[CpuSet]) -> Vec<u64> {
+    let (ping_rx, ping_tx) = pipe();
+    let (pong_rx, pong_tx) = pipe();
+    let stopwatch = *stopwatch;
+
+    let ping = move || {
+        let mut byte = 0u8;
+        time_each(n, &stopwatch, || unsafe {
+            libc::write(ping_tx, &byte as *const u8 as *const c_void, 1);
+            libc::read(pong_rx, &mut byte as *mut u8 as *mut c_void, 1);
+        })
+    };
+
+    let pong = move || {
+        let mut byte = 0u8;
+        for _ in 0..n {
+            unsafe {
+                libc::read(ping_rx, &mut byte as *mut u8 as *mut c_void, 1);
+                libc::write(pong_tx, &byte as *const u8 as *const c_void, 1);
+            }
+        }
+    };
+
+    let measurements = round_trips(cpus, ping, pong);
+
+    for fd in [ping_rx, ping_tx, pong_rx, pong_tx].iter() {
+        unsafe { libc::close(*fd) };
+    }
     measurements
 }
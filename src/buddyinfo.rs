@@ -0,0 +1,284 @@
+//! Parsers for `/proc/buddyinfo` and `/proc/pagetypeinfo`, and the kernel's measures of external
+//! fragmentation computed from them.
+//!
+//! The indices use the kernel's integer arithmetic (`unusable_free_index` and
+//! `__fragmentation_index` in `mm/vmstat.c`), so they should match what it reports in
+//! `/sys/kernel/debug/extfrag/{unusable_index,extfrag_index}`:
+//!
+//! - The _unusable free space index_ for order `k` is the fraction of free memory that is in
+//!   blocks smaller than `2^k` pages, i.e. that cannot be used for an allocation of order `k`. It is
+//!   0 when there is no free memory.
+//! - The _fragmentation index_ for order `k` says whether a failed allocation of order `k` would be
+//!   due to a lack of memory (towards 0) or to external fragmentation (towards 1). It is -1 if an
+//!   allocation of order `k` would succeed, and 0 if there is no free memory.
+
+use crate::{error, Error, Result};
+
+const BUDDYINFO_PATH: &str = "/proc/buddyinfo";
+const PAGETYPEINFO_PATH: &str = "/proc/pagetypeinfo";
+
+/// The free blocks of each order in one zone (and, for `/proc/pagetypeinfo`, one migrate type).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FreeAreas {
+    pub node: usize,
+    pub zone: String,
+
+    /// The migrate type, or `None` for all migrate types (`/proc/buddyinfo`).
+    pub migrate_type: Option<String>,
+
+    /// The number of free blocks of each order: `free[k]` is the number of free blocks of `2^k`
+    /// pages.
+    pub free: Vec<u64>,
+}
+
+/// The free-block statistics that the indices are computed from (`struct contig_page_info` in the
+/// kernel).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContigInfo {
+    /// The number of free pages.
+    pub free_pages: u64,
+
+    /// The number of free blocks of any order.
+    pub free_blocks_total: u64,
+
+    /// The number of free blocks of the requested order that could be made from the free blocks of
+    /// that order or higher.
+    pub free_blocks_suitable: u64,
+}
+
+/// The fragmentation of one zone for allocations of one order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fragmentation {
+    pub node: usize,
+    pub zone: String,
+    pub migrate_type: Option<String>,
+    pub order: usize,
+    pub free_pages: u64,
+
+    /// The unusable free space index, in `[0, 1]`.
+    pub unusable_index: f64,
+
+    /// The fragmentation index, in `[0, 1]`, or -1 if the allocation would succeed.
+    pub fragmentation_index: f64,
+}
+
+impl FreeAreas {
+    /// The number of free pages.
+    pub fn free_pages(&self) -> u64 {
+        self.free
+            .iter()
+            .enumerate()
+            .map(|(order, &blocks)| blocks << order)
+            .sum()
+    }
+
+    /// The free-block statistics for allocations of the given order (`fill_contig_page_info`).
+    pub fn contig_info(&self, order: usize) -> ContigInfo {
+        let mut info = ContigInfo {
+            free_pages: 0,
+            free_blocks_total: 0,
+            free_blocks_suitable: 0,
+        };
+
+        for (k, &blocks) in self.free.iter().enumerate() {
+            info.free_blocks_total += blocks;
+            info.free_pages += blocks << k;
+            if k >= order {
+                info.free_blocks_suitable += blocks << (k - order);
+            }
+        }
+
+        info
+    }
+
+    /// The unusable free space index for the given order, in thousandths, as computed by the
+    /// kernel (`unusable_free_index`).
+    fn unusable_index_milli(&self, order: usize) -> u64 {
+        let info = self.contig_info(order);
+        if info.free_pages == 0 {
+            return 0;
+        }
+
+        (info.free_pages - (info.free_blocks_suitable << order)) * 1000 / info.free_pages
+    }
+
+    /// The fragmentation index for the given order, in thousandths, as computed by the kernel
+    /// (`__fragmentation_index`).
+    fn fragmentation_index_milli(&self, order: usize) -> i64 {
+        let info = self.contig_info(order);
+        if info.free_blocks_total == 0 {
+            return 0;
+        }
+        if info.free_blocks_suitable > 0 {
+            return -1000;
+        }
+
+        // Same integer arithmetic as the kernel: divide before adding, and round down at the end.
+        let requested = 1u64 << order;
+        1000 - ((1000 + info.free_pages * 1000 / requested) / info.free_blocks_total) as i64
+    }
+
+    /// The unusable free space index for the given order, in `[0, 1]`.
+    pub fn unusable_index(&self, order: usize) -> f64 {
+        self.unusable_index_milli(order) as f64 / 1000.
+    }
+
+    /// The fragmentation index for the given order, in `[0, 1]`, or -1 if an allocation of that
+    /// order would succeed.
+    pub fn fragmentation_index(&self, order: usize) -> f64 {
+        self.fragmentation_index_milli(order) as f64 / 1000.
+    }
+
+    /// Both indices for the given order.
+    pub fn fragmentation(&self, order: usize) -> Fragmentation {
+        Fragmentation {
+            node: self.node,
+            zone: self.zone.clone(),
+            migrate_type: self.migrate_type.clone(),
+            order,
+            free_pages: self.free_pages(),
+            unusable_index: self.unusable_index(order),
+            fragmentation_index: self.fragmentation_index(order),
+        }
+    }
+}
+
+/// The number of pageblocks of each migrate type in one zone.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageBlocks {
+    pub node: usize,
+    pub zone: String,
+
+    /// (migrate type, number of pageblocks).
+    pub counts: Vec<(String, u64)>,
+}
+
+/// The contents of `/proc/pagetypeinfo`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageTypeInfo {
+    /// The order of a pageblock.
+    pub pageblock_order: usize,
+
+    /// The free blocks of each order, per zone and migrate type.
+    pub free: Vec<FreeAreas>,
+
+    /// The number of pageblocks of each migrate type, per zone.
+    pub blocks: Vec<PageBlocks>,
+}
+
+/// Parse the node and zone from a `Node 0, zone Normal` prefix, returning the rest of the line.
+fn parse_node_zone(line: &str) -> Option<(usize, String, &str)> {
+    let rest = line.trim_start().strip_prefix("Node")?;
+    let comma = rest.find(',')?;
+    let node = rest[..comma].trim().parse().ok()?;
+
+    let rest = rest[comma + 1..].trim_start().strip_prefix("zone")?;
+    let rest = rest.trim_start();
+    let end = rest
+        .find(|c: char| c.is_whitespace() || c == ',')
+        .unwrap_or(rest.len());
+    let zone = rest[..end].to_owned();
+
+    Some((node, zone, rest[end..].trim_start_matches(',')))
+}
+
+/// Parse whitespace-separated counts.
+fn parse_counts(s: &str) -> Option<Vec<u64>> {
+    s.split_whitespace().map(|n| n.parse().ok()).collect()
+}
+
+/// Parse the contents of `/proc/buddyinfo`.
+pub fn parse_buddyinfo(s: &str) -> std::result::Result<Vec<FreeAreas>, String> {
+    s.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            // Lines look like "Node 0, zone   Normal      2      2     26 ..."
+            let (node, zone, rest) =
+                parse_node_zone(line).ok_or_else(|| format!("bad line: {}", line))?;
+            let free = parse_counts(rest).ok_or_else(|| format!("bad counts: {}", line))?;
+
+            Ok(FreeAreas {
+                node,
+                zone,
+                migrate_type: None,
+                free,
+            })
+        })
+        .collect()
+}
+
+/// Parse the contents of `/proc/pagetypeinfo`.
+pub fn parse_pagetypeinfo(s: &str) -> std::result::Result<PageTypeInfo, String> {
+    let mut info = PageTypeInfo {
+        pageblock_order: 0,
+        free: vec![],
+        blocks: vec![],
+    };
+
+    // The migrate types of the "Number of blocks type" table, once its header has been seen.
+    let mut block_types: Option<Vec<String>> = None;
+
+    for line in s.lines().filter(|line| !line.trim().is_empty()) {
+        if let Some(order) = line.strip_prefix("Page block order:") {
+            info.pageblock_order = order
+                .trim()
+                .parse()
+                .map_err(|_| format!("bad pageblock order: {}", line))?;
+        } else if let Some(types) = line.strip_prefix("Number of blocks type") {
+            block_types = Some(types.split_whitespace().map(Into::into).collect());
+        } else if let Some((node, zone, rest)) = parse_node_zone(line) {
+            match block_types {
+                // "Node 0, zone   Normal           55         1458 ..."
+                Some(ref types) => {
+                    let counts = parse_counts(rest)
+                        .filter(|counts| counts.len() == types.len())
+                        .ok_or_else(|| format!("bad block counts: {}", line))?;
+                    info.blocks.push(PageBlocks {
+                        node,
+                        zone,
+                        counts: types.iter().cloned().zip(counts).collect(),
+                    });
+                }
+
+                // "Node    0, zone   Normal, type      Movable   2329   6128 ..."
+                None => {
+                    let rest = rest
+                        .trim_start()
+                        .strip_prefix("type")
+                        .ok_or_else(|| format!("bad line: {}", line))?;
+                    let mut words = rest.split_whitespace();
+                    let migrate_type = words
+                        .next()
+                        .ok_or_else(|| format!("no migrate type: {}", line))?;
+                    let free = words
+                        .map(|n| n.parse().ok())
+                        .collect::<Option<_>>()
+                        .ok_or_else(|| format!("bad counts: {}", line))?;
+
+                    info.free.push(FreeAreas {
+                        node,
+                        zone,
+                        migrate_type: Some(migrate_type.into()),
+                        free,
+                    });
+                }
+            }
+        }
+
+        // Other lines ("Pages per block", table headers) carry nothing we need.
+    }
+
+    Ok(info)
+}
+
+/// Read `/proc/buddyinfo`.
+pub fn buddyinfo() -> Result<Vec<FreeAreas>> {
+    parse_buddyinfo(&error::read_to_string(BUDDYINFO_PATH)?)
+        .map_err(|reason| Error::malformed(BUDDYINFO_PATH, reason))
+}
+
+/// Read `/proc/pagetypeinfo`. This usually requires root.
+pub fn pagetypeinfo() -> Result<PageTypeInfo> {
+    parse_pagetypeinfo(&error::read_to_string(PAGETYPEINFO_PATH)?)
+        .map_err(|reason| Error::malformed(PAGETYPEINFO_PATH, reason))
+}
@@ -0,0 +1,429 @@
+//! Calibration of the 0sim hypervisor's guest time offset, so that hypercalls appear to take no
+//! time to the guest.
+//!
+//! Each round times `acc` `nop` hypercalls and nudges the offset according to the average. The
+//! hypervisor only takes one-cycle nudges (`Hypervisor::calibrate`), so a `Controller` decides how
+//! many to make in each round: one at a time (`Step`, the original behavior), by bisecting between
+//! offsets that were too low and too high (`Bisect`), or in proportion to the error (`Pi`).
+//! Calibration has converged once `num_below_ep` rounds in a row average within `epsilon` cycles of
+//! zero, and gives up after `timeout`.
+//!
+//! The result is saved to a state file, so that other experiments can check that the guest was
+//! calibrated, and how well, before they measure anything (see `Calibration::problem`).
+
+use std::{
+    fmt, io,
+    path::Path,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    error,
+    hypervisor::Hypervisor,
+    output::{ClockSource, Output, Record, Units},
+    timer::Stopwatch,
+    Error, Result,
+};
+
+/// Where `time_calibrate` saves its result by default.
+pub const DEFAULT_STATE_PATH: &str = "/tmp/time_calibrate.state";
+
+/// The most nudges made after one round, so that a runaway controller cannot keep calibration from
+/// timing out.
+const MAX_ADJUSTMENT: i64 = 1 << 16;
+
+const BOOT_ID_PATH: &str = "/proc/sys/kernel/random/boot_id";
+
+/// How many nudges to make after each round.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Controller {
+    /// One nudge per round.
+    Step,
+
+    /// Double the adjustment each round until the average changes sign, then bisect between the
+    /// last offsets that were too low and too high.
+    #[default]
+    Bisect,
+
+    /// A PI controller: `kp` times the average plus `ki` times the sum of all averages so far.
+    Pi { kp: f64, ki: f64 },
+}
+
+impl Controller {
+    /// The gains of `pi` if none are given.
+    pub const DEFAULT_PI: Controller = Controller::Pi { kp: 0.5, ki: 0.05 };
+
+    /// Parse a controller: `step`, `bisect` or `pi[:<kp>,<ki>]`.
+    pub fn parse(s: &str) -> std::result::Result<Self, String> {
+        let bad = || {
+            format!(
+                "Not a valid controller (expected step, bisect or pi[:<kp>,<ki>]): {}",
+                s
+            )
+        };
+
+        let mut parts = s.splitn(2, ':');
+        match (parts.next().unwrap(), parts.next()) {
+            ("step", None) => Ok(Controller::Step),
+            ("bisect", None) => Ok(Controller::Bisect),
+            ("pi", None) => Ok(Controller::DEFAULT_PI),
+            ("pi", Some(gains)) => {
+                let (kp, ki) = gains.split_once(',').ok_or_else(bad)?;
+                Ok(Controller::Pi {
+                    kp: kp.parse().map_err(|_| bad())?,
+                    ki: ki.parse().map_err(|_| bad())?,
+                })
+            }
+            _ => Err(bad()),
+        }
+    }
+
+    /// The controller given by an optional command line argument (checked with `is_controller`),
+    /// or the default if it was not given.
+    pub fn from_arg(arg: Option<&str>) -> Self {
+        arg.map(|s| Controller::parse(s).unwrap())
+            .unwrap_or_default()
+    }
+}
+
+impl fmt::Display for Controller {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Controller::Step => write!(f, "step"),
+            Controller::Bisect => write!(f, "bisect"),
+            Controller::Pi { kp, ki } => write!(f, "pi:{},{}", kp, ki),
+        }
+    }
+}
+
+/// A clap validator for controllers.
+pub fn is_controller(arg: String) -> std::result::Result<(), String> {
+    Controller::parse(&arg).map(|_| ())
+}
+
+/// The parameters of a calibration.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CalibrationConfig {
+    /// The number of hypercalls timed in each round. Must be positive.
+    pub acc: usize,
+
+    /// How close to zero (in cycles) the average of a round must be.
+    pub epsilon: i64,
+
+    /// How many rounds in a row must be within `epsilon`.
+    pub num_below_ep: usize,
+
+    /// When to give up.
+    pub timeout: Duration,
+
+    pub controller: Controller,
+}
+
+impl CalibrationConfig {
+    /// Why calibrating with this configuration would be meaningless, if it would.
+    pub fn problem(&self) -> Option<String> {
+        if self.acc == 0 {
+            // Every round would average 0, and calibration would "converge" without measuring.
+            Some("acc must be positive".to_owned())
+        } else {
+            None
+        }
+    }
+}
+
+impl Default for CalibrationConfig {
+    fn default() -> Self {
+        CalibrationConfig {
+            acc: 100_000,
+            epsilon: 50,
+            num_below_ep: 50,
+            timeout: Duration::from_secs(600),
+            controller: Controller::default(),
+        }
+    }
+}
+
+/// The outcome of a calibration.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Calibration {
+    /// Whether calibration converged before the timeout.
+    pub converged: bool,
+
+    /// The total adjustment made, in nudges, relative to the offset the hypervisor had before.
+    pub offset: i64,
+
+    /// The mean of the averages of the last `num_below_ep` rounds (or fewer, if there were not
+    /// that many), in cycles.
+    pub residual: f64,
+
+    /// The `epsilon` that calibration aimed for.
+    pub epsilon: i64,
+
+    pub rounds: usize,
+
+    /// When calibration finished, in seconds since the Unix epoch.
+    pub timestamp: u64,
+
+    /// The kernel's boot ID when calibration finished. The hypervisor forgets the offset when the
+    /// guest reboots.
+    pub boot_id: String,
+}
+
+/// Chooses the next adjustment from the average of each round.
+struct State {
+    controller: Controller,
+    offset: i64,
+
+    /// `Bisect`: the last offsets that were too low and too high, and the adjustment to make while
+    /// only one of them is known.
+    too_low: Option<i64>,
+    too_high: Option<i64>,
+    step: i64,
+
+    /// `Pi`: the sum of the averages so far.
+    integral: f64,
+}
+
+impl State {
+    /// The number of nudges to make after a round with average `avg` (positive to increase the
+    /// offset).
+    fn adjustment(&mut self, avg: i64, epsilon: i64) -> i64 {
+        match self.controller {
+            Controller::Step => {
+                if avg.abs() > epsilon {
+                    avg.signum()
+                } else {
+                    0
+                }
+            }
+
+            Controller::Bisect => {
+                if avg > epsilon {
+                    self.too_low = Some(self.offset);
+                } else if avg < -epsilon {
+                    self.too_high = Some(self.offset);
+                } else {
+                    return 0;
+                }
+
+                let target = match (self.too_low, self.too_high) {
+                    (Some(lo), Some(hi)) if (hi - lo).abs() > 1 => lo + (hi - lo) / 2,
+
+                    // The bracket has closed without ever getting within epsilon, probably because
+                    // of noise or drift. Start over from here.
+                    (Some(_), Some(_)) => {
+                        self.too_low = None;
+                        self.too_high = None;
+                        self.step = 1;
+                        self.offset + avg.signum()
+                    }
+
+                    _ => {
+                        let target = self.offset + avg.signum() * self.step;
+                        self.step = self.step.saturating_mul(2);
+                        target
+                    }
+                };
+
+                target - self.offset
+            }
+
+            Controller::Pi { kp, ki } => {
+                self.integral += avg as f64;
+                if avg.abs() > epsilon {
+                    (kp * avg as f64 + ki * self.integral).round() as i64
+                } else {
+                    0
+                }
+            }
+        }
+    }
+}
+
+impl Calibration {
+    /// Calibrate `hv`, timing hypercalls with `stopwatch`. `round(i, avg)` is called with the
+    /// average of each round, in cycles.
+    ///
+    /// Panics if `config` has a problem (see `CalibrationConfig::problem`).
+    pub fn run(
+        hv: &impl Hypervisor,
+        stopwatch: &Stopwatch,
+        config: &CalibrationConfig,
+        mut round: impl FnMut(usize, i64),
+    ) -> Result<Self> {
+        if let Some(problem) = config.problem() {
+            panic!("invalid calibration config: {}", problem);
+        }
+
+        let start = Instant::now();
+        let mut state = State {
+            controller: config.controller,
+            offset: 0,
+            too_low: None,
+            too_high: None,
+            step: 1,
+            integral: 0.,
+        };
+
+        let mut avgs = vec![];
+        let mut below = 0;
+
+        let converged = loop {
+            let mut sum: i64 = 0;
+            for _ in 0..config.acc {
+                let start = stopwatch.start();
+                hv.nop();
+                sum += stopwatch.stop_signed(start);
+            }
+
+            let avg = sum / config.acc as i64;
+            round(avgs.len(), avg);
+            avgs.push(avg);
+
+            if avg.abs() <= config.epsilon {
+                below += 1;
+                if below >= config.num_below_ep {
+                    break true;
+                }
+            } else {
+                below = 0;
+            }
+
+            if start.elapsed() >= config.timeout {
+                break false;
+            }
+
+            let adjustment = state
+                .adjustment(avg, config.epsilon)
+                .clamp(-MAX_ADJUSTMENT, MAX_ADJUSTMENT);
+            for _ in 0..adjustment.abs() {
+                hv.calibrate(adjustment > 0);
+            }
+            state.offset += adjustment;
+        };
+
+        let last = &avgs[avgs.len().saturating_sub(config.num_below_ep.max(1))..];
+
+        Ok(Calibration {
+            converged,
+            offset: state.offset,
+            residual: last.iter().sum::<i64>() as f64 / last.len() as f64,
+            epsilon: config.epsilon,
+            rounds: avgs.len(),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |t| t.as_secs()),
+            boot_id: boot_id()?,
+        })
+    }
+
+    /// Read a calibration saved with `save`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = error::read_to_string(path)?;
+
+        // Lines look like "offset 1234".
+        let field = |name: &str| {
+            contents
+                .lines()
+                .filter_map(|line| line.split_once(' '))
+                .find(|&(key, _)| key == name)
+                .map(|(_, value)| value.trim())
+                .ok_or_else(|| Error::malformed(path, format!("no field `{}`", name)))
+        };
+
+        Ok(Calibration {
+            converged: error::parse::<u8>(path, Some(field("converged")?))? != 0,
+            offset: error::parse(path, Some(field("offset")?))?,
+            residual: error::parse(path, Some(field("residual")?))?,
+            epsilon: error::parse(path, Some(field("epsilon")?))?,
+            rounds: error::parse(path, Some(field("rounds")?))?,
+            timestamp: error::parse(path, Some(field("timestamp")?))?,
+            boot_id: field("boot_id")?.to_owned(),
+        })
+    }
+
+    /// Write the calibration to `path`.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let contents = format!(
+            "converged {}\noffset {}\nresidual {}\nepsilon {}\nrounds {}\ntimestamp {}\nboot_id {}\n",
+            self.converged as u8,
+            self.offset,
+            self.residual,
+            self.epsilon,
+            self.rounds,
+            self.timestamp,
+            self.boot_id,
+        );
+        std::fs::write(path, contents).map_err(|err| Error::from_io(path, err))
+    }
+
+    /// Why this calibration cannot be relied on, if it cannot: it did not converge, the residual
+    /// is larger than its epsilon, or the guest has rebooted since.
+    pub fn problem(&self) -> Result<Option<String>> {
+        Ok(if !self.converged {
+            Some(format!(
+                "calibration did not converge after {} rounds",
+                self.rounds
+            ))
+        } else if self.residual.abs() > self.epsilon as f64 {
+            Some(format!(
+                "the residual ({} cycles) exceeds epsilon ({} cycles)",
+                self.residual, self.epsilon
+            ))
+        } else if self.boot_id != boot_id()? {
+            Some("the guest has rebooted since calibration".to_owned())
+        } else {
+            None
+        })
+    }
+
+    /// Load the calibration saved at `path` and check it. Returns a description of the problem if
+    /// there is no usable calibration.
+    pub fn check(path: impl AsRef<Path>) -> std::result::Result<Self, String> {
+        let calibration = Self::load(&path).map_err(|e| match e {
+            Error::Missing { .. } => format!(
+                "no calibration at {} (run time_calibrate first)",
+                path.as_ref().display()
+            ),
+            e => e.to_string(),
+        })?;
+
+        match calibration.problem() {
+            Ok(None) => Ok(calibration),
+            Ok(Some(problem)) => Err(problem),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    /// Write the offset and residual to `out`.
+    pub fn report(&self, out: &mut Output) -> io::Result<()> {
+        out.emit(
+            format_args!(
+                "Calibration: offset {}, residual {} cycles ({} after {} rounds)",
+                self.offset,
+                self.residual,
+                if self.converged {
+                    "converged"
+                } else {
+                    "did not converge"
+                },
+                self.rounds
+            ),
+            &[
+                Record::new("calibration", "converged", self.converged as u64),
+                Record::new("calibration", "offset", self.offset),
+                Record::new("calibration", "residual", self.residual)
+                    .clock(ClockSource::Rdtsc)
+                    .units(Units::Cycles),
+                Record::new("calibration", "rounds", self.rounds),
+            ],
+        )
+    }
+}
+
+/// The kernel's random ID for the current boot.
+fn boot_id() -> Result<String> {
+    Ok(error::read_to_string(BOOT_ID_PATH)?.trim().to_owned())
+}
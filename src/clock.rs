@@ -0,0 +1,324 @@
+//! The frequency of the TSC, for converting `rdtsc` cycle counts to time.
+//!
+//! The frequency is found, in order of preference, from CPUID leaf 0x15 (the TSC/crystal clock
+//! ratio), CPUID leaf 0x16 (the base frequency), the nominal frequency in the `/proc/cpuinfo` model
+//! name, or by calibrating against `CLOCK_MONOTONIC_RAW`. Hypervisors often hide the CPUID leaves,
+//! in which case calibration is usually what ends up being used.
+//!
+//! Conversion only makes sense if the TSC ticks at a constant rate, even across frequency changes
+//! and idle states. `TscFlags` reports whether the CPU says so.
+//!
+//! `tsc()` detects the frequency once per process, so that all conversions agree.
+//! `Output::convert_cycles` uses it to report `rdtsc` measurements in nanoseconds.
+
+use std::{arch::x86_64::__cpuid, fmt, io, sync::OnceLock, time::Duration};
+
+use bmk_linux::timing::Clock;
+
+use crate::{
+    error,
+    output::{Output, Record, Units},
+    timer::rdtsc,
+    Result,
+};
+
+const CPUINFO_PATH: &str = "/proc/cpuinfo";
+
+/// How long to calibrate for if nothing reports the frequency.
+pub const DEFAULT_CALIBRATION: Duration = Duration::from_millis(100);
+
+/// Where a TSC frequency came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrequencySource {
+    /// CPUID leaf 0x15: crystal clock frequency times the TSC/crystal ratio.
+    Cpuid15,
+
+    /// CPUID leaf 0x16: the processor base frequency.
+    Cpuid16,
+
+    /// The nominal frequency in the model name in `/proc/cpuinfo` (e.g. "@ 2.10GHz").
+    ModelName,
+
+    /// Measured against `CLOCK_MONOTONIC_RAW`.
+    Calibration,
+
+    /// Given by the user.
+    User,
+}
+
+impl fmt::Display for FrequencySource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            FrequencySource::Cpuid15 => "cpuid 0x15",
+            FrequencySource::Cpuid16 => "cpuid 0x16",
+            FrequencySource::ModelName => "model name",
+            FrequencySource::Calibration => "calibration",
+            FrequencySource::User => "user",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// What the CPU says about the stability of its TSC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TscFlags {
+    /// The TSC ticks at a constant rate regardless of the CPU frequency (`constant_tsc`).
+    pub constant: bool,
+
+    /// The TSC keeps ticking in deep idle states (`nonstop_tsc`).
+    pub nonstop: bool,
+
+    /// The invariant TSC bit (CPUID leaf 0x80000007, EDX bit 8), which implies both.
+    pub invariant: bool,
+}
+
+impl TscFlags {
+    /// Read the flags of the first CPU from `/proc/cpuinfo` and CPUID.
+    pub fn read() -> Result<Self> {
+        let cpuinfo = error::read_to_string(CPUINFO_PATH)?;
+        let flags: Vec<&str> = cpuinfo
+            .lines()
+            .find(|line| line.starts_with("flags"))
+            .and_then(|line| line.split_once(':'))
+            .map(|(_, flags)| flags.split_whitespace().collect())
+            .unwrap_or_default();
+
+        let invariant =
+            __cpuid(0x8000_0000).eax >= 0x8000_0007 && __cpuid(0x8000_0007).edx & (1 << 8) != 0;
+
+        Ok(TscFlags {
+            constant: flags.contains(&"constant_tsc"),
+            nonstop: flags.contains(&"nonstop_tsc"),
+            invariant,
+        })
+    }
+
+    /// Can cycle counts be converted to time?
+    pub fn is_reliable(&self) -> bool {
+        self.invariant || (self.constant && self.nonstop)
+    }
+}
+
+/// The frequency of the TSC.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TscClock {
+    hz: f64,
+    source: FrequencySource,
+}
+
+impl TscClock {
+    /// A TSC with the given frequency in MHz.
+    pub fn from_mhz(mhz: f64) -> Self {
+        TscClock {
+            hz: mhz * 1e6,
+            source: FrequencySource::User,
+        }
+    }
+
+    /// Find the frequency from CPUID, `/proc/cpuinfo` or, failing those, by calibrating for
+    /// `DEFAULT_CALIBRATION`.
+    pub fn detect() -> Self {
+        Self::from_cpuid()
+            .or_else(Self::from_model_name)
+            .unwrap_or_else(|| Self::calibrate(DEFAULT_CALIBRATION))
+    }
+
+    /// The frequency from CPUID leaf 0x15 or 0x16, if the CPU (or hypervisor) reports it.
+    pub fn from_cpuid() -> Option<Self> {
+        let max_leaf = __cpuid(0).eax;
+
+        if max_leaf >= 0x15 {
+            // EAX/EBX is the TSC/crystal ratio, and ECX the crystal frequency in Hz.
+            let leaf = __cpuid(0x15);
+            if leaf.eax != 0 && leaf.ebx != 0 && leaf.ecx != 0 {
+                return Some(TscClock {
+                    hz: leaf.ecx as f64 * leaf.ebx as f64 / leaf.eax as f64,
+                    source: FrequencySource::Cpuid15,
+                });
+            }
+        }
+
+        if max_leaf >= 0x16 {
+            // EAX is the base frequency in MHz.
+            let leaf = __cpuid(0x16);
+            if leaf.eax != 0 {
+                return Some(TscClock {
+                    hz: leaf.eax as f64 * 1e6,
+                    source: FrequencySource::Cpuid16,
+                });
+            }
+        }
+
+        None
+    }
+
+    /// The nominal frequency in the model name in `/proc/cpuinfo` (e.g. "Intel(R) Xeon(R) CPU
+    /// E5-2620 v4 @ 2.10GHz"), which is the TSC frequency on Intel CPUs with an invariant TSC.
+    pub fn from_model_name() -> Option<Self> {
+        let cpuinfo = error::read_to_string(CPUINFO_PATH).ok()?;
+        let model = cpuinfo
+            .lines()
+            .find(|line| line.starts_with("model name"))?;
+        let ghz = model.rsplit_once('@')?.1.trim().strip_suffix("GHz")?;
+
+        Some(TscClock {
+            hz: ghz.trim().parse::<f64>().ok()? * 1e9,
+            source: FrequencySource::ModelName,
+        })
+    }
+
+    /// Measure the frequency by counting cycles over `duration` of `CLOCK_MONOTONIC_RAW`, which is
+    /// not slewed by NTP.
+    pub fn calibrate(duration: Duration) -> Self {
+        // Bracket each clock read with TSC reads, and use the midpoint.
+        let sample = || {
+            let before = rdtsc();
+            let ns = monotonic_raw_ns();
+            let after = rdtsc();
+            (before / 2 + after / 2, ns)
+        };
+
+        let (start_tsc, start_ns) = sample();
+        std::thread::sleep(duration);
+        let (end_tsc, end_ns) = sample();
+
+        TscClock {
+            hz: (end_tsc - start_tsc) as f64 * 1e9 / (end_ns - start_ns) as f64,
+            source: FrequencySource::Calibration,
+        }
+    }
+
+    pub fn hz(&self) -> f64 {
+        self.hz
+    }
+
+    pub fn mhz(&self) -> f64 {
+        self.hz / 1e6
+    }
+
+    pub fn source(&self) -> FrequencySource {
+        self.source
+    }
+
+    /// Convert a number of cycles to nanoseconds.
+    pub fn cycles_to_ns(&self, cycles: f64) -> f64 {
+        cycles * 1e9 / self.hz
+    }
+
+    /// Convert a number of nanoseconds to cycles.
+    pub fn ns_to_cycles(&self, ns: f64) -> f64 {
+        ns * self.hz / 1e9
+    }
+
+    /// Convert a number of cycles to a `Duration`.
+    pub fn duration(&self, cycles: u64) -> Duration {
+        Duration::from_nanos(self.cycles_to_ns(cycles as f64).round() as u64)
+    }
+
+    /// Write the frequency and where it came from to `out`.
+    pub fn report(&self, out: &mut Output) -> io::Result<()> {
+        out.emit(
+            format_args!("TSC: {:.3} MHz ({})", self.mhz(), self.source),
+            &[Record::new("clock", "tsc_frequency", self.hz).units(Units::Hertz)],
+        )
+    }
+}
+
+/// The current time of `CLOCK_MONOTONIC_RAW` in nanoseconds.
+pub fn monotonic_raw_ns() -> u64 {
+    clock_ns(libc::CLOCK_MONOTONIC_RAW)
+}
+
+/// The current time of `CLOCK_MONOTONIC` in nanoseconds.
+pub fn monotonic_ns() -> u64 {
+    clock_ns(libc::CLOCK_MONOTONIC)
+}
+
+fn clock_ns(clock: libc::clockid_t) -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe {
+        libc::clock_gettime(clock, &mut ts);
+    }
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+/// Parse a duration with units: `ns`, `us`, `ms` or `s` (e.g. `10ms`).
+pub fn parse_duration(s: &str) -> std::result::Result<Duration, String> {
+    let bad = || {
+        format!(
+            "Not a valid duration (expected e.g. 100ns, 10us, 1ms or 2s): {}",
+            s
+        )
+    };
+    let split = s.find(|c: char| !c.is_ascii_digit()).ok_or_else(bad)?;
+    let n: u64 = s[..split].parse().map_err(|_| bad())?;
+
+    match &s[split..] {
+        "ns" => Ok(Duration::from_nanos(n)),
+        "us" => Ok(Duration::from_micros(n)),
+        "ms" => Ok(Duration::from_millis(n)),
+        "s" => Ok(Duration::from_secs(n)),
+        _ => Err(bad()),
+    }
+}
+
+/// A clap validator for comma-separated lists of durations.
+pub fn is_durations(arg: String) -> std::result::Result<(), String> {
+    arg.split(',')
+        .try_for_each(|d| parse_duration(d).map(|_| ()))
+}
+
+static TSC: OnceLock<TscClock> = OnceLock::new();
+
+/// The TSC frequency of this process: the one given to `set_tsc`, or else detected on first use.
+/// A warning is printed if the TSC does not tick at a constant rate.
+pub fn tsc() -> TscClock {
+    *TSC.get_or_init(|| {
+        match TscFlags::read() {
+            Ok(flags) if !flags.is_reliable() => eprintln!(
+                "warning: the TSC may not tick at a constant rate ({:?}); conversions from \
+                 cycles to time are unreliable",
+                flags
+            ),
+            Ok(_) => {}
+            Err(e) => eprintln!("warning: unable to check TSC flags: {}", e),
+        }
+
+        TscClock::detect()
+    })
+}
+
+/// Use the given TSC frequency for the rest of the process instead of detecting it. Returns false
+/// if the frequency was already fixed by an earlier call to `tsc` or `set_tsc`.
+pub fn set_tsc(clock: TscClock) -> bool {
+    TSC.set(clock).is_ok()
+}
+
+/// Like `std::time::Instant`, but read with `rdtsc` and converted to time with `tsc()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TscInstant(u64);
+
+impl TscInstant {
+    /// The raw TSC value.
+    pub fn cycles(&self) -> u64 {
+        self.0
+    }
+}
+
+impl Clock for TscInstant {
+    #[inline(always)]
+    fn now() -> Self {
+        TscInstant(rdtsc())
+    }
+
+    fn set_scaling_factor(&mut self, _freq: usize) {
+        // nop because the frequency comes from `tsc()`...
+    }
+
+    fn duration_since(&self, earlier: Self) -> Duration {
+        tsc().duration(self.0.saturating_sub(earlier.0))
+    }
+}
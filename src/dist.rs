@@ -0,0 +1,102 @@
+//! Random distributions used by the workload generators.
+
+use rand::Rng;
+
+/// A Zipfian distribution over `[0, n)`, where 0 is the most popular item.
+///
+/// This is the algorithm from Gray et al., "Quickly generating billion-record synthetic
+/// databases", as used by YCSB. It requires `0 < theta < 1`.
+#[derive(Debug, Clone)]
+pub struct Zipf {
+    n: usize,
+    theta: f64,
+    alpha: f64,
+    zetan: f64,
+    eta: f64,
+}
+
+impl Zipf {
+    /// The skew used by YCSB by default.
+    pub const DEFAULT_THETA: f64 = 0.99;
+
+    /// Create a distribution over `[0, n)` with skew `theta`.
+    ///
+    /// # Panics
+    ///
+    /// If `n == 0` or `theta` is not in `(0, 1)`.
+    pub fn new(n: usize, theta: f64) -> Self {
+        assert!(n > 0);
+        assert!(theta > 0.0 && theta < 1.0);
+
+        let zetan = Self::zeta(n, theta);
+
+        Self::with_zeta(n, theta, zetan)
+    }
+
+    /// Like `new`, but with a precomputed `zetan = zeta(n, theta)`.
+    fn with_zeta(n: usize, theta: f64, zetan: f64) -> Self {
+        let zeta2 = Self::zeta(2, theta);
+        let alpha = 1.0 / (1.0 - theta);
+        let eta = (1.0 - (2.0 / n as f64).powf(1.0 - theta)) / (1.0 - zeta2 / zetan);
+
+        Zipf {
+            n,
+            theta,
+            alpha,
+            zetan,
+            eta,
+        }
+    }
+
+    /// The generalized harmonic number: `sum(1 / i^theta)` for `i` in `1..=n`.
+    fn zeta(n: usize, theta: f64) -> f64 {
+        (1..=n).map(|i| 1.0 / (i as f64).powf(theta)).sum()
+    }
+
+    /// The number of items.
+    pub fn n(&self) -> usize {
+        self.n
+    }
+
+    /// The skew.
+    pub fn theta(&self) -> f64 {
+        self.theta
+    }
+
+    /// The probability of item `i`.
+    pub fn probability(&self, i: usize) -> f64 {
+        1.0 / ((i + 1) as f64).powf(self.theta) / self.zetan
+    }
+
+    /// Grow the distribution to `[0, n)`. This is cheaper than creating a new one because `zeta`
+    /// is computed incrementally.
+    pub fn grow(&mut self, n: usize) {
+        if n <= self.n {
+            return;
+        }
+
+        let zetan = self.zetan
+            + (self.n + 1..=n)
+                .map(|i| 1.0 / (i as f64).powf(self.theta))
+                .sum::<f64>();
+
+        *self = Self::with_zeta(n, self.theta, zetan);
+    }
+
+    /// Draw an item.
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> usize {
+        let u: f64 = rng.gen();
+        let uz = u * self.zetan;
+
+        if uz < 1.0 {
+            return 0;
+        }
+
+        if uz < 1.0 + 0.5f64.powf(self.theta) {
+            return 1.min(self.n - 1);
+        }
+
+        let i = (self.n as f64 * (self.eta * u - self.eta + 1.0).powf(self.alpha)) as usize;
+        i.min(self.n - 1)
+    }
+}
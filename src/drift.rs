@@ -0,0 +1,212 @@
+//! Drift between the guest's clocks and the host's elapsed time, e.g. to check that 0sim's time
+//! virtualization stays consistent over a long experiment.
+//!
+//! A `DriftTracker` reads the guest TSC, `CLOCK_MONOTONIC` and the host elapsed time
+//! (`Hypervisor::host_elapsed`) together, and fits each of the latter two against the TSC online.
+//! The _skew_ is how much faster (positive) or slower the other clock runs than the TSC, in parts
+//! per million, and the _offset_ is where the fit says it was when the TSC was first sampled. The
+//! _drift_ of a sample is how far the other clock has moved apart from the TSC since the first
+//! sample.
+//!
+//! `CLOCK_MONOTONIC` is compared with the TSC converted to time with `clock::tsc()`, so its skew
+//! also includes any error in the detected TSC frequency.
+
+use std::io;
+
+use crate::{
+    clock::{self, TscClock},
+    hypervisor::Hypervisor,
+    output::{ClockSource, Output, Record, Units},
+    stats::LinearFit,
+    timer::rdtsc,
+};
+
+/// The three clocks, read at (nearly) the same time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DriftSample {
+    /// The guest TSC, in cycles.
+    pub tsc: u64,
+
+    /// `CLOCK_MONOTONIC`, in nanoseconds.
+    pub monotonic_ns: u64,
+
+    /// The host elapsed time, in (host) cycles.
+    pub host_elapsed: u64,
+}
+
+impl DriftSample {
+    /// Read the clocks. The TSC is read before and after the other two, and the midpoint is used.
+    pub fn read(hv: &impl Hypervisor) -> Self {
+        let before = rdtsc();
+        let monotonic_ns = clock::monotonic_ns();
+        let host_elapsed = hv.host_elapsed();
+        let after = rdtsc();
+
+        DriftSample {
+            tsc: before / 2 + after / 2,
+            monotonic_ns,
+            host_elapsed,
+        }
+    }
+}
+
+/// How far the clocks have moved apart since the first sample.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Drift {
+    /// The guest TSC cycles elapsed since the first sample.
+    pub elapsed: u64,
+
+    /// The host elapsed time minus the guest TSC since the first sample, in cycles.
+    pub host: i64,
+
+    /// `CLOCK_MONOTONIC` minus the guest TSC (as time) since the first sample, in nanoseconds.
+    pub monotonic_ns: i64,
+}
+
+/// Tracks the skew, offset and drift of `CLOCK_MONOTONIC` and the host elapsed time against the
+/// guest TSC.
+#[derive(Debug)]
+pub struct DriftTracker<'a, H: Hypervisor> {
+    hv: &'a H,
+    tsc: TscClock,
+    first: Option<DriftSample>,
+
+    /// Host elapsed cycles against guest cycles since the first sample.
+    host: LinearFit,
+
+    /// Monotonic nanoseconds against guest cycles since the first sample.
+    monotonic: LinearFit,
+
+    last: Option<Drift>,
+}
+
+impl<'a, H: Hypervisor> DriftTracker<'a, H> {
+    /// Track the drift of the host elapsed time from `hv`. The TSC frequency comes from
+    /// `clock::tsc()`.
+    pub fn new(hv: &'a H) -> Self {
+        DriftTracker {
+            hv,
+            tsc: clock::tsc(),
+            first: None,
+            host: LinearFit::new(),
+            monotonic: LinearFit::new(),
+            last: None,
+        }
+    }
+
+    /// Sample the clocks and update the fits.
+    pub fn sample(&mut self) -> Drift {
+        let sample = DriftSample::read(self.hv);
+        let first = *self.first.get_or_insert(sample);
+
+        let elapsed = sample.tsc.wrapping_sub(first.tsc);
+        let host = sample.host_elapsed.wrapping_sub(first.host_elapsed) as i64;
+        let monotonic_ns = sample.monotonic_ns.wrapping_sub(first.monotonic_ns) as i64;
+
+        self.host.record(elapsed as f64, host as f64);
+        self.monotonic.record(elapsed as f64, monotonic_ns as f64);
+
+        let drift = Drift {
+            elapsed,
+            host: host - elapsed as i64,
+            monotonic_ns: monotonic_ns - self.tsc.cycles_to_ns(elapsed as f64).round() as i64,
+        };
+        self.last = Some(drift);
+        drift
+    }
+
+    /// The number of samples so far.
+    pub fn samples(&self) -> u64 {
+        self.host.count()
+    }
+
+    /// How much faster the host elapsed time runs than the guest TSC, in parts per million.
+    pub fn host_skew_ppm(&self) -> f64 {
+        (self.host.slope() - 1.) * 1e6
+    }
+
+    /// The host elapsed time at the first sample, according to the fit, in cycles (relative to the
+    /// actual first sample).
+    pub fn host_offset(&self) -> f64 {
+        self.host.intercept()
+    }
+
+    /// How much faster `CLOCK_MONOTONIC` runs than the guest TSC, in parts per million.
+    pub fn monotonic_skew_ppm(&self) -> f64 {
+        (self.tsc.ns_to_cycles(self.monotonic.slope()) - 1.) * 1e6
+    }
+
+    /// `CLOCK_MONOTONIC` at the first sample, according to the fit, in nanoseconds (relative to
+    /// the actual first sample).
+    pub fn monotonic_offset_ns(&self) -> f64 {
+        self.monotonic.intercept()
+    }
+
+    /// Write the fits and the drift of the last sample to `out`.
+    pub fn report(&self, out: &mut Output, phase: &str) -> io::Result<()> {
+        let last = match self.last {
+            Some(last) => last,
+            None => return Ok(()),
+        };
+
+        out.emit(
+            format_args!(
+                "{}: {} samples over {} cycles",
+                phase,
+                self.samples(),
+                last.elapsed
+            ),
+            &[
+                Record::new(phase, "samples", self.samples()),
+                Record::new(phase, "elapsed", last.elapsed)
+                    .clock(ClockSource::Rdtsc)
+                    .units(Units::Cycles),
+            ],
+        )?;
+
+        out.emit(
+            format_args!(
+                "host: skew {} ppm, offset {} cycles, residual sd {} cycles, drift {} cycles",
+                self.host_skew_ppm(),
+                self.host_offset(),
+                self.host.residual_sd(),
+                last.host
+            ),
+            &[
+                Record::new(phase, "host_skew", self.host_skew_ppm()).units(Units::PartsPerMillion),
+                Record::new(phase, "host_offset", self.host_offset())
+                    .clock(ClockSource::Hypercall)
+                    .units(Units::Cycles),
+                Record::new(phase, "host_residual_sd", self.host.residual_sd())
+                    .clock(ClockSource::Hypercall)
+                    .units(Units::Cycles),
+                Record::new(phase, "host_drift", last.host)
+                    .clock(ClockSource::Hypercall)
+                    .units(Units::Cycles),
+            ],
+        )?;
+
+        out.emit(
+            format_args!(
+                "monotonic: skew {} ppm, offset {} ns, residual sd {} ns, drift {} ns",
+                self.monotonic_skew_ppm(),
+                self.monotonic_offset_ns(),
+                self.monotonic.residual_sd(),
+                last.monotonic_ns
+            ),
+            &[
+                Record::new(phase, "monotonic_skew", self.monotonic_skew_ppm())
+                    .units(Units::PartsPerMillion),
+                Record::new(phase, "monotonic_offset", self.monotonic_offset_ns())
+                    .clock(ClockSource::Monotonic)
+                    .units(Units::Nanoseconds),
+                Record::new(phase, "monotonic_residual_sd", self.monotonic.residual_sd())
+                    .clock(ClockSource::Monotonic)
+                    .units(Units::Nanoseconds),
+                Record::new(phase, "monotonic_drift", last.monotonic_ns)
+                    .clock(ClockSource::Monotonic)
+                    .units(Units::Nanoseconds),
+            ],
+        )
+    }
+}
@@ -0,0 +1,103 @@
+//! The error type returned by the routines in this library.
+
+use std::{
+    fmt,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+/// A convenience alias for results with `paperexp::Error`.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Things that can go wrong when reading kernel interfaces or making syscalls.
+#[derive(Debug)]
+pub enum Error {
+    /// The file does not exist. For procfs/sysfs files, this usually means that the running kernel
+    /// does not support the feature (e.g. `/proc/compact_instrumentation` on a stock kernel).
+    Missing { path: PathBuf },
+
+    /// We are not allowed to access the file (e.g. not running as root).
+    Permission { path: PathBuf },
+
+    /// Some other I/O error occurred while accessing the file.
+    Io { path: PathBuf, err: io::Error },
+
+    /// The file was read, but its contents were not what we expected.
+    Malformed { path: PathBuf, reason: String },
+
+    /// A syscall returned an error.
+    Syscall {
+        name: &'static str,
+        errno: errno::Errno,
+    },
+}
+
+impl Error {
+    /// Classify an I/O error that occurred while accessing `path`.
+    pub fn from_io(path: impl AsRef<Path>, err: io::Error) -> Self {
+        let path = path.as_ref().to_owned();
+        match err.kind() {
+            ErrorKind::NotFound => Error::Missing { path },
+            ErrorKind::PermissionDenied => Error::Permission { path },
+            _ => Error::Io { path, err },
+        }
+    }
+
+    /// The contents of `path` could not be parsed for the given `reason`.
+    pub fn malformed(path: impl AsRef<Path>, reason: impl Into<String>) -> Self {
+        Error::Malformed {
+            path: path.as_ref().to_owned(),
+            reason: reason.into(),
+        }
+    }
+
+    /// The syscall `name` failed. The error code is taken from `errno`.
+    pub fn last_syscall(name: &'static str) -> Self {
+        Error::Syscall {
+            name,
+            errno: errno::errno(),
+        }
+    }
+
+    /// Returns true if this error means that a kernel interface is absent, as opposed to a bug or
+    /// misconfiguration.
+    pub fn is_missing(&self) -> bool {
+        matches!(self, Error::Missing { .. })
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Missing { path } => write!(f, "{} does not exist", path.display()),
+            Error::Permission { path } => write!(f, "permission denied: {}", path.display()),
+            Error::Io { path, err } => write!(f, "unable to access {}: {}", path.display(), err),
+            Error::Malformed { path, reason } => {
+                write!(f, "malformed contents in {}: {}", path.display(), reason)
+            }
+            Error::Syscall { name, errno } => write!(f, "{} failed: {}", name, errno),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { err, .. } => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Read the whole file at `path` into a string.
+pub(crate) fn read_to_string(path: impl AsRef<Path>) -> Result<String> {
+    let path = path.as_ref();
+    std::fs::read_to_string(path).map_err(|err| Error::from_io(path, err))
+}
+
+/// Parse `s`, which came from `path`, as a `T`.
+pub(crate) fn parse<T: std::str::FromStr>(path: impl AsRef<Path>, s: Option<&str>) -> Result<T> {
+    let s = s.ok_or_else(|| Error::malformed(&path, "unexpected end of file"))?;
+    s.parse()
+        .map_err(|_| Error::malformed(&path, format!("unable to parse `{}`", s)))
+}
@@ -0,0 +1,195 @@
+//! An abstraction over the 0sim hypercalls, so that experiments can run both inside a 0sim guest
+//! and on ordinary machines (e.g. for testing).
+//!
+//! `VmcallBackend` issues the real `vmcall` instructions, which will fault with SIGILL outside of
+//! a 0sim guest. `SimulatedBackend` fakes the hypervisor state in-process.
+
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+
+use crate::timer::rdtsc;
+
+/// The operations that the 0sim hypervisor exposes to the guest.
+pub trait Hypervisor {
+    /// Get the host elapsed time.
+    fn host_elapsed(&self) -> u64;
+
+    /// Do nothing (but still trap to the hypervisor).
+    fn nop(&self);
+
+    /// Nudge the guest time calibration offset. `too_low` indicates that the measured time is too
+    /// low and the offset should be increased.
+    fn calibrate(&self, too_low: bool);
+
+    /// Set the simulated page fault time.
+    fn pf_time(&self, pf_time: u64);
+}
+
+/// Uses the real 0sim hypercalls. Only works inside of a 0sim guest.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct VmcallBackend;
+
+impl Hypervisor for VmcallBackend {
+    #[inline(always)]
+    fn host_elapsed(&self) -> u64 {
+        crate::vmcall_host_elapsed()
+    }
+
+    #[inline(always)]
+    fn nop(&self) {
+        crate::vmcall_nop()
+    }
+
+    #[inline(always)]
+    fn calibrate(&self, too_low: bool) {
+        crate::vmcall_calibrate(too_low)
+    }
+
+    #[inline(always)]
+    fn pf_time(&self, pf_time: u64) {
+        crate::vmcall_pf_time(pf_time)
+    }
+}
+
+/// How long a simulated hypercall takes, in cycles, before calibration hides it.
+const SIMULATED_HYPERCALL_CYCLES: i64 = 1000;
+
+/// Fakes the hypervisor in-process. Host elapsed time is the TSC elapsed since the backend was
+/// created, adjusted by the calibration offset. A `nop` spins for `SIMULATED_HYPERCALL_CYCLES`
+/// less the offset, like a trap whose cost the offset has partly hidden from the guest.
+#[derive(Debug)]
+pub struct SimulatedBackend {
+    /// TSC value when the backend was created.
+    start: u64,
+
+    /// The calibration offset, adjusted by `calibrate`.
+    offset: AtomicI64,
+
+    /// The last value set with `pf_time`.
+    pf_time: AtomicU64,
+}
+
+impl SimulatedBackend {
+    /// Create a new simulated backend with zero offset and no PF_TIME set.
+    pub fn new() -> Self {
+        SimulatedBackend {
+            start: rdtsc(),
+            offset: AtomicI64::new(0),
+            pf_time: AtomicU64::new(0),
+        }
+    }
+
+    /// The current calibration offset.
+    pub fn offset(&self) -> i64 {
+        self.offset.load(Ordering::Relaxed)
+    }
+
+    /// The last PF_TIME set via `pf_time`, or 0 if it was never set.
+    pub fn get_pf_time(&self) -> u64 {
+        self.pf_time.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for SimulatedBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Hypervisor for SimulatedBackend {
+    fn host_elapsed(&self) -> u64 {
+        let elapsed = rdtsc().wrapping_sub(self.start) as i64;
+        elapsed.saturating_add(self.offset()).max(0) as u64
+    }
+
+    fn nop(&self) {
+        let cycles = (SIMULATED_HYPERCALL_CYCLES - self.offset()).max(0) as u64;
+        if cycles > 0 {
+            let start = rdtsc();
+            while rdtsc().wrapping_sub(start) < cycles {}
+        }
+    }
+
+    fn calibrate(&self, too_low: bool) {
+        let delta = if too_low { 1 } else { -1 };
+        self.offset.fetch_add(delta, Ordering::Relaxed);
+    }
+
+    fn pf_time(&self, pf_time: u64) {
+        self.pf_time.store(pf_time, Ordering::Relaxed);
+    }
+}
+
+/// A hypervisor backend selected at runtime (e.g. from a command line flag).
+#[derive(Debug)]
+pub enum Backend {
+    Vmcall(VmcallBackend),
+    Simulated(SimulatedBackend),
+}
+
+impl Backend {
+    /// The names accepted by `from_name`.
+    pub const NAMES: &'static [&'static str] = &["vmcall", "sim"];
+
+    /// Construct the backend with the given name (one of `NAMES`).
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "vmcall" => Some(Backend::Vmcall(VmcallBackend)),
+            "sim" => Some(Backend::Simulated(SimulatedBackend::new())),
+            _ => None,
+        }
+    }
+
+    /// The backend named by an optional command line argument (checked with `is_backend`), or the
+    /// default if it was not given.
+    pub fn from_arg(arg: Option<&str>) -> Self {
+        arg.map(|name| Backend::from_name(name).unwrap())
+            .unwrap_or_default()
+    }
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        Backend::Vmcall(VmcallBackend)
+    }
+}
+
+impl Hypervisor for Backend {
+    #[inline(always)]
+    fn host_elapsed(&self) -> u64 {
+        match self {
+            Backend::Vmcall(hv) => hv.host_elapsed(),
+            Backend::Simulated(hv) => hv.host_elapsed(),
+        }
+    }
+
+    #[inline(always)]
+    fn nop(&self) {
+        match self {
+            Backend::Vmcall(hv) => hv.nop(),
+            Backend::Simulated(hv) => hv.nop(),
+        }
+    }
+
+    #[inline(always)]
+    fn calibrate(&self, too_low: bool) {
+        match self {
+            Backend::Vmcall(hv) => hv.calibrate(too_low),
+            Backend::Simulated(hv) => hv.calibrate(too_low),
+        }
+    }
+
+    #[inline(always)]
+    fn pf_time(&self, pf_time: u64) {
+        match self {
+            Backend::Vmcall(hv) => hv.pf_time(pf_time),
+            Backend::Simulated(hv) => hv.pf_time(pf_time),
+        }
+    }
+}
+
+/// A clap validator for backend names.
+pub fn is_backend(arg: String) -> Result<(), String> {
+    Backend::from_name(&arg)
+        .map(|_| ())
+        .ok_or_else(|| format!("Not a valid backend (expected one of {:?})", Backend::NAMES))
+}
@@ -0,0 +1,264 @@
+//! YCSB-style key choosers and operation mixes for the key-value workloads.
+//!
+//! Keys are identified by their index; the key itself is the index formatted as a decimal string.
+//! The key space is `[0, n)`, where `n` grows as keys are inserted.
+
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+use crate::dist::Zipf;
+
+/// How keys are chosen.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyDistribution {
+    /// The `i`-th operation uses key `i` (modulo the key space).
+    Sequential,
+
+    /// All keys are equally likely.
+    Uniform,
+
+    /// Key popularity follows a Zipf distribution with the given skew. Popular keys are scattered
+    /// across the key space by hashing, as in YCSB's scrambled Zipfian generator.
+    Zipfian { theta: f64 },
+
+    /// A fraction `hot_fraction` of the keys (the lowest ones) receives a fraction `hot_ops` of the
+    /// operations. Keys are uniform within the hot and cold sets.
+    Hotspot { hot_fraction: f64, hot_ops: f64 },
+
+    /// Recently inserted keys are the most popular; popularity falls off following a Zipf
+    /// distribution with the given skew.
+    Latest { theta: f64 },
+}
+
+impl KeyDistribution {
+    /// Parse a key distribution: `sequential`, `uniform`, `zipf[:<theta>]`,
+    /// `hotspot:<hot_fraction>:<hot_ops>` or `latest[:<theta>]`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let parts: Vec<_> = s.split(':').collect();
+        let err = || {
+            format!(
+                "Not a valid key distribution (expected sequential, uniform, zipf[:<theta>], \
+                 hotspot:<hot_fraction>:<hot_ops> or latest[:<theta>]): {}",
+                s
+            )
+        };
+        let float = |i: usize| parts[i].parse::<f64>().map_err(|_| err());
+        let theta = || match parts.len() {
+            1 => Ok(Zipf::DEFAULT_THETA),
+            2 => match float(1)? {
+                theta if theta > 0.0 && theta < 1.0 => Ok(theta),
+                _ => Err(format!("Need 0 < theta < 1: {}", s)),
+            },
+            _ => Err(err()),
+        };
+        let fraction = |i: usize| match float(i)? {
+            f if (0.0..=1.0).contains(&f) => Ok(f),
+            _ => Err(format!("Need fractions in [0, 1]: {}", s)),
+        };
+
+        match (parts[0], parts.len()) {
+            ("sequential", 1) => Ok(KeyDistribution::Sequential),
+            ("uniform", 1) => Ok(KeyDistribution::Uniform),
+            ("zipf", _) => Ok(KeyDistribution::Zipfian { theta: theta()? }),
+            ("latest", _) => Ok(KeyDistribution::Latest { theta: theta()? }),
+            ("hotspot", 3) => Ok(KeyDistribution::Hotspot {
+                hot_fraction: fraction(1)?,
+                hot_ops: fraction(2)?,
+            }),
+            _ => Err(err()),
+        }
+    }
+}
+
+/// A clap validator for key distributions.
+pub fn is_key_distribution(arg: String) -> Result<(), String> {
+    KeyDistribution::parse(&arg).map(|_| ())
+}
+
+/// Chooses keys according to a `KeyDistribution`.
+pub struct KeyChooser {
+    dist: KeyDistribution,
+    rng: SmallRng,
+
+    /// For `Zipfian` and `Latest`, the Zipf distribution over the current key space.
+    zipf: Option<Zipf>,
+
+    /// For `Sequential`, the number of keys chosen so far.
+    count: usize,
+}
+
+impl KeyChooser {
+    pub fn new(dist: KeyDistribution, seed: u64) -> Self {
+        KeyChooser {
+            dist,
+            rng: SmallRng::seed_from_u64(seed),
+            zipf: None,
+            count: 0,
+        }
+    }
+
+    /// Choose a key from the key space `[0, n)`.
+    ///
+    /// # Panics
+    ///
+    /// If `n == 0`.
+    pub fn next(&mut self, n: usize) -> usize {
+        assert!(n > 0);
+
+        match self.dist {
+            KeyDistribution::Sequential => {
+                let key = self.count % n;
+                self.count += 1;
+                key
+            }
+
+            KeyDistribution::Uniform => self.rng.gen_range(0, n),
+
+            KeyDistribution::Zipfian { theta } => {
+                let rank = zipf_over(&mut self.zipf, n, theta).sample(&mut self.rng);
+                (fnv1a(rank as u64) % n as u64) as usize
+            }
+
+            KeyDistribution::Hotspot {
+                hot_fraction,
+                hot_ops,
+            } => {
+                let nhot = ((n as f64 * hot_fraction) as usize).min(n);
+                let hot = nhot > 0 && (nhot == n || self.rng.gen::<f64>() < hot_ops);
+                if hot {
+                    self.rng.gen_range(0, nhot)
+                } else {
+                    self.rng.gen_range(nhot, n)
+                }
+            }
+
+            KeyDistribution::Latest { theta } => {
+                let age = zipf_over(&mut self.zipf, n, theta).sample(&mut self.rng);
+                n - 1 - age
+            }
+        }
+    }
+}
+
+/// The Zipf distribution over `[0, n)` in `zipf`, created or grown as needed.
+fn zipf_over(zipf: &mut Option<Zipf>, n: usize, theta: f64) -> &Zipf {
+    match zipf {
+        Some(ref z) if z.n() == n => {}
+        Some(ref mut z) if z.n() < n => z.grow(n),
+        _ => *zipf = Some(Zipf::new(n, theta)),
+    }
+
+    zipf.as_ref().unwrap()
+}
+
+/// The 64-bit FNV-1a hash of the bytes of `x`.
+fn fnv1a(x: u64) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+
+    x.to_le_bytes().iter().fold(OFFSET_BASIS, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(PRIME)
+    })
+}
+
+/// A key-value operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    /// `get` an existing key.
+    Read,
+
+    /// `put` an existing key.
+    Update,
+
+    /// `put` a new key.
+    Insert,
+
+    /// `delete` an existing key.
+    Delete,
+}
+
+impl Op {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Op::Read => "read",
+            Op::Update => "update",
+            Op::Insert => "insert",
+            Op::Delete => "delete",
+        }
+    }
+}
+
+/// The relative frequencies of the operations in a workload.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OpMix {
+    pub read: f64,
+    pub update: f64,
+    pub insert: f64,
+    pub delete: f64,
+}
+
+impl OpMix {
+    /// Parse a mix like `read=0.5,update=0.5`. Omitted operations have weight 0. The weights need
+    /// not sum to 1.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut mix = OpMix {
+            read: 0.0,
+            update: 0.0,
+            insert: 0.0,
+            delete: 0.0,
+        };
+
+        for part in s.split(',') {
+            let mut kv = part.splitn(2, '=');
+            let (op, weight) = match (kv.next(), kv.next()) {
+                (Some(op), Some(weight)) => (op.trim(), weight.trim()),
+                _ => return Err(format!("Expected <op>=<weight>: {}", part)),
+            };
+            let weight = match weight.parse::<f64>() {
+                Ok(weight) if weight >= 0.0 => weight,
+                _ => return Err(format!("Not a valid weight: {}", weight)),
+            };
+            match op {
+                "read" => mix.read = weight,
+                "update" => mix.update = weight,
+                "insert" => mix.insert = weight,
+                "delete" => mix.delete = weight,
+                _ => {
+                    return Err(format!(
+                        "Not a valid op (read, update, insert, delete): {}",
+                        op
+                    ))
+                }
+            }
+        }
+
+        if mix.total() <= 0.0 {
+            return Err("At least one weight must be positive".into());
+        }
+
+        Ok(mix)
+    }
+
+    fn total(&self) -> f64 {
+        self.read + self.update + self.insert + self.delete
+    }
+
+    /// Choose an operation.
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> Op {
+        let x = rng.gen::<f64>() * self.total();
+
+        if x < self.read {
+            Op::Read
+        } else if x < self.read + self.update {
+            Op::Update
+        } else if x < self.read + self.update + self.insert {
+            Op::Insert
+        } else {
+            Op::Delete
+        }
+    }
+}
+
+/// A clap validator for op mixes.
+pub fn is_op_mix(arg: String) -> Result<(), String> {
+    OpMix::parse(&arg).map(|_| ())
+}
@@ -0,0 +1,524 @@
+//! A load generator for key-value stores.
+//!
+//! `KvBackend` abstracts over the store (memcached or redis). `Driver` runs the workload: it
+//! `put`s large values (from a `PayloadGenerator`) with unique keys until the requested amount of
+//! data has been inserted, retrying failed operations and periodically emitting a measurement.
+//! Optionally, it then runs a mix of reads, updates, inserts and deletes on keys drawn from a
+//! `KeyChooser`, and then a phase of `get`s. The latency of every operation can be recorded in a
+//! `Histogram`, which is reported at the end of each phase.
+
+use std::time::Instant;
+
+use bmk_linux::timing::Clock;
+
+use rand::{rngs::SmallRng, SeedableRng};
+
+use crate::{
+    clock::TscInstant,
+    hypervisor::{Backend, Hypervisor},
+    keys::{KeyChooser, KeyDistribution, Op, OpMix},
+    output::{ClockSource, Output, Record, Units},
+    payload::PayloadGenerator,
+    stats::Histogram,
+};
+
+/// Operations supported by a key-value store.
+pub trait KvBackend {
+    /// The error type of the client library.
+    type Error: std::fmt::Debug + std::fmt::Display;
+
+    /// Store `val` under `key`.
+    fn put(&mut self, key: &str, val: &[u8]) -> Result<(), Self::Error>;
+
+    /// Get the value of `key`. Returns `None` if the key is not present.
+    fn get(&mut self, key: &str) -> Result<Option<Vec<u8>>, Self::Error>;
+
+    /// Delete `key`.
+    fn delete(&mut self, key: &str) -> Result<(), Self::Error>;
+
+    /// Called after an operation fails with `err`, before it is retried. Returns `Err` if the
+    /// error is not recoverable.
+    fn recover(&mut self, err: Self::Error) -> Result<(), Self::Error>;
+}
+
+/// The names of the stores accepted by `is_store`.
+pub const STORES: &[&str] = &["memcached", "redis"];
+
+/// A clap validator for store names.
+pub fn is_store(arg: String) -> Result<(), String> {
+    if STORES.contains(&arg.as_str()) {
+        Ok(())
+    } else {
+        Err(format!("Not a valid store (expected one of {:?})", STORES))
+    }
+}
+
+/// A memcached client.
+///
+/// NOTE: The server should be started with e.g. `memcached -M -m 50000` for 50GB.
+pub struct MemcachedBackend {
+    client: memcache::Client,
+}
+
+impl MemcachedBackend {
+    /// The TTL of the key/value pairs
+    const EXPIRATION: u32 = 1_000_000; // A really long time
+
+    /// The size of a single value in a key value pair. This is fine tuned so that there is no
+    /// wasted space if memcached is started with `-f 1.11`.
+    pub const DEFAULT_VAL_SIZE: usize = 523800;
+
+    /// Connect to the memcached instance at `addr` (an IP:PORT).
+    pub fn connect(addr: &str) -> Result<Self, memcache::MemcacheError> {
+        let client = memcache::Client::new(format!("memcache://{}", addr).as_str())?;
+        Ok(MemcachedBackend { client })
+    }
+}
+
+impl KvBackend for MemcachedBackend {
+    type Error = memcache::MemcacheError;
+
+    fn put(&mut self, key: &str, val: &[u8]) -> Result<(), Self::Error> {
+        self.client.set(key, val, Self::EXPIRATION)
+    }
+
+    fn get(&mut self, key: &str) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.client.get(key)
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), Self::Error> {
+        self.client.delete(key).map(|_| ())
+    }
+
+    fn recover(&mut self, err: Self::Error) -> Result<(), Self::Error> {
+        // HACK: Still not sure why things fail. So retry if we failed. That seems to work, unless
+        // the connection is gone.
+        match err {
+            memcache::MemcacheError::Io(ref e) if e.kind() == std::io::ErrorKind::BrokenPipe => {
+                Err(err)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A redis client.
+///
+/// NOTE: The server should be started and configured already.
+pub struct RedisBackend {
+    addr: String,
+    client: redis::Client,
+}
+
+impl RedisBackend {
+    /// 2^19. 2^20 seems to give a "too large" error.
+    pub const DEFAULT_VAL_SIZE: usize = 1 << 19;
+
+    /// Connect to the redis instance at `addr` (a `redis://<IP>:<PORT>` or `unix:<UDS path>`).
+    pub fn connect(addr: &str) -> redis::RedisResult<Self> {
+        let client = redis::Client::open(addr)?;
+        Ok(RedisBackend {
+            addr: addr.to_owned(),
+            client,
+        })
+    }
+}
+
+impl KvBackend for RedisBackend {
+    type Error = redis::RedisError;
+
+    fn put(&mut self, key: &str, val: &[u8]) -> Result<(), Self::Error> {
+        redis::Commands::set::<_, _, String>(&self.client, key, val).map(|_| ())
+    }
+
+    fn get(&mut self, key: &str) -> Result<Option<Vec<u8>>, Self::Error> {
+        redis::Commands::get(&self.client, key)
+    }
+
+    fn delete(&mut self, key: &str) -> Result<(), Self::Error> {
+        redis::Commands::del::<_, usize>(&self.client, key).map(|_| ())
+    }
+
+    fn recover(&mut self, _err: Self::Error) -> Result<(), Self::Error> {
+        // Try to reconnect.
+        self.client = redis::Client::open(self.addr.as_str())?;
+        Ok(())
+    }
+}
+
+/// Run `op` on `backend`, retrying up to `max_retries` times (or forever if `None`) as long as the
+/// backend can recover from the error.
+pub fn with_retries<B, T, F>(
+    backend: &mut B,
+    max_retries: Option<usize>,
+    mut op: F,
+) -> Result<T, B::Error>
+where
+    B: KvBackend,
+    F: FnMut(&mut B) -> Result<T, B::Error>,
+{
+    let mut tries = 0;
+
+    loop {
+        match op(backend) {
+            Ok(val) => return Ok(val),
+            Err(e) => {
+                eprintln!("store returned error: {}", e);
+
+                if max_retries.map(|max| tries >= max).unwrap_or(false) {
+                    return Err(e);
+                }
+
+                backend.recover(e)?;
+                tries += 1;
+            }
+        }
+    }
+}
+
+/// Drives a workload against a key-value store.
+pub struct Driver<'a> {
+    /// The total number of `put`s to do.
+    pub nputs: usize,
+
+    /// Emit a measurement every `print_interval`-th `put`. Must be positive.
+    pub print_interval: usize,
+
+    /// How many times to retry a failed operation before giving up (`None` for no limit).
+    pub max_retries: Option<usize>,
+
+    /// Measure page table size (in KB) instead of latency.
+    pub page_tables: bool,
+
+    /// Use `rdtsc` as the clock source, converting cycles to time with `clock::tsc()`. Otherwise,
+    /// use `Instant`.
+    pub rdtsc: bool,
+
+    /// If `Some`, also record the host elapsed time with every latency measurement.
+    pub hv: Option<&'a Backend>,
+
+    /// The number of operations to run after the fill.
+    pub nops: usize,
+
+    /// The mix of operations to run after the fill.
+    pub mix: OpMix,
+
+    /// How keys are chosen for the operations after the fill.
+    pub keys: KeyDistribution,
+
+    /// The number of `get`s to do in the get phase.
+    pub ngets: usize,
+
+    /// Seeds the choice of operations and keys.
+    pub seed: u64,
+
+    /// Record the latency of every operation and report percentiles at the end of each phase.
+    pub latency: bool,
+}
+
+impl<'a> Driver<'a> {
+    /// A driver that puts `size` bytes (on average) of values from `payload`.
+    pub fn new(size: usize, payload: &PayloadGenerator) -> Self {
+        Driver {
+            nputs: (size as f64 / payload.mean_size()) as usize,
+            print_interval: 100,
+            max_retries: None,
+            page_tables: false,
+            rdtsc: false,
+            hv: None,
+            nops: 0,
+            mix: OpMix {
+                read: 1.0,
+                update: 0.0,
+                insert: 0.0,
+                delete: 0.0,
+            },
+            keys: KeyDistribution::Uniform,
+            ngets: 0,
+            seed: 0,
+            latency: false,
+        }
+    }
+
+    /// Fill the store, writing measurements to `out`.
+    pub fn fill<B: KvBackend>(
+        &self,
+        backend: &mut B,
+        payload: &mut PayloadGenerator,
+        out: &mut Output,
+    ) -> Result<(), B::Error> {
+        if self.rdtsc {
+            self.fill_with_clock::<TscInstant, B>(backend, payload, ClockSource::Rdtsc, out)
+        } else {
+            self.fill_with_clock::<Instant, B>(backend, payload, ClockSource::Monotonic, out)
+        }
+    }
+
+    fn fill_with_clock<C: Clock, B: KvBackend>(
+        &self,
+        backend: &mut B,
+        payload: &mut PayloadGenerator,
+        clock: ClockSource,
+        out: &mut Output,
+    ) -> Result<(), B::Error> {
+        let mut hist = Histogram::new();
+
+        // First time stamp
+        let mut time = C::now();
+
+        // Actually put into the kv-store
+        for i in 0..self.nputs {
+            let key = format!("{}", i);
+            let val = payload.next(i);
+            self.timed::<C, _>(&mut hist, || {
+                with_retries(backend, self.max_retries, |backend| backend.put(&key, val))
+            })?;
+
+            // periodically print
+            if i % self.print_interval == 0 {
+                time = self.sample("fill", i, time, clock, out);
+            }
+        }
+
+        if self.latency {
+            hist.report(out, "fill", clock, Units::Nanoseconds).unwrap();
+        }
+
+        Ok(())
+    }
+
+    /// Run `nops` operations from `mix` on the filled store, writing measurements to `out`.
+    pub fn run<B: KvBackend>(
+        &self,
+        backend: &mut B,
+        payload: &mut PayloadGenerator,
+        out: &mut Output,
+    ) -> Result<(), B::Error> {
+        if self.rdtsc {
+            self.run_with_clock::<TscInstant, B>(backend, payload, ClockSource::Rdtsc, out)
+        } else {
+            self.run_with_clock::<Instant, B>(backend, payload, ClockSource::Monotonic, out)
+        }
+    }
+
+    fn run_with_clock<C: Clock, B: KvBackend>(
+        &self,
+        backend: &mut B,
+        payload: &mut PayloadGenerator,
+        clock: ClockSource,
+        out: &mut Output,
+    ) -> Result<(), B::Error> {
+        let mut rng = SmallRng::seed_from_u64(self.seed);
+        let mut keys = KeyChooser::new(self.keys.clone(), self.seed);
+
+        // The key space is `[0, nkeys)`. New keys are inserted at the end.
+        let mut nkeys = self.nputs;
+
+        // Number of ops of each kind, and number of reads that missed.
+        let mut counts = [0usize; 4];
+        let mut misses = 0usize;
+
+        // Latency of each kind of op.
+        let mut hists = [
+            Histogram::new(),
+            Histogram::new(),
+            Histogram::new(),
+            Histogram::new(),
+        ];
+
+        let mut time = C::now();
+
+        for i in 0..self.nops {
+            let op = match self.mix.sample(&mut rng) {
+                // Nothing to operate on yet; insert something.
+                _ if nkeys == 0 => Op::Insert,
+                op => op,
+            };
+
+            let idx = match op {
+                Op::Insert => {
+                    nkeys += 1;
+                    nkeys - 1
+                }
+                _ => keys.next(nkeys),
+            };
+            let key = format!("{}", idx);
+
+            let hist = &mut hists[op as usize];
+            match op {
+                Op::Read => {
+                    let val = self.timed::<C, _>(hist, || {
+                        with_retries(backend, self.max_retries, |backend| backend.get(&key))
+                    })?;
+                    if val.is_none() {
+                        misses += 1;
+                    }
+                }
+                Op::Update | Op::Insert => {
+                    let val = payload.next(idx);
+                    self.timed::<C, _>(hist, || {
+                        with_retries(backend, self.max_retries, |backend| backend.put(&key, val))
+                    })?;
+                }
+                Op::Delete => {
+                    self.timed::<C, _>(hist, || {
+                        with_retries(backend, self.max_retries, |backend| backend.delete(&key))
+                    })?;
+                }
+            }
+
+            counts[op as usize] += 1;
+
+            // periodically print
+            if i % self.print_interval == 0 {
+                time = self.sample("run", i, time, clock, out);
+            }
+        }
+
+        let [read, update, insert, delete] = counts;
+        out.emit(
+            format_args!(
+                "OPS read={} update={} insert={} delete={} misses={}",
+                read, update, insert, delete, misses
+            ),
+            &[
+                Record::new("run", "read", read),
+                Record::new("run", "update", update),
+                Record::new("run", "insert", insert),
+                Record::new("run", "delete", delete),
+                Record::new("run", "misses", misses),
+            ],
+        )
+        .unwrap();
+
+        if self.latency {
+            for op in &[Op::Read, Op::Update, Op::Insert, Op::Delete] {
+                let hist = &hists[*op as usize];
+                if hist.count() > 0 {
+                    let phase = format!("run.{}", op.as_str());
+                    hist.report(out, &phase, clock, Units::Nanoseconds).unwrap();
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// `get` `ngets` keys from the `nputs` filled keys, writing measurements to `out`.
+    pub fn get<B: KvBackend>(&self, backend: &mut B, out: &mut Output) -> Result<(), B::Error> {
+        if self.rdtsc {
+            self.get_with_clock::<TscInstant, B>(backend, ClockSource::Rdtsc, out)
+        } else {
+            self.get_with_clock::<Instant, B>(backend, ClockSource::Monotonic, out)
+        }
+    }
+
+    fn get_with_clock<C: Clock, B: KvBackend>(
+        &self,
+        backend: &mut B,
+        clock: ClockSource,
+        out: &mut Output,
+    ) -> Result<(), B::Error> {
+        let mut keys = KeyChooser::new(self.keys.clone(), self.seed);
+        let mut hist = Histogram::new();
+        let mut misses = 0usize;
+
+        let mut time = C::now();
+
+        for i in 0..self.ngets {
+            let key = format!("{}", keys.next(self.nputs.max(1)));
+            let val = self.timed::<C, _>(&mut hist, || {
+                with_retries(backend, self.max_retries, |backend| backend.get(&key))
+            })?;
+            if val.is_none() {
+                misses += 1;
+            }
+
+            // periodically print
+            if i % self.print_interval == 0 {
+                time = self.sample("get", i, time, clock, out);
+            }
+        }
+
+        out.emit(
+            format_args!("GETS {} misses={}", self.ngets, misses),
+            &[
+                Record::new("get", "gets", self.ngets),
+                Record::new("get", "misses", misses),
+            ],
+        )
+        .unwrap();
+
+        if self.latency {
+            hist.report(out, "get", clock, Units::Nanoseconds).unwrap();
+        }
+
+        Ok(())
+    }
+
+    /// Run `op`, recording its latency (in nanoseconds) in `hist` if `self.latency` is set.
+    fn timed<C: Clock, T>(&self, hist: &mut Histogram, op: impl FnOnce() -> T) -> T {
+        if !self.latency {
+            return op();
+        }
+
+        let start = C::now();
+        let result = op();
+        let end = C::now();
+        hist.record(end.duration_since(start).as_nanos() as u64);
+
+        result
+    }
+
+    /// Emit a measurement for the `i`-th operation of `phase`. `time` is the time of the last
+    /// measurement. Returns the time of this measurement.
+    fn sample<C: Clock>(
+        &self,
+        phase: &str,
+        i: usize,
+        time: C,
+        clock: ClockSource,
+        out: &mut Output,
+    ) -> C {
+        if self.page_tables {
+            let kbs = crate::get_page_table_kbs().expect("unable to read page tables");
+            out.emit(
+                format_args!("DONE {} {}", i, kbs),
+                &[Record::new(phase, "page_tables", kbs)
+                    .iteration(i)
+                    .units(Units::Kilobytes)],
+            )
+            .unwrap();
+
+            time
+        } else {
+            let now = C::now();
+            let diff = now.duration_since(time);
+            let hypercall = self.hv.map(|hv| hv.host_elapsed());
+            let duration = Record::new(phase, "duration", diff.as_nanos() as u64)
+                .iteration(i)
+                .clock(clock)
+                .units(Units::Nanoseconds);
+            let host_elapsed = hypercall.map(|hypercall| {
+                Record::new(phase, "host_elapsed", hypercall)
+                    .iteration(i)
+                    .clock(ClockSource::Hypercall)
+                    .units(Units::Cycles)
+            });
+            let records: Vec<_> = Some(duration).into_iter().chain(host_elapsed).collect();
+            out.emit(
+                format_args!(
+                    "DONE {} Duration {{ secs: {}, nanos: {} }} {}",
+                    i,
+                    diff.as_secs(),
+                    diff.subsec_nanos(),
+                    hypercall.unwrap_or(0)
+                ),
+                &records,
+            )
+            .unwrap();
+
+            now
+        }
+    }
+}
@@ -2,6 +2,28 @@
 
 use std::arch::asm;
 
+pub mod access;
+pub mod buddyinfo;
+pub mod calibration;
+pub mod clock;
+pub mod dist;
+pub mod drift;
+mod error;
+pub mod hypervisor;
+pub mod keys;
+pub mod kv;
+pub mod output;
+pub mod pagemap;
+pub mod payload;
+pub mod region;
+pub mod sampler;
+pub mod script;
+pub mod stats;
+pub mod timer;
+pub mod topology;
+
+pub use crate::error::{Error, Result};
+
 /// The host elapsed time hypercall number.
 const HV_GET_HOST_ELAPSED: u32 = 0x9;
 
@@ -72,11 +94,25 @@ pub fn vmcall_pf_time(pf_time: u64) {
     }
 }
 
-pub fn get_page_table_kbs() -> usize {
-    bmk_linux::linux4_4::procfs::meminfo::ProcMeminfo::read()
-        .unwrap()
-        .page_tables
-        .kilobytes()
+/// Read the amount of memory used by page tables (in KB) from `/proc/meminfo`.
+pub fn get_page_table_kbs() -> Result<usize> {
+    meminfo_kbs("PageTables")
+}
+
+/// Read the given field of `/proc/meminfo` (in KB).
+pub fn meminfo_kbs(field: &str) -> Result<usize> {
+    const MEMINFO_PATH: &str = "/proc/meminfo";
+
+    let meminfo = error::read_to_string(MEMINFO_PATH)?;
+
+    let line = meminfo
+        .lines()
+        .find(|line| line.split(':').next() == Some(field))
+        .ok_or_else(|| Error::malformed(MEMINFO_PATH, format!("no field `{}`", field)))?;
+
+    // Lines look like "PageTables:     1234 kB"
+    let mut value = line.split(':').nth(1).unwrap_or("").split_whitespace();
+    error::parse(MEMINFO_PATH, value.next())
 }
 
 /// Stats from `proc/compact_instrumentation`.
@@ -89,22 +125,25 @@ pub struct CompactInstrumentationStats {
 }
 
 /// Read the contents of `/proc/compact_instrumentation`.
-pub fn thp_compact_instrumentation() -> CompactInstrumentationStats {
+///
+/// Returns `Error::Missing` if the kernel does not have compaction instrumentation.
+pub fn thp_compact_instrumentation() -> Result<CompactInstrumentationStats> {
     const COMPACT_INSTRUMENTATION_PATH: &str = "/proc/compact_instrumentation";
 
-    let stats =
-        std::fs::read_to_string(COMPACT_INSTRUMENTATION_PATH).expect("unable to read procfs");
+    let stats = error::read_to_string(COMPACT_INSTRUMENTATION_PATH)?;
 
     let mut stats = stats.split_whitespace();
 
-    CompactInstrumentationStats {
-        ops: stats.next().unwrap().parse().unwrap(),
-        undos: stats.next().unwrap().parse().unwrap(),
-    }
+    Ok(CompactInstrumentationStats {
+        ops: error::parse(COMPACT_INSTRUMENTATION_PATH, stats.next())?,
+        undos: error::parse(COMPACT_INSTRUMENTATION_PATH, stats.next())?,
+    })
 }
 
 /// Trigger the given number of compaction attempts.
-pub fn trigger_compaction(n: u16) -> Result<(), std::io::Error> {
+///
+/// Returns `Error::Missing` if the kernel does not support triggering compaction.
+pub fn trigger_compaction(n: u16) -> Result<()> {
     const COMPACT_TRIGGER_PATH: &str = "/proc/compact_trigger";
 
     // Needs to be a C-FFI-compatible string. So we will manually format `n` into a null-terminated
@@ -127,21 +166,17 @@ pub fn trigger_compaction(n: u16) -> Result<(), std::io::Error> {
     // null terminate
     s.push(0);
 
-    std::fs::write(COMPACT_TRIGGER_PATH, s)
+    std::fs::write(COMPACT_TRIGGER_PATH, s).map_err(|err| Error::from_io(COMPACT_TRIGGER_PATH, err))
 }
 
-/// Pin the calling thread to the given logical core.
-///
-/// # Panics
+/// Pin the calling thread to the given logical cores: either a single core or a
+/// `topology::CpuSet`.
 ///
-/// If an error is returned from `sched_setaffinity`.
-pub fn set_cpu(core: usize) {
-    unsafe {
-        let mut cpuset = std::mem::MaybeUninit::<libc::cpu_set_t>::uninit();
-        libc::CPU_ZERO(cpuset.assume_init_mut());
-        let mut cpuset = cpuset.assume_init();
-        libc::CPU_SET(core, &mut cpuset);
+/// Returns `Error::Syscall` if `sched_setaffinity` fails (e.g. none of the cores exist).
+pub fn set_cpu(cpus: impl Into<topology::CpuSet>) -> Result<()> {
+    let cpuset = cpus.into().to_libc();
 
+    unsafe {
         let res = libc::sched_setaffinity(
             /* self */ 0,
             std::mem::size_of::<libc::cpu_set_t>(),
@@ -149,8 +184,9 @@ pub fn set_cpu(core: usize) {
         );
 
         if res != 0 {
-            let err = errno::errno();
-            panic!("sched_setaffinity failed: {}", err);
+            return Err(Error::last_syscall("sched_setaffinity"));
         }
     }
+
+    Ok(())
 }
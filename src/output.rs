@@ -0,0 +1,429 @@
+//! Machine-readable output for the experiment binaries.
+//!
+//! Every binary takes a `--format` flag. In `text` mode, the binary prints its traditional ad-hoc
+//! output. In `jsonl` and `csv` modes, it instead emits a stream of `Record`s, each of which is a
+//! single measurement tagged with the phase of the experiment, the iteration, the clock source and
+//! the units of the value.
+
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use crate::{clock::TscClock, Error, Result};
+
+/// The output formats supported by the binaries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    /// Human-readable ad-hoc text (the historical output of each binary).
+    #[default]
+    Text,
+
+    /// One JSON object per line.
+    Jsonl,
+
+    /// Comma-separated values with a header row.
+    Csv,
+}
+
+impl Format {
+    /// The names accepted by `from_name`.
+    pub const NAMES: &'static [&'static str] = &["text", "jsonl", "csv"];
+
+    /// Parse a format name (one of `NAMES`).
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "text" => Some(Format::Text),
+            "jsonl" => Some(Format::Jsonl),
+            "csv" => Some(Format::Csv),
+            _ => None,
+        }
+    }
+
+    /// The format named by an optional command line argument (checked with `is_format`), or the
+    /// default if it was not given.
+    pub fn from_arg(arg: Option<&str>) -> Self {
+        arg.map(|name| Format::from_name(name).unwrap())
+            .unwrap_or_default()
+    }
+}
+
+/// A clap validator for format names.
+pub fn is_format(arg: String) -> std::result::Result<(), String> {
+    Format::from_name(&arg)
+        .map(|_| ())
+        .ok_or_else(|| format!("Not a valid format (expected one of {:?})", Format::NAMES))
+}
+
+/// Where the value of a record came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockSource {
+    /// The `rdtsc` instruction.
+    Rdtsc,
+
+    /// `std::time::Instant` (i.e. `CLOCK_MONOTONIC`).
+    Monotonic,
+
+    /// The host elapsed time hypercall.
+    Hypercall,
+
+    /// The value is not a time (e.g. a size or a counter).
+    None,
+}
+
+impl ClockSource {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ClockSource::Rdtsc => "rdtsc",
+            ClockSource::Monotonic => "monotonic",
+            ClockSource::Hypercall => "hypercall",
+            ClockSource::None => "none",
+        }
+    }
+}
+
+/// The units of the value of a record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Units {
+    Cycles,
+    Nanoseconds,
+    Kilobytes,
+    Bytes,
+    Pages,
+    Count,
+    Percent,
+    Hertz,
+    PartsPerMillion,
+}
+
+impl Units {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Units::Cycles => "cycles",
+            Units::Nanoseconds => "ns",
+            Units::Kilobytes => "kB",
+            Units::Bytes => "B",
+            Units::Pages => "pages",
+            Units::Count => "count",
+            Units::Percent => "percent",
+            Units::Hertz => "Hz",
+            Units::PartsPerMillion => "ppm",
+        }
+    }
+}
+
+/// The value of a record.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Unsigned(u64),
+    Signed(i64),
+    Float(f64),
+}
+
+impl From<u64> for Value {
+    fn from(v: u64) -> Self {
+        Value::Unsigned(v)
+    }
+}
+
+impl From<usize> for Value {
+    fn from(v: usize) -> Self {
+        Value::Unsigned(v as u64)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(v: i64) -> Self {
+        Value::Signed(v)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(v: f64) -> Self {
+        Value::Float(v)
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Unsigned(v) => write!(f, "{}", v),
+            Value::Signed(v) => write!(f, "{}", v),
+            // JSON has no representation for NaN or infinity.
+            Value::Float(v) if !v.is_finite() => write!(f, "null"),
+            Value::Float(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+/// A single measurement.
+#[derive(Debug, Clone)]
+pub struct Record<'a> {
+    /// The phase of the experiment (e.g. "fill" or "delete").
+    pub phase: &'a str,
+
+    /// The iteration within the phase, if any.
+    pub iteration: Option<usize>,
+
+    /// What is being measured (e.g. "latency" or "page_tables").
+    pub metric: &'a str,
+
+    /// The measured value.
+    pub value: Value,
+
+    /// The clock that the value was measured with.
+    pub clock: ClockSource,
+
+    /// The units of `value`.
+    pub units: Units,
+}
+
+impl<'a> Record<'a> {
+    /// A new record with no iteration, no clock and `Count` units.
+    pub fn new(phase: &'a str, metric: &'a str, value: impl Into<Value>) -> Self {
+        Record {
+            phase,
+            iteration: None,
+            metric,
+            value: value.into(),
+            clock: ClockSource::None,
+            units: Units::Count,
+        }
+    }
+
+    pub fn iteration(mut self, iteration: usize) -> Self {
+        self.iteration = Some(iteration);
+        self
+    }
+
+    pub fn clock(mut self, clock: ClockSource) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn units(mut self, units: Units) -> Self {
+        self.units = units;
+        self
+    }
+}
+
+/// The columns of the CSV output, in order.
+const CSV_HEADER: &str = "phase,iteration,metric,value,clock,units";
+
+/// Where an `Output` writes to, shared by all of its handles (see `Output::share`).
+struct Sink {
+    out: Box<dyn Write + Send>,
+
+    /// Have we written the CSV header yet?
+    header_written: bool,
+}
+
+/// A sink for records in the chosen format.
+pub struct Output {
+    format: Format,
+    sink: Arc<Mutex<Sink>>,
+
+    /// If set, `rdtsc` cycle counts are converted to nanoseconds with this clock.
+    tsc: Option<TscClock>,
+}
+
+impl Output {
+    /// Write to stdout.
+    pub fn stdout(format: Format) -> Self {
+        Self::new(format, Box::new(io::stdout()))
+    }
+
+    /// Create (or truncate) the file at `path` and write to it.
+    pub fn create(format: Format, path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::create(path).map_err(|err| Error::from_io(path, err))?;
+        Ok(Self::new(format, Box::new(BufWriter::new(file))))
+    }
+
+    /// Write to an arbitrary writer.
+    pub fn new(format: Format, out: Box<dyn Write + Send>) -> Self {
+        Output {
+            format,
+            sink: Arc::new(Mutex::new(Sink {
+                out,
+                header_written: false,
+            })),
+            tsc: None,
+        }
+    }
+
+    /// Another handle to the same destination with the same settings, e.g. for a background
+    /// thread. Each line is written whole, and the CSV header is only written once.
+    pub fn share(&self) -> Self {
+        Output {
+            format: self.format,
+            sink: Arc::clone(&self.sink),
+            tsc: self.tsc,
+        }
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Convert records of `rdtsc` cycle counts to nanoseconds with the given clock from now on. In
+    /// `Text` mode, the ad-hoc text of measurements in cycles can't be converted, so `emit` writes
+    /// the generic rendering of their records instead.
+    pub fn convert_cycles(&mut self, tsc: TscClock) {
+        self.tsc = Some(tsc);
+    }
+
+    /// Will `record` convert this record?
+    fn converts(&self, record: &Record) -> bool {
+        self.tsc.is_some() && record.clock == ClockSource::Rdtsc && record.units == Units::Cycles
+    }
+
+    /// Emit a measurement. In `Text` mode, `text` is written as a line. Otherwise, each of
+    /// `records` is written.
+    pub fn emit(&mut self, text: fmt::Arguments, records: &[Record]) -> io::Result<()> {
+        match self.format {
+            Format::Text if !records.iter().any(|record| self.converts(record)) => {
+                writeln!(self.sink.lock().unwrap().out, "{}", text)
+            }
+            _ => records.iter().try_for_each(|record| self.record(record)),
+        }
+    }
+
+    /// Write a line only in `Text` mode (e.g. a separator between phases).
+    pub fn text(&mut self, text: fmt::Arguments) -> io::Result<()> {
+        match self.format {
+            Format::Text => writeln!(self.sink.lock().unwrap().out, "{}", text),
+            _ => Ok(()),
+        }
+    }
+
+    /// Write a single record. In `Text` mode, a generic rendering of the record is used.
+    pub fn record(&mut self, record: &Record) -> io::Result<()> {
+        let converted;
+        let record = match self.tsc {
+            Some(tsc) if self.converts(record) => {
+                let ns = |cycles: f64| tsc.cycles_to_ns(cycles);
+                converted = Record {
+                    value: match record.value {
+                        Value::Unsigned(v) => Value::Unsigned(ns(v as f64).round() as u64),
+                        Value::Signed(v) => Value::Signed(ns(v as f64).round() as i64),
+                        Value::Float(v) => Value::Float(ns(v)),
+                    },
+                    units: Units::Nanoseconds,
+                    ..record.clone()
+                };
+                &converted
+            }
+            _ => record,
+        };
+
+        // Hold the lock for the whole line, so that lines from other handles don't interleave.
+        let mut sink = self.sink.lock().unwrap();
+        let Sink {
+            out,
+            header_written,
+        } = &mut *sink;
+
+        match self.format {
+            Format::Text => {
+                write!(out, "{} ", record.phase)?;
+                if let Some(iteration) = record.iteration {
+                    write!(out, "{} ", iteration)?;
+                }
+                writeln!(
+                    out,
+                    "{}: {} {}",
+                    record.metric,
+                    record.value,
+                    record.units.as_str()
+                )
+            }
+
+            Format::Jsonl => {
+                write!(out, "{{\"phase\":")?;
+                write_json_str(out, record.phase)?;
+                match record.iteration {
+                    Some(iteration) => write!(out, ",\"iteration\":{}", iteration)?,
+                    None => write!(out, ",\"iteration\":null")?,
+                }
+                write!(out, ",\"metric\":")?;
+                write_json_str(out, record.metric)?;
+                writeln!(
+                    out,
+                    ",\"value\":{},\"clock\":\"{}\",\"units\":\"{}\"}}",
+                    record.value,
+                    record.clock.as_str(),
+                    record.units.as_str()
+                )
+            }
+
+            Format::Csv => {
+                if !*header_written {
+                    writeln!(out, "{}", CSV_HEADER)?;
+                    *header_written = true;
+                }
+
+                write_csv_str(out, record.phase)?;
+                write!(out, ",")?;
+                if let Some(iteration) = record.iteration {
+                    write!(out, "{}", iteration)?;
+                }
+                write!(out, ",")?;
+                write_csv_str(out, record.metric)?;
+                let value = match record.value {
+                    // Leave non-finite floats empty rather than writing `null`.
+                    Value::Float(v) if !v.is_finite() => String::new(),
+                    value => value.to_string(),
+                };
+                writeln!(
+                    out,
+                    ",{},{},{}",
+                    value,
+                    record.clock.as_str(),
+                    record.units.as_str()
+                )
+            }
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.sink.lock().unwrap().out.flush()
+    }
+}
+
+impl Drop for Output {
+    fn drop(&mut self) {
+        if let Ok(mut sink) = self.sink.lock() {
+            let _ = sink.out.flush();
+        }
+    }
+}
+
+/// Write `s` as a quoted and escaped JSON string.
+fn write_json_str(out: &mut dyn Write, s: &str) -> io::Result<()> {
+    write!(out, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(out, "\\\"")?,
+            '\\' => write!(out, "\\\\")?,
+            '\n' => write!(out, "\\n")?,
+            '\r' => write!(out, "\\r")?,
+            '\t' => write!(out, "\\t")?,
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32)?,
+            c => write!(out, "{}", c)?,
+        }
+    }
+    write!(out, "\"")
+}
+
+/// Write `s` as a CSV field, quoting it if needed.
+fn write_csv_str(out: &mut dyn Write, s: &str) -> io::Result<()> {
+    if s.contains([',', '"', '\n', '\r']) {
+        write!(out, "\"{}\"", s.replace('"', "\"\""))
+    } else {
+        write!(out, "{}", s)
+    }
+}
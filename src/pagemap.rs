@@ -0,0 +1,497 @@
+//! Readers for `/proc/<pid>/pagemap`, `/proc/kpageflags` and `/proc/kpagecount`, which say how each
+//! virtual page of a process is mapped and what kind of physical page backs it.
+//!
+//! See `Documentation/admin-guide/mm/pagemap.rst`. Without `CAP_SYS_ADMIN`, the kernel reports all
+//! PFNs as 0 and `/proc/kpageflags` and `/proc/kpagecount` cannot be opened, so only presence and
+//! swap information is available.
+//!
+//! A `PageInspector` joins the three files to produce a `PageInfo` for every page of a virtual
+//! range, and a `PageSummary` aggregates them, e.g. to audit where a benchmark's memory landed or
+//! how much of a server's heap is THP-backed.
+
+use std::{
+    fs::File,
+    io,
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    error,
+    output::{Output, Record, Units},
+    Error, Result,
+};
+
+/// The size of an entry in `pagemap` and `kpageflags`.
+const ENTRY_SIZE: u64 = 8;
+
+/// The base page size.
+const PAGE_SIZE: u64 = 1 << 12;
+
+const KPAGEFLAGS_PATH: &str = "/proc/kpageflags";
+const KPAGECOUNT_PATH: &str = "/proc/kpagecount";
+
+/// The number of pagemap entries read at a time when walking a range.
+const BATCH: usize = 512;
+
+/// An entry of `/proc/<pid>/pagemap`, describing one virtual page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PagemapEntry(pub u64);
+
+impl PagemapEntry {
+    const PFN_MASK: u64 = (1 << 55) - 1;
+    const SOFT_DIRTY: u64 = 1 << 55;
+    const EXCLUSIVE: u64 = 1 << 56;
+    const FILE_OR_SHARED: u64 = 1 << 61;
+    const SWAPPED: u64 = 1 << 62;
+    const PRESENT: u64 = 1 << 63;
+
+    /// Whether the page is present in RAM.
+    pub fn present(self) -> bool {
+        self.0 & Self::PRESENT != 0
+    }
+
+    /// Whether the page is swapped out.
+    pub fn swapped(self) -> bool {
+        self.0 & Self::SWAPPED != 0
+    }
+
+    /// Whether the page is file-backed or shared anonymous memory.
+    pub fn file_or_shared(self) -> bool {
+        self.0 & Self::FILE_OR_SHARED != 0
+    }
+
+    /// Whether the page is mapped exclusively by this process.
+    pub fn exclusive(self) -> bool {
+        self.0 & Self::EXCLUSIVE != 0
+    }
+
+    pub fn soft_dirty(self) -> bool {
+        self.0 & Self::SOFT_DIRTY != 0
+    }
+
+    /// The physical frame number, if the page is present and the PFN is visible to us.
+    pub fn pfn(self) -> Option<u64> {
+        match self.0 & Self::PFN_MASK {
+            pfn if self.present() && pfn != 0 => Some(pfn),
+            _ => None,
+        }
+    }
+}
+
+/// An entry of `/proc/kpageflags`, describing one physical page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KPageFlags(pub u64);
+
+impl KPageFlags {
+    pub const ANON: u64 = 1 << 12;
+    pub const COMPOUND_HEAD: u64 = 1 << 15;
+    pub const COMPOUND_TAIL: u64 = 1 << 16;
+    pub const HUGE: u64 = 1 << 17;
+    pub const NOPAGE: u64 = 1 << 20;
+    pub const KSM: u64 = 1 << 21;
+    pub const THP: u64 = 1 << 22;
+    pub const ZERO_PAGE: u64 = 1 << 24;
+
+    fn has(self, flag: u64) -> bool {
+        self.0 & flag != 0
+    }
+
+    /// Whether the page is part of a transparent huge page.
+    pub fn thp(self) -> bool {
+        self.has(Self::THP)
+    }
+
+    /// Whether the page is part of a hugetlb page.
+    pub fn hugetlb(self) -> bool {
+        self.has(Self::HUGE)
+    }
+
+    /// Whether the page is part of a huge page of either kind.
+    pub fn huge(self) -> bool {
+        self.thp() || self.hugetlb()
+    }
+
+    /// Whether the page is the first page of a compound (e.g. huge) page.
+    pub fn compound_head(self) -> bool {
+        self.has(Self::COMPOUND_HEAD)
+    }
+
+    /// Whether the page is a non-first page of a compound (e.g. huge) page.
+    pub fn compound_tail(self) -> bool {
+        self.has(Self::COMPOUND_TAIL)
+    }
+
+    /// Whether the page is the shared zero page (or huge zero page).
+    pub fn zero_page(self) -> bool {
+        self.has(Self::ZERO_PAGE)
+    }
+
+    /// Whether the page has been merged by KSM.
+    pub fn ksm(self) -> bool {
+        self.has(Self::KSM)
+    }
+
+    pub fn anon(self) -> bool {
+        self.has(Self::ANON)
+    }
+}
+
+/// Reads 64-bit entries from an open `pagemap`-style file.
+fn read_entry(file: &File, path: &Path, index: u64) -> Result<u64> {
+    let mut buf = [0; ENTRY_SIZE as usize];
+    file.read_exact_at(&mut buf, index * ENTRY_SIZE)
+        .map_err(|err| Error::from_io(path, err))?;
+    Ok(u64::from_ne_bytes(buf))
+}
+
+/// An open `/proc/<pid>/pagemap`.
+#[derive(Debug)]
+pub struct Pagemap {
+    path: PathBuf,
+    file: File,
+}
+
+impl Pagemap {
+    /// Open the pagemap of the given process, or of this process if `pid` is `None`.
+    pub fn open(pid: Option<u32>) -> Result<Self> {
+        let path = PathBuf::from(match pid {
+            Some(pid) => format!("/proc/{}/pagemap", pid),
+            None => "/proc/self/pagemap".into(),
+        });
+        let file = File::open(&path).map_err(|err| Error::from_io(&path, err))?;
+        Ok(Pagemap { path, file })
+    }
+
+    /// The entry for the page containing `vaddr`.
+    pub fn entry(&self, vaddr: usize) -> Result<PagemapEntry> {
+        read_entry(&self.file, &self.path, vaddr as u64 / PAGE_SIZE).map(PagemapEntry)
+    }
+}
+
+/// An open `/proc/kpageflags`.
+#[derive(Debug)]
+pub struct KPageFlagsFile {
+    path: PathBuf,
+    file: File,
+}
+
+impl KPageFlagsFile {
+    /// Open `/proc/kpageflags`. This requires root.
+    pub fn open() -> Result<Self> {
+        let path = PathBuf::from(KPAGEFLAGS_PATH);
+        let file = File::open(&path).map_err(|err| Error::from_io(&path, err))?;
+        Ok(KPageFlagsFile { path, file })
+    }
+
+    /// The flags of the given physical frame.
+    pub fn flags(&self, pfn: u64) -> Result<KPageFlags> {
+        read_entry(&self.file, &self.path, pfn).map(KPageFlags)
+    }
+}
+
+/// An open `/proc/kpagecount`.
+#[derive(Debug)]
+pub struct KPageCountFile {
+    path: PathBuf,
+    file: File,
+}
+
+impl KPageCountFile {
+    /// Open `/proc/kpagecount`. This requires root.
+    pub fn open() -> Result<Self> {
+        let path = PathBuf::from(KPAGECOUNT_PATH);
+        let file = File::open(&path).map_err(|err| Error::from_io(&path, err))?;
+        Ok(KPageCountFile { path, file })
+    }
+
+    /// The number of times the given physical frame is mapped.
+    pub fn count(&self, pfn: u64) -> Result<u64> {
+        read_entry(&self.file, &self.path, pfn)
+    }
+}
+
+/// Everything we know about one virtual page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageInfo {
+    pub vaddr: usize,
+    pub entry: PagemapEntry,
+
+    /// The flags of the physical page, if it is present and we can see them.
+    pub flags: Option<KPageFlags>,
+
+    /// The number of mappings of the physical page, if it is present and we can see it.
+    pub mapcount: Option<u64>,
+}
+
+impl PageInfo {
+    pub fn present(&self) -> bool {
+        self.entry.present()
+    }
+
+    pub fn swapped(&self) -> bool {
+        self.entry.swapped()
+    }
+
+    pub fn pfn(&self) -> Option<u64> {
+        self.entry.pfn()
+    }
+
+    fn flag(&self, f: fn(KPageFlags) -> bool) -> bool {
+        self.flags.map(f).unwrap_or(false)
+    }
+
+    /// Whether the page is the head of a THP.
+    pub fn thp_head(&self) -> bool {
+        self.flag(|f| f.thp() && f.compound_head())
+    }
+
+    /// Whether the page is a tail page of a THP.
+    pub fn thp_tail(&self) -> bool {
+        self.flag(|f| f.thp() && f.compound_tail())
+    }
+
+    pub fn thp(&self) -> bool {
+        self.flag(KPageFlags::thp)
+    }
+
+    pub fn hugetlb(&self) -> bool {
+        self.flag(KPageFlags::hugetlb)
+    }
+
+    pub fn zero_page(&self) -> bool {
+        self.flag(KPageFlags::zero_page)
+    }
+
+    pub fn ksm(&self) -> bool {
+        self.flag(KPageFlags::ksm)
+    }
+}
+
+/// Joins a process's pagemap with `/proc/kpageflags` and `/proc/kpagecount`.
+#[derive(Debug)]
+pub struct PageInspector {
+    pagemap: Pagemap,
+    kpageflags: Option<KPageFlagsFile>,
+    kpagecount: Option<KPageCountFile>,
+}
+
+impl PageInspector {
+    /// Open the pagemap of the given process (or this process if `pid` is `None`), along with
+    /// `/proc/kpageflags` and `/proc/kpagecount` if we are allowed to read them.
+    pub fn open(pid: Option<u32>) -> Result<Self> {
+        Ok(PageInspector {
+            pagemap: Pagemap::open(pid)?,
+            kpageflags: KPageFlagsFile::open().ok(),
+            kpagecount: KPageCountFile::open().ok(),
+        })
+    }
+
+    /// Whether physical page information is available (i.e. we are root).
+    pub fn has_physical(&self) -> bool {
+        self.kpageflags.is_some()
+    }
+
+    /// Call `f` with the `PageInfo` of every page in `[start, start + len)`.
+    pub fn walk(&self, start: usize, len: usize, mut f: impl FnMut(PageInfo)) -> Result<()> {
+        let first = start as u64 / PAGE_SIZE;
+        let end = ((start + len) as u64).div_ceil(PAGE_SIZE);
+
+        let mut buf = vec![0u8; BATCH * ENTRY_SIZE as usize];
+        let mut page = first;
+
+        while page < end {
+            let n = ((end - page) as usize).min(BATCH);
+            let buf = &mut buf[..n * ENTRY_SIZE as usize];
+            self.pagemap
+                .file
+                .read_exact_at(buf, page * ENTRY_SIZE)
+                .map_err(|err| Error::from_io(&self.pagemap.path, err))?;
+
+            for (i, raw) in buf.chunks_exact(ENTRY_SIZE as usize).enumerate() {
+                let mut bytes = [0; ENTRY_SIZE as usize];
+                bytes.copy_from_slice(raw);
+                let entry = PagemapEntry(u64::from_ne_bytes(bytes));
+
+                let (flags, mapcount) = match entry.pfn() {
+                    Some(pfn) => (
+                        self.kpageflags.as_ref().map(|k| k.flags(pfn)).transpose()?,
+                        self.kpagecount.as_ref().map(|k| k.count(pfn)).transpose()?,
+                    ),
+                    None => (None, None),
+                };
+
+                f(PageInfo {
+                    vaddr: ((page + i as u64) * PAGE_SIZE) as usize,
+                    entry,
+                    flags,
+                    mapcount,
+                });
+            }
+
+            page += n as u64;
+        }
+
+        Ok(())
+    }
+
+    /// The `PageInfo` of every page in `[start, start + len)`.
+    pub fn pages(&self, start: usize, len: usize) -> Result<Vec<PageInfo>> {
+        let mut pages = Vec::with_capacity(len / PAGE_SIZE as usize + 1);
+        self.walk(start, len, |page| pages.push(page))?;
+        Ok(pages)
+    }
+
+    /// A summary of the pages in `[start, start + len)`.
+    pub fn summarize(&self, start: usize, len: usize) -> Result<PageSummary> {
+        let mut summary = PageSummary::default();
+        self.walk(start, len, |page| summary.add(&page))?;
+        Ok(summary)
+    }
+}
+
+/// Aggregate counts over a set of pages. All counts are in base pages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PageSummary {
+    pub pages: u64,
+    pub present: u64,
+    pub swapped: u64,
+
+    /// Present pages whose PFN we cannot see (i.e. we are not root).
+    pub no_pfn: u64,
+
+    /// Pages that are part of a THP, and how many of those are THP heads.
+    pub thp: u64,
+    pub thp_heads: u64,
+
+    pub hugetlb: u64,
+    pub zero: u64,
+    pub ksm: u64,
+
+    /// Pages whose physical page is mapped more than once.
+    pub shared: u64,
+}
+
+impl PageSummary {
+    pub fn add(&mut self, page: &PageInfo) {
+        self.pages += 1;
+        self.present += page.present() as u64;
+        self.swapped += page.swapped() as u64;
+        self.no_pfn += (page.present() && page.pfn().is_none()) as u64;
+        self.thp += page.thp() as u64;
+        self.thp_heads += page.thp_head() as u64;
+        self.hugetlb += page.hugetlb() as u64;
+        self.zero += page.zero_page() as u64;
+        self.ksm += page.ksm() as u64;
+        self.shared += (page.mapcount.unwrap_or(0) > 1) as u64;
+    }
+
+    pub fn merge(&mut self, other: &PageSummary) {
+        self.pages += other.pages;
+        self.present += other.present;
+        self.swapped += other.swapped;
+        self.no_pfn += other.no_pfn;
+        self.thp += other.thp;
+        self.thp_heads += other.thp_heads;
+        self.hugetlb += other.hugetlb;
+        self.zero += other.zero;
+        self.ksm += other.ksm;
+        self.shared += other.shared;
+    }
+
+    /// Write the summary to `out`.
+    pub fn report(&self, out: &mut Output, phase: &str) -> io::Result<()> {
+        let fields = [
+            ("pages", self.pages),
+            ("present", self.present),
+            ("swapped", self.swapped),
+            ("no_pfn", self.no_pfn),
+            ("thp", self.thp),
+            ("thp_heads", self.thp_heads),
+            ("hugetlb", self.hugetlb),
+            ("zero", self.zero),
+            ("ksm", self.ksm),
+            ("shared", self.shared),
+        ];
+
+        let text = fields
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let records: Vec<_> = fields
+            .iter()
+            .map(|&(name, value)| Record::new(phase, name, value).units(Units::Pages))
+            .collect();
+
+        out.emit(format_args!("{}: {}", phase, text), &records)
+    }
+}
+
+/// A mapping of a process's address space, from `/proc/<pid>/maps`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mapping {
+    pub start: usize,
+    pub end: usize,
+    pub perms: String,
+
+    /// The backing file or pseudo-path (e.g. `[heap]`), if any.
+    pub path: Option<String>,
+}
+
+impl Mapping {
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+}
+
+/// Parse the contents of `/proc/<pid>/maps`.
+pub fn parse_maps(s: &str) -> std::result::Result<Vec<Mapping>, String> {
+    // Lines look like "7f1234000000-7f1234200000 rw-p 00000000 00:00 0    [heap]"
+    s.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            // The path is everything after the 5th field, and may contain spaces.
+            let mut rest = line;
+            let mut field = || {
+                let trimmed = rest.trim_start();
+                let end = trimmed.find(char::is_whitespace).unwrap_or(trimmed.len());
+                rest = &trimmed[end..];
+                &trimmed[..end]
+            };
+            let range = field();
+            let perms = field().to_owned();
+            // Skip the offset, device and inode.
+            field();
+            field();
+            field();
+            let path = Some(rest.trim_start())
+                .filter(|path| !path.is_empty())
+                .map(Into::into);
+
+            let malformed = || format!("bad mapping: {}", line);
+            let (start, end) = range.split_once('-').ok_or_else(malformed)?;
+
+            Ok(Mapping {
+                start: usize::from_str_radix(start, 16).map_err(|_| malformed())?,
+                end: usize::from_str_radix(end, 16).map_err(|_| malformed())?,
+                perms,
+                path,
+            })
+        })
+        .collect()
+}
+
+/// The mappings of the given process, or of this process if `pid` is `None`.
+pub fn maps(pid: Option<u32>) -> Result<Vec<Mapping>> {
+    let path = match pid {
+        Some(pid) => format!("/proc/{}/maps", pid),
+        None => "/proc/self/maps".into(),
+    };
+
+    parse_maps(&error::read_to_string(&path)?).map_err(|reason| Error::malformed(&path, reason))
+}
@@ -0,0 +1,251 @@
+//! Generators for the values stored by the key-value workloads.
+//!
+//! All-zero values are exactly what KSM, zswap and zero-page optimizations are good at, so
+//! workloads can instead choose a `Pattern` for the contents of values and a `SizeDistribution`
+//! for their sizes.
+
+use std::path::PathBuf;
+
+use rand::{rngs::SmallRng, Rng, RngCore, SeedableRng};
+
+use crate::{dist::Zipf, Error, Result};
+
+/// What to fill values with.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pattern {
+    /// All zeros.
+    Zeros,
+
+    /// Every word of the value is the index of the key (like `Pattern::Counter` in
+    /// `time_mmap_touch`), so every value is distinct but trivially compressible.
+    Counter,
+
+    /// Pseudo-random bytes (incompressible).
+    Random,
+
+    /// A random chunk repeated so that the value compresses by roughly the given ratio (e.g. 4.0
+    /// means that the value should compress to about a quarter of its size).
+    Text { ratio: f64 },
+
+    /// Consecutive chunks of the contents of the given file, wrapping around at the end.
+    File(PathBuf),
+}
+
+impl Pattern {
+    /// Parse a pattern: `zeros`, `counter`, `random`, `text:<ratio>` or `file:<path>`.
+    pub fn parse(s: &str) -> std::result::Result<Self, String> {
+        let mut parts = s.splitn(2, ':');
+        match (parts.next().unwrap(), parts.next()) {
+            ("zeros", None) => Ok(Pattern::Zeros),
+            ("counter", None) => Ok(Pattern::Counter),
+            ("random", None) => Ok(Pattern::Random),
+            ("text", Some(ratio)) => match ratio.parse::<f64>() {
+                Ok(ratio) if ratio >= 1.0 => Ok(Pattern::Text { ratio }),
+                _ => Err(format!("Not a valid compression ratio (>= 1.0): {}", ratio)),
+            },
+            ("file", Some(path)) if !path.is_empty() => Ok(Pattern::File(path.into())),
+            _ => Err(format!(
+                "Not a valid payload (expected zeros, counter, random, text:<ratio> or \
+                 file:<path>): {}",
+                s
+            )),
+        }
+    }
+}
+
+/// A clap validator for patterns.
+pub fn is_pattern(arg: String) -> std::result::Result<(), String> {
+    Pattern::parse(&arg).map(|_| ())
+}
+
+/// How large values are.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SizeDistribution {
+    /// Every value has the given size.
+    Fixed(usize),
+
+    /// Sizes are uniformly distributed in `[min, max]`.
+    Uniform { min: usize, max: usize },
+
+    /// Sizes are Zipf-distributed in `[min, max]` with the given skew; smaller values are more
+    /// common.
+    Zipf { min: usize, max: usize, theta: f64 },
+}
+
+impl SizeDistribution {
+    /// Parse a size distribution: `fixed`, `uniform:<min>:<max>` or `zipf:<min>:<max>:<theta>`.
+    /// `fixed` uses `default_size`.
+    pub fn parse(s: &str, default_size: usize) -> std::result::Result<Self, String> {
+        let parts: Vec<_> = s.split(':').collect();
+        let err = || {
+            format!(
+                "Not a valid size distribution (expected fixed, uniform:<min>:<max> or \
+                 zipf:<min>:<max>:<theta>): {}",
+                s
+            )
+        };
+        let size = |i: usize| parts[i].parse::<usize>().map_err(|_| err());
+
+        let dist = match (parts[0], parts.len()) {
+            ("fixed", 1) => SizeDistribution::Fixed(default_size),
+            ("uniform", 3) => SizeDistribution::Uniform {
+                min: size(1)?,
+                max: size(2)?,
+            },
+            ("zipf", 4) => SizeDistribution::Zipf {
+                min: size(1)?,
+                max: size(2)?,
+                theta: parts[3].parse().map_err(|_| err())?,
+            },
+            _ => return Err(err()),
+        };
+
+        match dist {
+            SizeDistribution::Fixed(0) => Err("Value size must be positive".into()),
+            SizeDistribution::Uniform { min, max } | SizeDistribution::Zipf { min, max, .. }
+                if min == 0 || min > max =>
+            {
+                Err(format!("Need 0 < min <= max: {}", s))
+            }
+            SizeDistribution::Zipf { theta, .. } if !(theta > 0.0 && theta < 1.0) => {
+                Err(format!("Need 0 < theta < 1: {}", s))
+            }
+            dist => Ok(dist),
+        }
+    }
+
+    /// The largest value size.
+    pub fn max(&self) -> usize {
+        match *self {
+            SizeDistribution::Fixed(size) => size,
+            SizeDistribution::Uniform { max, .. } | SizeDistribution::Zipf { max, .. } => max,
+        }
+    }
+}
+
+/// A clap validator for size distributions.
+pub fn is_size_distribution(arg: String) -> std::result::Result<(), String> {
+    SizeDistribution::parse(&arg, 1).map(|_| ())
+}
+
+/// The sampler for a `SizeDistribution`.
+#[derive(Debug, Clone)]
+enum Sizes {
+    Fixed(usize),
+    Uniform { min: usize, max: usize },
+    Zipf { min: usize, zipf: Zipf },
+}
+
+/// Generates values according to a `Pattern` and `SizeDistribution`.
+pub struct PayloadGenerator {
+    pattern: Pattern,
+    sizes: Sizes,
+    rng: SmallRng,
+
+    /// The generated value. Reused across calls to avoid allocation.
+    buf: Vec<u8>,
+
+    /// For `Pattern::File`, the contents of the file and the offset of the next value.
+    file: Vec<u8>,
+    file_offset: usize,
+}
+
+impl PayloadGenerator {
+    /// Create a generator. `seed` seeds all randomness, so the same seed produces the same values.
+    ///
+    /// Returns an error if the pattern is `File` and the file cannot be read or is empty.
+    pub fn new(pattern: Pattern, sizes: SizeDistribution, seed: u64) -> Result<Self> {
+        let file = match pattern {
+            Pattern::File(ref path) => {
+                let file = std::fs::read(path).map_err(|err| Error::from_io(path, err))?;
+                if file.is_empty() {
+                    return Err(Error::malformed(path, "file is empty"));
+                }
+                file
+            }
+            _ => Vec::new(),
+        };
+
+        let buf = vec![0; sizes.max()];
+
+        let sizes = match sizes {
+            SizeDistribution::Fixed(size) => Sizes::Fixed(size),
+            SizeDistribution::Uniform { min, max } => Sizes::Uniform { min, max },
+            SizeDistribution::Zipf { min, max, theta } => Sizes::Zipf {
+                min,
+                zipf: Zipf::new(max - min + 1, theta),
+            },
+        };
+
+        Ok(PayloadGenerator {
+            pattern,
+            sizes,
+            rng: SmallRng::seed_from_u64(seed),
+            buf,
+            file,
+            file_offset: 0,
+        })
+    }
+
+    /// All-zero values of the given size (the historical behavior of the KV workloads).
+    pub fn zeros(size: usize) -> Self {
+        Self::new(Pattern::Zeros, SizeDistribution::Fixed(size), 0).unwrap()
+    }
+
+    /// The expected size of a value.
+    pub fn mean_size(&self) -> f64 {
+        match self.sizes {
+            Sizes::Fixed(size) => size as f64,
+            Sizes::Uniform { min, max } => (min + max) as f64 / 2.0,
+            Sizes::Zipf { min, ref zipf } => (0..zipf.n())
+                .map(|i| (min + i) as f64 * zipf.probability(i))
+                .sum(),
+        }
+    }
+
+    /// Generate the value for the key with the given index. The value is only valid until the next
+    /// call.
+    pub fn next(&mut self, key: usize) -> &[u8] {
+        let size = match self.sizes {
+            Sizes::Fixed(size) => size,
+            Sizes::Uniform { min, max } => self.rng.gen_range(min, max + 1),
+            Sizes::Zipf { min, ref zipf } => min + zipf.sample(&mut self.rng),
+        };
+
+        let buf = &mut self.buf[..size];
+
+        match self.pattern {
+            // `buf` is never written to in this case.
+            Pattern::Zeros => {}
+
+            Pattern::Counter => {
+                let word = (key as u64).to_le_bytes();
+                for (i, b) in buf.iter_mut().enumerate() {
+                    *b = word[i % word.len()];
+                }
+            }
+
+            Pattern::Random => self.rng.fill_bytes(buf),
+
+            Pattern::Text { ratio } => {
+                // Generate a random prefix and repeat it. An LZ-style compressor will only have to
+                // store the prefix, which is incompressible because every byte value is equally
+                // likely.
+                let unique = ((size as f64 / ratio).ceil() as usize).clamp(1, size.max(1));
+                self.rng.fill_bytes(&mut buf[..unique]);
+                for i in unique..size {
+                    buf[i] = buf[i % unique];
+                }
+            }
+
+            Pattern::File(_) => {
+                for b in buf.iter_mut() {
+                    *b = self.file[self.file_offset];
+                    self.file_offset = (self.file_offset + 1) % self.file.len();
+                }
+            }
+        }
+
+        &self.buf[..size]
+    }
+}
@@ -0,0 +1,638 @@
+//! Memory regions with control over page sizes and backing, for the mmap-based micro-benchmarks.
+//!
+//! By default, a region is a private anonymous mapping, and huge pages are left to the system's THP
+//! settings. A `RegionBuilder` can instead ask for THP with `madvise`, use hugetlb pages, or back the
+//! region with a memfd or a file on tmpfs/hugetlbfs. After mapping, `Region::usage` reports (from
+//! `/proc/self/smaps`) how much of the region is actually backed by huge pages.
+//!
+//! A `MemPolicy` places memory on NUMA nodes, either for a region (`mbind`) or for everything a
+//! thread allocates (`set_mempolicy`).
+
+use std::{
+    ffi::CString,
+    fs::{File, OpenOptions},
+    io,
+    os::unix::io::{AsRawFd, FromRawFd},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use libc::{
+    MAP_ANONYMOUS, MAP_FAILED, MAP_HUGETLB, MAP_POPULATE, MAP_PRIVATE, MAP_SHARED, PROT_READ,
+    PROT_WRITE,
+};
+
+use crate::{
+    error,
+    output::{Output, Record, Units},
+    topology, Error, Result,
+};
+
+/// The base page size.
+pub const BASE_PAGE_SIZE: usize = 1 << 12;
+
+/// The size of a hugetlb page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HugetlbSize {
+    Size2M,
+    Size1G,
+}
+
+impl HugetlbSize {
+    /// The size in bytes.
+    pub fn bytes(self) -> usize {
+        match self {
+            HugetlbSize::Size2M => 2 << 20,
+            HugetlbSize::Size1G => 1 << 30,
+        }
+    }
+
+    /// The `MAP_HUGE_*`/`MFD_HUGE_*` encoding of the size (log2 of the size, shifted by 26).
+    fn flag(self) -> libc::c_int {
+        (self.bytes().trailing_zeros() as libc::c_int) << 26
+    }
+}
+
+/// How huge pages are used for a region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HugePages {
+    /// Whatever the system's THP settings do.
+    #[default]
+    Default,
+
+    /// Ask for THP with `MADV_HUGEPAGE`.
+    Thp,
+
+    /// Ask for no THP with `MADV_NOHUGEPAGE`.
+    NoThp,
+
+    /// Use hugetlb pages of the given size (`MAP_HUGETLB` or `MFD_HUGETLB`). The region is rounded
+    /// up to a multiple of the page size. With `Backing::File`, the file should be on a hugetlbfs
+    /// mount with that page size.
+    Hugetlb(HugetlbSize),
+}
+
+impl HugePages {
+    /// Parse a huge page policy: `default`, `thp`, `nothp`, `hugetlb:2m` or `hugetlb:1g`.
+    pub fn parse(s: &str) -> std::result::Result<Self, String> {
+        match s {
+            "default" => Ok(HugePages::Default),
+            "thp" => Ok(HugePages::Thp),
+            "nothp" => Ok(HugePages::NoThp),
+            "hugetlb:2m" => Ok(HugePages::Hugetlb(HugetlbSize::Size2M)),
+            "hugetlb:1g" => Ok(HugePages::Hugetlb(HugetlbSize::Size1G)),
+            _ => Err(format!(
+                "Not a valid huge page policy (expected default, thp, nothp, hugetlb:2m or \
+                 hugetlb:1g): {}",
+                s
+            )),
+        }
+    }
+
+    /// The policy given by an optional command line argument (checked with `is_huge_pages`), or
+    /// the default if it was not given.
+    pub fn from_arg(arg: Option<&str>) -> Self {
+        arg.map(|s| HugePages::parse(s).unwrap())
+            .unwrap_or_default()
+    }
+
+    /// The granularity of regions mapped with this policy.
+    pub fn page_size(self) -> usize {
+        match self {
+            HugePages::Hugetlb(size) => size.bytes(),
+            _ => BASE_PAGE_SIZE,
+        }
+    }
+}
+
+/// A clap validator for huge page policies.
+pub fn is_huge_pages(arg: String) -> std::result::Result<(), String> {
+    HugePages::parse(&arg).map(|_| ())
+}
+
+/// What memory backs a region.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Backing {
+    /// Anonymous private memory.
+    #[default]
+    Anonymous,
+
+    /// A shared mapping of a memfd (i.e. shmem).
+    Memfd,
+
+    /// A shared mapping of a file, e.g. on a tmpfs or hugetlbfs mount. If the path is a directory,
+    /// a new file is created in it and unlinked immediately; otherwise, the file is created or
+    /// truncated to the size of the region.
+    File(PathBuf),
+}
+
+impl Backing {
+    /// Parse a backing: `anon`, `memfd` or `file:<path>`.
+    pub fn parse(s: &str) -> std::result::Result<Self, String> {
+        let mut parts = s.splitn(2, ':');
+        match (parts.next().unwrap(), parts.next()) {
+            ("anon", None) => Ok(Backing::Anonymous),
+            ("memfd", None) => Ok(Backing::Memfd),
+            ("file", Some(path)) if !path.is_empty() => Ok(Backing::File(path.into())),
+            _ => Err(format!(
+                "Not a valid backing (expected anon, memfd or file:<path>): {}",
+                s
+            )),
+        }
+    }
+
+    /// The backing given by an optional command line argument (checked with `is_backing`), or the
+    /// default if it was not given.
+    pub fn from_arg(arg: Option<&str>) -> Self {
+        arg.map(|s| Backing::parse(s).unwrap()).unwrap_or_default()
+    }
+}
+
+/// A clap validator for backings.
+pub fn is_backing(arg: String) -> std::result::Result<(), String> {
+    Backing::parse(&arg).map(|_| ())
+}
+
+/// A NUMA memory policy mode (`MPOL_*`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemPolicyMode {
+    /// Allocate on the node of the CPU that faults (`MPOL_DEFAULT`).
+    Default,
+
+    /// Allocate on the given node if possible (`MPOL_PREFERRED`).
+    Preferred,
+
+    /// Only allocate on the given nodes (`MPOL_BIND`).
+    Bind,
+
+    /// Interleave allocations page by page across the given nodes (`MPOL_INTERLEAVE`).
+    Interleave,
+}
+
+impl MemPolicyMode {
+    fn mode(self) -> libc::c_int {
+        match self {
+            MemPolicyMode::Default => 0,
+            MemPolicyMode::Preferred => 1,
+            MemPolicyMode::Bind => 2,
+            MemPolicyMode::Interleave => 3,
+        }
+    }
+}
+
+/// A NUMA memory policy: a mode and a set of nodes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemPolicy {
+    pub mode: MemPolicyMode,
+    pub nodes: Vec<usize>,
+}
+
+impl MemPolicy {
+    /// Parse a policy: `default`, `preferred:<node>`, `bind:<nodes>` or `interleave:<nodes>`,
+    /// where `<nodes>` is a list like `0,2-3`.
+    pub fn parse(s: &str) -> std::result::Result<Self, String> {
+        let mut parts = s.splitn(2, ':');
+        let (mode, nodes) = match (parts.next().unwrap(), parts.next()) {
+            ("default", None) => {
+                return Ok(MemPolicy {
+                    mode: MemPolicyMode::Default,
+                    nodes: vec![],
+                })
+            }
+            ("preferred", Some(nodes)) => (MemPolicyMode::Preferred, nodes),
+            ("bind", Some(nodes)) => (MemPolicyMode::Bind, nodes),
+            ("interleave", Some(nodes)) => (MemPolicyMode::Interleave, nodes),
+            _ => {
+                return Err(format!(
+                    "Not a valid memory policy (expected default, preferred:<node>, \
+                     bind:<nodes> or interleave:<nodes>): {}",
+                    s
+                ))
+            }
+        };
+
+        let nodes = topology::parse_list(nodes)?;
+        if mode == MemPolicyMode::Preferred && nodes.len() != 1 {
+            return Err(format!("Expected exactly one preferred node: {}", s));
+        }
+
+        Ok(MemPolicy { mode, nodes })
+    }
+
+    /// The nodes as a bitmask, one bit per node.
+    fn nodemask(&self) -> Vec<libc::c_ulong> {
+        const BITS: usize = 8 * std::mem::size_of::<libc::c_ulong>();
+
+        let len = self.nodes.iter().max().map_or(0, |&max| max / BITS + 1);
+        let mut mask = vec![0; len];
+        for &node in self.nodes.iter() {
+            mask[node / BITS] |= 1 << (node % BITS);
+        }
+        mask
+    }
+
+    /// Apply the policy to a region with `mbind`. Pages that are already resident are not moved.
+    pub fn apply(&self, region: &Region) -> Result<()> {
+        let mask = self.nodemask();
+
+        // The kernel ignores the last bit of `maxnode`, so we pass one more.
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_mbind,
+                region.addr,
+                region.len,
+                self.mode.mode(),
+                mask.as_ptr(),
+                mask.len() * 8 * std::mem::size_of::<libc::c_ulong>() + 1,
+                0,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::last_syscall("mbind"));
+        }
+
+        Ok(())
+    }
+
+    /// Make the policy the calling thread's default with `set_mempolicy`.
+    pub fn set_for_thread(&self) -> Result<()> {
+        let mask = self.nodemask();
+
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_set_mempolicy,
+                self.mode.mode(),
+                mask.as_ptr(),
+                mask.len() * 8 * std::mem::size_of::<libc::c_ulong>() + 1,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::last_syscall("set_mempolicy"));
+        }
+
+        Ok(())
+    }
+}
+
+/// A clap validator for memory policies.
+pub fn is_mem_policy(arg: String) -> std::result::Result<(), String> {
+    MemPolicy::parse(&arg).map(|_| ())
+}
+
+/// Builds a `Region`.
+#[derive(Debug, Clone)]
+pub struct RegionBuilder {
+    len: usize,
+    huge_pages: HugePages,
+    backing: Backing,
+    populate: bool,
+    mem_policy: Option<MemPolicy>,
+}
+
+impl RegionBuilder {
+    /// A private anonymous region of `len` bytes, using the default huge page behavior.
+    pub fn new(len: usize) -> Self {
+        RegionBuilder {
+            len,
+            huge_pages: HugePages::Default,
+            backing: Backing::Anonymous,
+            populate: false,
+            mem_policy: None,
+        }
+    }
+
+    pub fn huge_pages(mut self, huge_pages: HugePages) -> Self {
+        self.huge_pages = huge_pages;
+        self
+    }
+
+    pub fn backing(mut self, backing: Backing) -> Self {
+        self.backing = backing;
+        self
+    }
+
+    /// Fault in the whole region before returning it.
+    pub fn populate(mut self, populate: bool) -> Self {
+        self.populate = populate;
+        self
+    }
+
+    /// Place the region's memory on NUMA nodes with `mbind`.
+    pub fn mem_policy(mut self, mem_policy: Option<MemPolicy>) -> Self {
+        self.mem_policy = mem_policy;
+        self
+    }
+
+    /// Map the region.
+    pub fn map(self) -> Result<Region> {
+        let page_size = self.huge_pages.page_size();
+        let len = self.len.max(1).next_multiple_of(page_size);
+
+        let hugetlb = match self.huge_pages {
+            HugePages::Hugetlb(size) => Some(size),
+            _ => None,
+        };
+
+        let file = match self.backing {
+            Backing::Anonymous => None,
+            Backing::Memfd => Some(memfd(hugetlb)?),
+            Backing::File(ref path) => Some(backing_file(path)?),
+        };
+
+        if let Some(ref file) = file {
+            if unsafe { libc::ftruncate(file.as_raw_fd(), len as libc::off_t) } != 0 {
+                return Err(Error::last_syscall("ftruncate"));
+            }
+        }
+
+        // With `madvise` or `mbind`, the advice or policy has to be given before the region is
+        // faulted in, so we populate it by hand afterwards.
+        let advice = match self.huge_pages {
+            HugePages::Thp => Some(libc::MADV_HUGEPAGE),
+            HugePages::NoThp => Some(libc::MADV_NOHUGEPAGE),
+            _ => None,
+        };
+        let populate_by_hand = advice.is_some() || self.mem_policy.is_some();
+
+        let mut flags = match file {
+            None => MAP_PRIVATE | MAP_ANONYMOUS,
+            Some(_) => MAP_SHARED,
+        };
+        if let (None, Some(size)) = (&file, hugetlb) {
+            flags |= MAP_HUGETLB | size.flag();
+        }
+        if self.populate && !populate_by_hand {
+            flags |= MAP_POPULATE;
+        }
+
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                PROT_READ | PROT_WRITE,
+                flags,
+                file.as_ref().map(|f| f.as_raw_fd()).unwrap_or(-1),
+                0,
+            )
+        };
+        if addr == MAP_FAILED {
+            return Err(Error::last_syscall("mmap"));
+        }
+
+        let region = Region {
+            addr: addr as *mut u8,
+            len,
+            _file: file,
+        };
+
+        if let Some(advice) = advice {
+            region.advise(0, len, advice)?;
+        }
+
+        if let Some(ref policy) = self.mem_policy {
+            policy.apply(&region)?;
+        }
+
+        if self.populate && populate_by_hand {
+            region.touch(0, len);
+        }
+
+        Ok(region)
+    }
+}
+
+/// Create a memfd, using hugetlb pages of the given size if any.
+fn memfd(hugetlb: Option<HugetlbSize>) -> Result<File> {
+    let name = CString::new("paperexp").unwrap();
+    let flags = match hugetlb {
+        Some(size) => libc::MFD_CLOEXEC | libc::MFD_HUGETLB | size.flag() as libc::c_uint,
+        None => libc::MFD_CLOEXEC,
+    };
+
+    let fd = unsafe { libc::syscall(libc::SYS_memfd_create, name.as_ptr(), flags) };
+    if fd < 0 {
+        return Err(Error::last_syscall("memfd_create"));
+    }
+
+    Ok(unsafe { File::from_raw_fd(fd as libc::c_int) })
+}
+
+/// Open the file backing a region, creating an unlinked file if `path` is a directory.
+fn backing_file(path: &Path) -> Result<File> {
+    let open = |path: &Path| {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .map_err(|err| Error::from_io(path, err))
+    };
+
+    if !path.is_dir() {
+        return open(path);
+    }
+
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+    let path = path.join(format!(
+        "paperexp-region-{}-{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let file = open(&path)?;
+    std::fs::remove_file(&path).map_err(|err| Error::from_io(&path, err))?;
+    Ok(file)
+}
+
+/// A mapped memory region. It is unmapped when dropped.
+#[derive(Debug)]
+pub struct Region {
+    addr: *mut u8,
+    len: usize,
+
+    /// The backing file, if any. Kept open for the lifetime of the mapping.
+    _file: Option<File>,
+}
+
+impl Region {
+    /// The start of the region.
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.addr
+    }
+
+    /// The length of the region in bytes. This may be larger than requested, since regions are
+    /// rounded up to a multiple of their page size.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Write to every base page of `[offset, offset + len)` of the region, faulting it in (again,
+    /// if it was released).
+    pub fn touch(&self, offset: usize, len: usize) {
+        assert!(offset + len <= self.len);
+        for offset in (offset..offset + len).step_by(BASE_PAGE_SIZE) {
+            unsafe {
+                std::ptr::write_volatile(self.addr.add(offset), 0);
+            }
+        }
+    }
+
+    /// Give the kernel `advice` about `[offset, offset + len)` of the region with `madvise`, e.g.
+    /// `MADV_DONTNEED`. `offset` must be a multiple of the region's page size.
+    pub fn advise(&self, offset: usize, len: usize, advice: libc::c_int) -> Result<()> {
+        assert!(offset + len <= self.len);
+        let addr = unsafe { self.addr.add(offset) } as *mut libc::c_void;
+        if unsafe { libc::madvise(addr, len, advice) } != 0 {
+            return Err(Error::last_syscall("madvise"));
+        }
+        Ok(())
+    }
+
+    /// Unmap all but the first `len` bytes of the region, which must be a multiple of its page
+    /// size. A backing file is truncated to match, so that the memory is actually freed.
+    pub fn truncate(&mut self, len: usize) -> Result<()> {
+        if len >= self.len {
+            return Ok(());
+        }
+
+        let tail = unsafe { self.addr.add(len) } as *mut libc::c_void;
+        if unsafe { libc::munmap(tail, self.len - len) } != 0 {
+            return Err(Error::last_syscall("munmap"));
+        }
+        self.len = len;
+
+        if let Some(ref file) = self._file {
+            if unsafe { libc::ftruncate(file.as_raw_fd(), len as libc::off_t) } != 0 {
+                return Err(Error::last_syscall("ftruncate"));
+            }
+        }
+        Ok(())
+    }
+
+    /// How much of the region is resident and how much of that is huge, from `/proc/self/smaps`.
+    pub fn usage(&self) -> Result<RegionUsage> {
+        smaps_usage(self.addr as usize, self.len)
+    }
+}
+
+impl Drop for Region {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.addr as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+/// The memory usage of a range of the address space, in KB, from `/proc/self/smaps`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RegionUsage {
+    /// Resident memory (`Rss`).
+    pub rss_kbs: usize,
+
+    /// Anonymous THP (`AnonHugePages`).
+    pub anon_huge_kbs: usize,
+
+    /// Shmem THP mapped with PMDs (`ShmemPmdMapped`).
+    pub shmem_huge_kbs: usize,
+
+    /// File THP mapped with PMDs (`FilePmdMapped`).
+    pub file_huge_kbs: usize,
+
+    /// Hugetlb pages (`Private_Hugetlb` and `Shared_Hugetlb`).
+    pub hugetlb_kbs: usize,
+}
+
+impl RegionUsage {
+    /// The total amount of memory backed by huge pages of any kind.
+    pub fn huge_kbs(&self) -> usize {
+        self.anon_huge_kbs + self.shmem_huge_kbs + self.file_huge_kbs + self.hugetlb_kbs
+    }
+
+    /// Write the usage of a region of `len` bytes to `out`.
+    pub fn report(&self, out: &mut Output, phase: &str, len: usize) -> io::Result<()> {
+        let record = |metric, kbs: usize| Record::new(phase, metric, kbs).units(Units::Kilobytes);
+
+        out.emit(
+            format_args!(
+                "Huge: {} kB of {} kB resident, {} kB mapped (anon THP {} kB, shmem THP {} kB, \
+                 file THP {} kB, hugetlb {} kB)",
+                self.huge_kbs(),
+                self.rss_kbs,
+                len >> 10,
+                self.anon_huge_kbs,
+                self.shmem_huge_kbs,
+                self.file_huge_kbs,
+                self.hugetlb_kbs
+            ),
+            &[
+                record("mapped", len >> 10),
+                record("rss", self.rss_kbs),
+                record("huge", self.huge_kbs()),
+                record("anon_huge", self.anon_huge_kbs),
+                record("shmem_huge", self.shmem_huge_kbs),
+                record("file_huge", self.file_huge_kbs),
+                record("hugetlb", self.hugetlb_kbs),
+            ],
+        )
+    }
+}
+
+/// The resident set size of this process, in KB, from `/proc/self/smaps_rollup`.
+pub fn rss_kbs() -> Result<usize> {
+    const SMAPS_ROLLUP_PATH: &str = "/proc/self/smaps_rollup";
+
+    // Fields look like "Rss:   1540 kB".
+    let rollup = error::read_to_string(SMAPS_ROLLUP_PATH)?;
+    let rss = rollup
+        .lines()
+        .find_map(|line| line.strip_prefix("Rss:"))
+        .and_then(|rest| rest.split_whitespace().next());
+    error::parse(SMAPS_ROLLUP_PATH, rss)
+}
+
+/// The memory usage of the mappings that overlap `[start, start + len)` in this process.
+pub fn smaps_usage(start: usize, len: usize) -> Result<RegionUsage> {
+    const SMAPS_PATH: &str = "/proc/self/smaps";
+
+    let smaps = error::read_to_string(SMAPS_PATH)?;
+    let end = start + len;
+
+    let mut usage = RegionUsage::default();
+    let mut in_range = false;
+
+    for line in smaps.lines() {
+        let mut fields = line.split_whitespace();
+        let first = fields.next().unwrap_or("");
+
+        // Mapping headers look like "7f1234000000-7f1234200000 rw-p 00000000 00:00 0".
+        if let Some((lo, hi)) = first.split_once('-') {
+            if let (Ok(lo), Ok(hi)) = (usize::from_str_radix(lo, 16), usize::from_str_radix(hi, 16))
+            {
+                in_range = lo < end && start < hi;
+                continue;
+            }
+        }
+
+        if !in_range {
+            continue;
+        }
+
+        // Fields look like "AnonHugePages:      2048 kB".
+        let field = match first {
+            "Rss:" => &mut usage.rss_kbs,
+            "AnonHugePages:" => &mut usage.anon_huge_kbs,
+            "ShmemPmdMapped:" => &mut usage.shmem_huge_kbs,
+            "FilePmdMapped:" => &mut usage.file_huge_kbs,
+            "Private_Hugetlb:" | "Shared_Hugetlb:" => &mut usage.hugetlb_kbs,
+            _ => continue,
+        };
+        *field += error::parse::<usize>(SMAPS_PATH, fields.next())?;
+    }
+
+    Ok(usage)
+}
@@ -0,0 +1,404 @@
+//! A background thread that periodically snapshots kernel and process metrics while a workload
+//! runs.
+//!
+//! A `Sampler` reads a set of `Source`s every interval and writes one timestamped row per sample to
+//! an `Output` (e.g. a file, or a handle shared with the workload's own output). In text mode, each
+//! row is the time since the sampler started (in ns) followed by the value of every metric, under a
+//! `#`-prefixed header naming the columns. The header is repeated whenever the set of columns
+//! changes (e.g. because a source became unavailable). The exception is sampling only
+//! `Compaction`, which keeps the historical `<ops> <undos>` lines of `memcached_and_capture_thp`.
+//!
+//! Sources that cannot be read (e.g. the kernel does not have the instrumentation, or the process
+//! being watched exited) are reported on stderr and dropped, so the sampler never brings down the
+//! workload it is attached to. Likewise, if the output cannot be written, the sampler reports the
+//! error and stops sampling.
+
+use std::{
+    fmt,
+    path::Path,
+    sync::mpsc::{self, RecvTimeoutError, Sender},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    buddyinfo, error,
+    output::{ClockSource, Format, Output, Record, Units, Value},
+    Error, Result,
+};
+
+/// The default order for `Source::Fragmentation`: that of a 2MB huge page.
+pub const DEFAULT_FRAGMENTATION_ORDER: usize = 9;
+
+/// Something to sample.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    /// The 0sim THP compaction instrumentation (`thp_compact_instrumentation`).
+    Compaction,
+
+    /// The amount of memory used for page tables (`/proc/meminfo`).
+    PageTables,
+
+    /// The counters in `/proc/vmstat`, optionally only those with the given prefix.
+    Vmstat { prefix: Option<String> },
+
+    /// The khugepaged counters and per-size THP statistics under
+    /// `/sys/kernel/mm/transparent_hugepage`.
+    Thp,
+
+    /// The number of free blocks of each order in each zone (`/proc/buddyinfo`).
+    Buddyinfo,
+
+    /// The unusable free space and fragmentation indices of each zone for the given order (see
+    /// `buddyinfo`).
+    Fragmentation { order: usize },
+
+    /// The memory usage of a process (`/proc/<pid>/smaps_rollup`); this process if `pid` is `None`.
+    Process { pid: Option<u32> },
+}
+
+impl Source {
+    /// Parse a source: `compaction`, `page_tables`, `vmstat[:<prefix>]`, `thp`, `buddyinfo`,
+    /// `extfrag[:<order>]` or `rss[:<pid>]`.
+    pub fn parse(s: &str) -> std::result::Result<Self, String> {
+        let mut parts = s.splitn(2, ':');
+        match (parts.next().unwrap(), parts.next()) {
+            ("compaction", None) => Ok(Source::Compaction),
+            ("page_tables", None) => Ok(Source::PageTables),
+            ("vmstat", prefix) => Ok(Source::Vmstat {
+                prefix: prefix.map(Into::into),
+            }),
+            ("thp", None) => Ok(Source::Thp),
+            ("buddyinfo", None) => Ok(Source::Buddyinfo),
+            ("extfrag", None) => Ok(Source::Fragmentation {
+                order: DEFAULT_FRAGMENTATION_ORDER,
+            }),
+            ("extfrag", Some(order)) => match order.parse() {
+                Ok(order) => Ok(Source::Fragmentation { order }),
+                Err(_) => Err(format!("Not a valid order: {}", order)),
+            },
+            ("rss", None) => Ok(Source::Process { pid: None }),
+            ("rss", Some(pid)) => match pid.parse() {
+                Ok(pid) => Ok(Source::Process { pid: Some(pid) }),
+                Err(_) => Err(format!("Not a valid pid: {}", pid)),
+            },
+            _ => Err(format!(
+                "Not a valid source (expected compaction, page_tables, vmstat[:<prefix>], thp, \
+                 buddyinfo, extfrag[:<order>] or rss[:<pid>]): {}",
+                s
+            )),
+        }
+    }
+
+    /// Parse a comma-separated list of sources.
+    pub fn parse_list(s: &str) -> std::result::Result<Vec<Self>, String> {
+        s.split(',').map(|s| Source::parse(s.trim())).collect()
+    }
+
+    /// The name of the source, used as the phase of its records.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Source::Compaction => "compaction",
+            Source::PageTables => "page_tables",
+            Source::Vmstat { .. } => "vmstat",
+            Source::Thp => "thp",
+            Source::Buddyinfo => "buddyinfo",
+            Source::Fragmentation { .. } => "extfrag",
+            Source::Process { .. } => "rss",
+        }
+    }
+
+    /// Read the current values of the metrics of this source, as (metric, value, units).
+    pub fn read(&self) -> Result<Vec<(String, Value, Units)>> {
+        match self {
+            Source::Compaction => {
+                let stats = crate::thp_compact_instrumentation()?;
+                Ok(vec![
+                    ("ops".into(), (stats.ops as u64).into(), Units::Count),
+                    ("undos".into(), (stats.undos as u64).into(), Units::Count),
+                ])
+            }
+
+            Source::PageTables => Ok(vec![(
+                "kbs".into(),
+                crate::get_page_table_kbs()?.into(),
+                Units::Kilobytes,
+            )]),
+
+            Source::Vmstat { prefix } => {
+                const VMSTAT_PATH: &str = "/proc/vmstat";
+
+                // Lines look like "nr_free_pages 1234"
+                error::read_to_string(VMSTAT_PATH)?
+                    .lines()
+                    .filter_map(|line| {
+                        let mut fields = line.split_whitespace();
+                        let name = fields.next()?;
+                        match prefix {
+                            Some(prefix) if !name.starts_with(prefix.as_str()) => None,
+                            _ => Some((name, fields.next())),
+                        }
+                    })
+                    .map(|(name, value)| {
+                        Ok((
+                            name.into(),
+                            error::parse::<u64>(VMSTAT_PATH, value)?.into(),
+                            Units::Count,
+                        ))
+                    })
+                    .collect()
+            }
+
+            Source::Thp => {
+                const THP_PATH: &str = "/sys/kernel/mm/transparent_hugepage";
+
+                let mut values = vec![];
+                for counter in &["full_scans", "pages_collapsed"] {
+                    let path = format!("{}/khugepaged/{}", THP_PATH, counter);
+                    let value = error::read_to_string(&path)?;
+                    values.push((
+                        format!("khugepaged.{}", counter),
+                        error::parse::<u64>(&path, Some(value.trim()))?.into(),
+                        Units::Count,
+                    ));
+                }
+
+                // Per-size statistics only exist on newer kernels.
+                let mut sizes = read_dir_names(THP_PATH)?;
+                sizes.retain(|name| name.starts_with("hugepages-"));
+                for size in sizes {
+                    let dir = format!("{}/{}/stats", THP_PATH, size);
+                    let stats = match read_dir_names(&dir) {
+                        Ok(stats) => stats,
+                        Err(e) if e.is_missing() => continue,
+                        Err(e) => return Err(e),
+                    };
+                    for stat in stats {
+                        let path = format!("{}/{}", dir, stat);
+                        let value = error::read_to_string(&path)?;
+                        values.push((
+                            format!("{}.{}", size, stat),
+                            error::parse::<u64>(&path, Some(value.trim()))?.into(),
+                            Units::Count,
+                        ));
+                    }
+                }
+
+                Ok(values)
+            }
+
+            Source::Buddyinfo => Ok(buddyinfo::buddyinfo()?
+                .into_iter()
+                .flat_map(|zone| {
+                    let prefix = format!("node{}.{}", zone.node, zone.zone);
+                    zone.free
+                        .into_iter()
+                        .enumerate()
+                        .map(move |(order, count)| {
+                            (
+                                format!("{}.order{}", prefix, order),
+                                count.into(),
+                                Units::Count,
+                            )
+                        })
+                })
+                .collect()),
+
+            Source::Fragmentation { order } => {
+                let mut values = vec![];
+                for zone in buddyinfo::buddyinfo()? {
+                    let prefix = format!("node{}.{}", zone.node, zone.zone);
+                    values.push((
+                        format!("{}.unusable_index", prefix),
+                        zone.unusable_index(*order).into(),
+                        Units::Count,
+                    ));
+                    values.push((
+                        format!("{}.fragmentation_index", prefix),
+                        zone.fragmentation_index(*order).into(),
+                        Units::Count,
+                    ));
+                }
+
+                Ok(values)
+            }
+
+            Source::Process { pid } => {
+                let path = match pid {
+                    Some(pid) => format!("/proc/{}/smaps_rollup", pid),
+                    None => "/proc/self/smaps_rollup".into(),
+                };
+
+                // The first line is the address range; the rest look like "Rss:   1540 kB".
+                error::read_to_string(&path)?
+                    .lines()
+                    .skip(1)
+                    .map(|line| {
+                        let mut fields = line.split_whitespace();
+                        let name = fields.next().unwrap_or("").trim_end_matches(':');
+                        Ok((
+                            name.into(),
+                            error::parse::<u64>(&path, fields.next())?.into(),
+                            Units::Kilobytes,
+                        ))
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Vmstat {
+                prefix: Some(prefix),
+            } => write!(f, "vmstat:{}", prefix),
+            Source::Fragmentation { order } => write!(f, "extfrag:{}", order),
+            Source::Process { pid: Some(pid) } => write!(f, "rss:{}", pid),
+            _ => write!(f, "{}", self.name()),
+        }
+    }
+}
+
+/// A clap validator for comma-separated lists of sources.
+pub fn is_sources(arg: String) -> std::result::Result<(), String> {
+    Source::parse_list(&arg).map(|_| ())
+}
+
+/// The names of the entries of a directory, sorted.
+fn read_dir_names(path: impl AsRef<Path>) -> Result<Vec<String>> {
+    let path = path.as_ref();
+    let mut names = std::fs::read_dir(path)
+        .and_then(|entries| {
+            entries
+                .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+                .collect::<std::io::Result<Vec<_>>>()
+        })
+        .map_err(|err| Error::from_io(path, err))?;
+    names.sort();
+    Ok(names)
+}
+
+/// Samples a set of `Source`s on a background thread until stopped.
+pub struct Sampler {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Sampler {
+    /// Start sampling `sources` every `interval`, writing rows to `out`. The first sample is taken
+    /// immediately.
+    pub fn start(sources: Vec<Source>, interval: Duration, mut out: Output) -> Self {
+        let (stop, stopped) = mpsc::channel();
+
+        let thread = std::thread::spawn(move || {
+            let start = Instant::now();
+            let mut sources = sources;
+            let mut header = String::new();
+
+            let mut iteration = 0;
+
+            loop {
+                let timestamp = start.elapsed().as_nanos() as u64;
+                if let Err(e) = sample(&mut sources, &mut out, &mut header, iteration, timestamp) {
+                    eprintln!("sampler: unable to write sample, stopping: {}", e);
+                    return;
+                }
+                iteration += 1;
+
+                match stopped.recv_timeout(interval) {
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Ok(()) | Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+
+            // One last sample at the end of the workload.
+            let timestamp = start.elapsed().as_nanos() as u64;
+            if let Err(e) = sample(&mut sources, &mut out, &mut header, iteration, timestamp) {
+                eprintln!("sampler: unable to write sample: {}", e);
+            }
+        });
+
+        Sampler {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+
+    /// Take a final sample and stop the sampler.
+    pub fn stop(self) {
+        // Done by `drop`.
+    }
+}
+
+impl Drop for Sampler {
+    fn drop(&mut self) {
+        // Closing the channel stops the thread, if it has not already stopped by itself.
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                eprintln!("sampler: sampling thread panicked");
+            }
+        }
+    }
+}
+
+/// Take one sample of `sources` and write it to `out`, dropping any sources that fail. `header` is
+/// the text-mode header most recently written.
+fn sample(
+    sources: &mut Vec<Source>,
+    out: &mut Output,
+    header: &mut String,
+    iteration: usize,
+    timestamp: u64,
+) -> std::io::Result<()> {
+    let mut values = vec![];
+    sources.retain(|source| match source.read() {
+        Ok(metrics) => {
+            values.extend(
+                metrics
+                    .into_iter()
+                    .map(|(metric, value, units)| (source.name(), metric, value, units)),
+            );
+            true
+        }
+        Err(e) => {
+            eprintln!("sampler: dropping source `{}`: {}", source, e);
+            false
+        }
+    });
+
+    if out.format() == Format::Text && sources.as_slice() == [Source::Compaction] {
+        out.text(format_args!("{} {}", values[0].2, values[1].2))?;
+    } else if out.format() == Format::Text {
+        let mut columns = String::from("# time_ns");
+        let mut row = timestamp.to_string();
+        for (source, metric, value, _) in &values {
+            columns += &format!(" {}.{}", source, metric);
+            row += &format!(" {}", value);
+        }
+
+        if columns != *header {
+            out.text(format_args!("{}", columns))?;
+            *header = columns;
+        }
+        out.text(format_args!("{}", row))?;
+    } else {
+        out.record(
+            &Record::new("sampler", "timestamp", timestamp)
+                .iteration(iteration)
+                .clock(ClockSource::Monotonic)
+                .units(Units::Nanoseconds),
+        )?;
+        for (source, metric, value, units) in &values {
+            out.record(
+                &Record::new(source, metric, *value)
+                    .iteration(iteration)
+                    .units(*units),
+            )?;
+        }
+    }
+
+    out.flush()
+}
@@ -0,0 +1,415 @@
+//! Declarative multi-phase workloads for key-value stores.
+//!
+//! A script is a sequence of steps, one per line. Blank lines and everything after a `#` are
+//! ignored. Each step starts with an operation, followed by optional `key=value` settings:
+//!
+//! ```text
+//! <op> [name=<name>] [keys=<lo>..<hi>] [dist=<key distribution>]
+//!      [count=<n> | duration=<secs>s] [pause=<secs>s]
+//! ```
+//!
+//! - `op` is one of `insert`, `update` (both `put`), `get` or `delete`.
+//! - `name` names the phase in the output (default: the op).
+//! - `keys` is the range of key indices to operate on (default: `0..N`). It must not be empty.
+//! - `dist` chooses keys within the range, in the syntax of `KeyDistribution::parse` (default:
+//!   `sequential`).
+//! - `count` is the number of operations, or `duration` is how long to run for (default: one
+//!   operation per key in the range).
+//! - `pause` is how long to sleep after the step (default: none).
+//!
+//! Key indices and counts are sums of terms, where each term is an integer or `[a*]N[/b]`, and `N`
+//! is the number of values needed to fill the store with the requested amount of data. For
+//! example, the following is the default workload of `memcached_and_capture_thp`:
+//!
+//! ```text
+//! insert keys=0..N
+//! delete keys=0..N count=N/3
+//! insert name=reinsert keys=N..N+N/2
+//! ```
+
+use std::{path::Path, time::Duration, time::Instant};
+
+use crate::{
+    keys::{KeyChooser, KeyDistribution},
+    kv::{with_retries, KvBackend},
+    output::{ClockSource, Format, Output, Record, Units},
+    payload::PayloadGenerator,
+    stats::{Summary, Trim},
+    timer::rdtsc,
+    Error, Result,
+};
+
+/// A term of an `Expr`: `mul * N / div`, or a constant if `n` is false.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Term {
+    n: bool,
+    mul: usize,
+    div: usize,
+}
+
+/// A sum of terms involving `N`, e.g. `N+N/2`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expr(Vec<Term>);
+
+impl Expr {
+    /// Parse an expression.
+    pub fn parse(s: &str) -> std::result::Result<Self, String> {
+        let term = |t: &str| -> std::result::Result<Term, String> {
+            let err = || {
+                format!(
+                    "Not a valid term (expected <int> or [<int>*]N[/<int>]): {}",
+                    t
+                )
+            };
+            let int = |s: &str| s.trim().parse::<usize>().map_err(|_| err());
+
+            let t = t.trim();
+            if !t.contains('N') {
+                return Ok(Term {
+                    n: false,
+                    mul: int(t)?,
+                    div: 1,
+                });
+            }
+
+            let (mul, rest) = match t.find('*') {
+                Some(i) => (int(&t[..i])?, &t[i + 1..]),
+                None => (1, t),
+            };
+            let (n, div) = match rest.find('/') {
+                Some(i) => (&rest[..i], int(&rest[i + 1..])?),
+                None => (rest, 1),
+            };
+            if n.trim() != "N" || div == 0 {
+                return Err(err());
+            }
+
+            Ok(Term { n: true, mul, div })
+        };
+
+        s.split('+')
+            .map(term)
+            .collect::<std::result::Result<_, _>>()
+            .map(Expr)
+    }
+
+    /// A constant expression.
+    pub fn constant(c: usize) -> Self {
+        Expr(vec![Term {
+            n: false,
+            mul: c,
+            div: 1,
+        }])
+    }
+
+    /// The expression `N`.
+    pub fn n() -> Self {
+        Expr(vec![Term {
+            n: true,
+            mul: 1,
+            div: 1,
+        }])
+    }
+
+    /// Does the expression not involve `N`?
+    pub fn is_constant(&self) -> bool {
+        self.0.iter().all(|t| !t.n)
+    }
+
+    /// Evaluate the expression with the given value of `N`. Division rounds down.
+    pub fn eval(&self, n: usize) -> usize {
+        self.0
+            .iter()
+            .map(|t| if t.n { t.mul * n / t.div } else { t.mul })
+            .sum()
+    }
+}
+
+/// The operation done by a `Step`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepOp {
+    Insert,
+    Update,
+    Get,
+    Delete,
+}
+
+impl StepOp {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            StepOp::Insert => "insert",
+            StepOp::Update => "update",
+            StepOp::Get => "get",
+            StepOp::Delete => "delete",
+        }
+    }
+}
+
+/// How long a `Step` runs.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Amount {
+    /// One operation per key in the range.
+    Keys,
+
+    /// A fixed number of operations.
+    Count(Expr),
+
+    /// As many operations as fit in the given time.
+    Duration(Duration),
+}
+
+/// One phase of a workload.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step {
+    pub name: String,
+    pub op: StepOp,
+    pub keys: (Expr, Expr),
+    pub dist: KeyDistribution,
+    pub amount: Amount,
+    pub pause: Duration,
+}
+
+impl Step {
+    /// A step doing `op` once on every key in `[0, N)` in order.
+    pub fn new(op: StepOp) -> Self {
+        Step {
+            name: op.as_str().to_owned(),
+            op,
+            keys: (Expr::constant(0), Expr::n()),
+            dist: KeyDistribution::Sequential,
+            amount: Amount::Keys,
+            pause: Duration::from_secs(0),
+        }
+    }
+
+    /// Parse a single (non-empty) line of a script.
+    fn parse(line: &str) -> std::result::Result<Self, String> {
+        let mut words = line.split_whitespace();
+
+        let op = match words.next().unwrap() {
+            "insert" => StepOp::Insert,
+            "update" => StepOp::Update,
+            "get" => StepOp::Get,
+            "delete" => StepOp::Delete,
+            other => {
+                return Err(format!(
+                    "Not a valid op (insert, update, get, delete): {}",
+                    other
+                ))
+            }
+        };
+
+        let mut step = Step::new(op);
+
+        let secs = |v: &str| -> std::result::Result<Duration, String> {
+            v.strip_suffix('s')
+                .and_then(|v| v.parse::<f64>().ok())
+                .filter(|v| *v >= 0.0)
+                .map(Duration::from_secs_f64)
+                .ok_or_else(|| format!("Not a valid duration (expected e.g. `10s`): {}", v))
+        };
+
+        for word in words {
+            let mut kv = word.splitn(2, '=');
+            let (key, value) = match (kv.next(), kv.next()) {
+                (Some(key), Some(value)) => (key, value),
+                _ => return Err(format!("Expected <setting>=<value>: {}", word)),
+            };
+
+            match key {
+                "name" => step.name = value.to_owned(),
+                "keys" => {
+                    let mut range = value.splitn(2, "..");
+                    match (range.next(), range.next()) {
+                        (Some(lo), Some(hi)) => {
+                            let (lo, hi) = (Expr::parse(lo)?, Expr::parse(hi)?);
+                            // Ranges involving `N` are checked by `Script::check`.
+                            if lo.is_constant() && hi.is_constant() && lo.eval(0) >= hi.eval(0) {
+                                return Err(format!("Empty key range: {}", value));
+                            }
+                            step.keys = (lo, hi);
+                        }
+                        _ => return Err(format!("Expected <lo>..<hi>: {}", value)),
+                    }
+                }
+                "dist" => step.dist = KeyDistribution::parse(value)?,
+                "count" => step.amount = Amount::Count(Expr::parse(value)?),
+                "duration" => step.amount = Amount::Duration(secs(value)?),
+                "pause" => step.pause = secs(value)?,
+                _ => return Err(format!("Unknown setting: {}", key)),
+            }
+        }
+
+        Ok(step)
+    }
+}
+
+/// A sequence of `Step`s.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Script {
+    pub steps: Vec<Step>,
+
+    /// Print a summary of each phase in text mode. Parsed scripts do, while built-in workloads
+    /// keep their original output. The summaries are always recorded in the other formats.
+    pub text_summaries: bool,
+
+    /// If `Some`, also summarize the latencies of each phase, with outliers trimmed.
+    pub summary: Option<Trim>,
+}
+
+impl Script {
+    /// Parse a script.
+    pub fn parse(src: &str) -> std::result::Result<Self, String> {
+        let mut steps = vec![];
+
+        for (i, line) in src.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            steps.push(Step::parse(line).map_err(|e| format!("line {}: {}", i + 1, e))?);
+        }
+
+        Ok(Script {
+            steps,
+            text_summaries: true,
+            summary: None,
+        })
+    }
+
+    /// Read and parse the script at `path`.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let src = std::fs::read_to_string(path).map_err(|err| Error::from_io(path, err))?;
+        Self::parse(&src).map_err(|reason| Error::malformed(path, reason))
+    }
+
+    /// Check that the key range of every step is non-empty with `n` as the value of `N`.
+    pub fn check(&self, n: usize) -> std::result::Result<(), String> {
+        for step in self.steps.iter() {
+            let (lo, hi) = (step.keys.0.eval(n), step.keys.1.eval(n));
+            if lo >= hi {
+                return Err(format!(
+                    "{}: empty key range {}..{} with N = {}",
+                    step.name, lo, hi, n
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Run each step in order against `backend`, with `n` as the value of `N`. Failed operations
+    /// are retried once. The latency of each operation is written to `latency`, and a summary of
+    /// each phase to `out`. In text mode, phases are separated by `NEXT!` lines.
+    ///
+    /// Panics if `check(n)` fails.
+    pub fn run<B: KvBackend>(
+        &self,
+        backend: &mut B,
+        payload: &mut PayloadGenerator,
+        n: usize,
+        seed: u64,
+        out: &mut Output,
+        latency: &mut Output,
+    ) -> std::result::Result<(), B::Error> {
+        if let Err(e) = self.check(n) {
+            panic!("invalid script: {}", e);
+        }
+
+        for (i, step) in self.steps.iter().enumerate() {
+            if i > 0 {
+                out.text(format_args!("NEXT!")).unwrap();
+                latency.text(format_args!("NEXT!")).unwrap();
+            }
+
+            let lo = step.keys.0.eval(n);
+            let hi = step.keys.1.eval(n);
+            let mut keys = KeyChooser::new(step.dist.clone(), seed.wrapping_add(i as u64));
+
+            let count = match step.amount {
+                Amount::Keys => Some(hi - lo),
+                Amount::Count(ref count) => Some(count.eval(n)),
+                Amount::Duration(_) => None,
+            };
+            let deadline = match step.amount {
+                Amount::Duration(duration) => Some(Instant::now() + duration),
+                _ => None,
+            };
+
+            let start_time = rdtsc();
+            let mut done = 0;
+            let mut latencies = vec![];
+
+            while count.map(|count| done < count).unwrap_or(true)
+                && deadline.map(|d| Instant::now() < d).unwrap_or(true)
+            {
+                let idx = lo + keys.next(hi - lo);
+                let key = format!("{}", idx);
+
+                let start = rdtsc();
+
+                match step.op {
+                    StepOp::Insert | StepOp::Update => {
+                        let val = payload.next(idx);
+                        with_retries(backend, Some(1), |backend| backend.put(&key, val))?;
+                    }
+                    StepOp::Get => {
+                        with_retries(backend, Some(1), |backend| backend.get(&key))?;
+                    }
+                    StepOp::Delete => {
+                        with_retries(backend, Some(1), |backend| backend.delete(&key))?;
+                    }
+                }
+
+                let cycles = rdtsc() - start;
+                if self.summary.is_some() {
+                    latencies.push(cycles as f64);
+                }
+                latency
+                    .emit(
+                        format_args!("{}", cycles),
+                        &[Record::new(&step.name, "latency", cycles)
+                            .iteration(done)
+                            .clock(ClockSource::Rdtsc)
+                            .units(Units::Cycles)],
+                    )
+                    .unwrap();
+
+                done += 1;
+            }
+
+            let elapsed = rdtsc() - start_time;
+            let records = [
+                Record::new(&step.name, "ops", done).iteration(i),
+                Record::new(&step.name, "elapsed", elapsed)
+                    .iteration(i)
+                    .clock(ClockSource::Rdtsc)
+                    .units(Units::Cycles),
+            ];
+            if self.text_summaries {
+                out.emit(
+                    format_args!("{}: {} ops in {} cycles", step.name, done, elapsed),
+                    &records,
+                )
+                .unwrap();
+            } else if out.format() != Format::Text {
+                for record in records.iter() {
+                    out.record(record).unwrap();
+                }
+            }
+            if let Some(trim) = self.summary {
+                Summary::new(latencies, trim)
+                    .report(out, &step.name, ClockSource::Rdtsc, Units::Cycles)
+                    .unwrap();
+            }
+
+            std::thread::sleep(step.pause);
+        }
+
+        out.text(format_args!("DONE!")).unwrap();
+
+        Ok(())
+    }
+}
@@ -0,0 +1,678 @@
+//! Statistics over measurements.
+//!
+//! - `Histogram` records values in constant time and memory, for summarizing in the timed loop.
+//! - `Welford` computes the mean and variance of a stream of values.
+//! - `Summary` summarizes a set of measurements after the fact: exact quantiles, a confidence
+//!   interval for the mean and, optionally, with outliers trimmed first (`Trim`).
+
+use std::io;
+
+use bmk_linux::timing::MemoizedTimingData;
+
+use crate::output::{ClockSource, Output, Record, Units, Value};
+
+/// The percentiles reported by `Histogram::report`, as (name, metric, quantile). This is the same
+/// set that `time_sleep_test` reports.
+pub const REPORTED_PERCENTILES: &[(&str, &str, f64)] = &[
+    ("50%", "p50", 0.5),
+    ("75%", "p75", 0.75),
+    ("90%", "p90", 0.9),
+    ("99%", "p99", 0.99),
+    ("99.9%", "p99.9", 0.999),
+    ("99.99%", "p99.99", 0.9999),
+    ("99.999%", "p99.999", 0.99999),
+    ("99.9999%", "p99.9999", 0.999999),
+];
+
+/// An HDR-style histogram of `u64` values with bounded relative error.
+///
+/// Values are grouped into buckets whose width grows with the magnitude of the value: every power
+/// of two is split into `2^(precision - 1)` equal sub-buckets, so the relative error of any
+/// reported value is at most `2^-(precision - 1)`. Values below `2^precision` are exact.
+///
+/// Recording is a constant-time array increment, so it is cheap enough to do for every operation.
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    /// Number of bits of precision.
+    precision: u32,
+
+    counts: Vec<u64>,
+
+    count: u64,
+    min: u64,
+    max: u64,
+
+    /// Sum and sum of squares (for exact mean and standard deviation).
+    sum: u128,
+    sum_sq: f64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Histogram {
+    /// The default precision: 8 bits, or less than 1% relative error.
+    pub const DEFAULT_PRECISION: u32 = 8;
+
+    /// A histogram with the default precision.
+    pub fn new() -> Self {
+        Self::with_precision(Self::DEFAULT_PRECISION)
+    }
+
+    /// A histogram with the given number of bits of precision.
+    ///
+    /// # Panics
+    ///
+    /// If `precision` is not in `1..=16`.
+    pub fn with_precision(precision: u32) -> Self {
+        assert!((1..=16).contains(&precision));
+
+        let half = 1usize << (precision - 1);
+        let nbuckets = (64 - precision as usize + 2) * half;
+
+        Histogram {
+            precision,
+            counts: vec![0; nbuckets],
+            count: 0,
+            min: u64::MAX,
+            max: 0,
+            sum: 0,
+            sum_sq: 0.0,
+        }
+    }
+
+    /// The index of the bucket containing `value`.
+    fn index(&self, value: u64) -> usize {
+        if value < (1 << self.precision) {
+            return value as usize;
+        }
+
+        let msb = 63 - value.leading_zeros();
+        let shift = msb - self.precision + 1;
+        let half = 1usize << (self.precision - 1);
+
+        shift as usize * half + (value >> shift) as usize
+    }
+
+    /// The largest value that falls in the bucket with the given index.
+    fn highest_in_bucket(&self, index: usize) -> u64 {
+        if index < (1 << self.precision) {
+            return index as u64;
+        }
+
+        let half = 1usize << (self.precision - 1);
+        let shift = index / half - 1;
+        let mantissa = (index % half + half) as u64;
+
+        ((mantissa + 1) << shift).wrapping_sub(1)
+    }
+
+    /// Record a value.
+    pub fn record(&mut self, value: u64) {
+        self.record_n(value, 1);
+    }
+
+    /// Record `n` occurrences of a value.
+    pub fn record_n(&mut self, value: u64, n: u64) {
+        let index = self.index(value);
+        self.counts[index] += n;
+        self.count += n;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value as u128 * n as u128;
+        self.sum_sq += (value as f64).powi(2) * n as f64;
+    }
+
+    /// Add all values from `other` to this histogram.
+    ///
+    /// # Panics
+    ///
+    /// If the histograms have different precisions.
+    pub fn merge(&mut self, other: &Histogram) {
+        assert_eq!(self.precision, other.precision);
+
+        for (mine, theirs) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *mine += theirs;
+        }
+        self.count += other.count;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.sum += other.sum;
+        self.sum_sq += other.sum_sq;
+    }
+
+    /// The number of recorded values.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// The smallest recorded value (exact), or 0 if empty.
+    pub fn min(&self) -> u64 {
+        if self.count == 0 {
+            0
+        } else {
+            self.min
+        }
+    }
+
+    /// The largest recorded value (exact).
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    /// The mean of the recorded values (exact), or NaN if empty.
+    pub fn mean(&self) -> f64 {
+        self.sum as f64 / self.count as f64
+    }
+
+    /// The population standard deviation of the recorded values, or NaN if empty.
+    pub fn sd(&self) -> f64 {
+        let mean = self.mean();
+        (self.sum_sq / self.count as f64 - mean * mean)
+            .max(0.0)
+            .sqrt()
+    }
+
+    /// The value at quantile `q` (in `[0, 1]`), i.e. the smallest bucket value such that at least
+    /// a fraction `q` of recorded values are less than or equal to it. Returns 0 if empty.
+    pub fn value_at_quantile(&self, q: f64) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+
+        let q = q.clamp(0.0, 1.0);
+        let target = ((q * self.count as f64).ceil() as u64).max(1);
+
+        let mut seen = 0;
+        for (index, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                return self.highest_in_bucket(index).clamp(self.min, self.max);
+            }
+        }
+
+        self.max
+    }
+
+    /// Write the mean, standard deviation, `REPORTED_PERCENTILES` and max of the histogram to
+    /// `out`, in the style of `time_sleep_test`. In text mode, the report is preceded by a line
+    /// naming the phase.
+    pub fn report(
+        &self,
+        out: &mut Output,
+        phase: &str,
+        clock: ClockSource,
+        units: Units,
+    ) -> io::Result<()> {
+        let record =
+            |metric, value: Value| Record::new(phase, metric, value).clock(clock).units(units);
+
+        let avg = self.mean();
+        let sd = self.sd();
+
+        out.text(format_args!("{} ({}):", phase, units.as_str()))?;
+        out.emit(
+            format_args!("samples: {}", self.count),
+            &[Record::new(phase, "samples", self.count)],
+        )?;
+        out.emit(format_args!("avg: {}", avg), &[record("avg", avg.into())])?;
+        out.emit(
+            format_args!("sd: {} ({}%)", sd, sd / avg * 100.),
+            &[
+                record("sd", sd.into()),
+                Record::new(phase, "sd_relative", sd / avg * 100.).units(Units::Percent),
+            ],
+        )?;
+
+        for &(name, metric, q) in REPORTED_PERCENTILES {
+            let value = self.value_at_quantile(q);
+            out.emit(
+                format_args!("{}: {}", name, value),
+                &[record(metric, value.into())],
+            )?;
+        }
+
+        let max = self.max();
+        out.emit(format_args!("max: {}", max), &[record("max", max.into())])
+    }
+}
+
+/// Write the same report as `Histogram::report`, but computed exactly from all of the measurements
+/// (with `MemoizedTimingData`). Nothing but the header and the sample count is written if there are
+/// no measurements.
+pub fn report_measurements(
+    out: &mut Output,
+    phase: &str,
+    clock: ClockSource,
+    units: Units,
+    measurements: &[u64],
+) -> io::Result<()> {
+    let record = |metric, value: f64| Record::new(phase, metric, value).clock(clock).units(units);
+
+    out.text(format_args!("{} ({}):", phase, units.as_str()))?;
+    out.emit(
+        format_args!("samples: {}", measurements.len()),
+        &[Record::new(phase, "samples", measurements.len())],
+    )?;
+    if measurements.is_empty() {
+        return Ok(());
+    }
+
+    let mut md = MemoizedTimingData::new();
+
+    let avg = md.avg(measurements);
+    let sd = md.sd(measurements);
+    out.emit(format_args!("avg: {}", avg), &[record("avg", avg)])?;
+    out.emit(
+        format_args!("sd: {} ({}%)", sd, sd / avg * 100.),
+        &[
+            record("sd", sd),
+            Record::new(phase, "sd_relative", sd / avg * 100.).units(Units::Percent),
+        ],
+    )?;
+
+    for &(name, metric, q) in REPORTED_PERCENTILES {
+        // `MemoizedTimingData` takes whole percentiles, or permicrotiles above the 99th percentile.
+        let value = if q <= 0.99 {
+            md.percentile(measurements, (q * 100.).round() as usize)
+        } else {
+            md.permicrotile(measurements, (q * 1_000_000.).round() as usize)
+        };
+        out.emit(
+            format_args!("{}: {}", name, value),
+            &[record(metric, value)],
+        )?;
+    }
+
+    let max = md.max(measurements);
+    out.emit(format_args!("max: {}", max), &[record("max", max)])
+}
+
+/// Streaming mean and variance with Welford's algorithm, which does not suffer from the
+/// cancellation of the naive sum-of-squares method.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Welford {
+    count: u64,
+    mean: f64,
+
+    /// The sum of squared differences from the mean.
+    m2: f64,
+}
+
+impl Welford {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a value.
+    pub fn record(&mut self, value: f64) {
+        self.count += 1;
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (value - self.mean);
+    }
+
+    /// Add all values from `other` (Chan et al.'s parallel algorithm).
+    pub fn merge(&mut self, other: &Welford) {
+        if other.count == 0 {
+            return;
+        }
+
+        let count = self.count + other.count;
+        let delta = other.mean - self.mean;
+        self.mean += delta * other.count as f64 / count as f64;
+        self.m2 += other.m2 + delta * delta * self.count as f64 * other.count as f64 / count as f64;
+        self.count = count;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// The mean, or NaN if empty.
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            f64::NAN
+        } else {
+            self.mean
+        }
+    }
+
+    /// The sample variance, or NaN with fewer than two values.
+    pub fn variance(&self) -> f64 {
+        if self.count < 2 {
+            f64::NAN
+        } else {
+            self.m2 / (self.count - 1) as f64
+        }
+    }
+
+    /// The sample standard deviation, or NaN with fewer than two values.
+    pub fn sd(&self) -> f64 {
+        self.variance().sqrt()
+    }
+
+    /// A two-sided confidence interval for the mean at the given level (e.g. 0.95), using the
+    /// normal approximation, which is reasonable for more than a few dozen values.
+    pub fn confidence_interval(&self, level: f64) -> (f64, f64) {
+        let half = z_score(level) * self.sd() / (self.count as f64).sqrt();
+        (self.mean() - half, self.mean() + half)
+    }
+}
+
+/// A streaming least-squares fit of `y = intercept + slope * x`, updated like `Welford`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LinearFit {
+    count: u64,
+    mean_x: f64,
+    mean_y: f64,
+
+    /// The sums of squared differences from the means, and of their products.
+    m2_x: f64,
+    m2_y: f64,
+    c_xy: f64,
+}
+
+impl LinearFit {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a point.
+    pub fn record(&mut self, x: f64, y: f64) {
+        self.count += 1;
+        let dx = x - self.mean_x;
+        let dy = y - self.mean_y;
+        self.mean_x += dx / self.count as f64;
+        self.mean_y += dy / self.count as f64;
+        self.m2_x += dx * (x - self.mean_x);
+        self.m2_y += dy * (y - self.mean_y);
+        self.c_xy += dx * (y - self.mean_y);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// The slope, or NaN with fewer than two distinct `x`s.
+    pub fn slope(&self) -> f64 {
+        if self.m2_x == 0. {
+            f64::NAN
+        } else {
+            self.c_xy / self.m2_x
+        }
+    }
+
+    /// The value of the fit at `x = 0`.
+    pub fn intercept(&self) -> f64 {
+        self.mean_y - self.slope() * self.mean_x
+    }
+
+    /// The standard deviation of the residuals, or NaN with fewer than three points.
+    pub fn residual_sd(&self) -> f64 {
+        if self.count < 3 {
+            f64::NAN
+        } else {
+            let sse = self.m2_y - self.slope() * self.c_xy;
+            (sse.max(0.) / (self.count - 2) as f64).sqrt()
+        }
+    }
+}
+
+/// The `z` such that a standard normal variable falls in `[-z, z]` with probability `level`.
+///
+/// # Panics
+///
+/// If `level` is not in `(0, 1)`.
+pub fn z_score(level: f64) -> f64 {
+    assert!(level > 0.0 && level < 1.0);
+    normal_quantile(0.5 + level / 2.0)
+}
+
+/// The inverse of the standard normal CDF, with Acklam's rational approximation (relative error
+/// below 1.2e-9).
+fn normal_quantile(p: f64) -> f64 {
+    const A: [f64; 6] = [
+        -3.969_683_028_665_376e1,
+        2.209_460_984_245_205e2,
+        -2.759_285_104_469_687e2,
+        1.383_577_518_672_69e2,
+        -3.066_479_806_614_716e1,
+        2.506_628_277_459_239,
+    ];
+    const B: [f64; 5] = [
+        -5.447_609_879_822_406e1,
+        1.615_858_368_580_409e2,
+        -1.556_989_798_598_866e2,
+        6.680_131_188_771_972e1,
+        -1.328_068_155_288_572e1,
+    ];
+    const C: [f64; 6] = [
+        -7.784_894_002_430_293e-3,
+        -3.223_964_580_411_365e-1,
+        -2.400_758_277_161_838,
+        -2.549_732_539_343_734,
+        4.374_664_141_464_968,
+        2.938_163_982_698_783,
+    ];
+    const D: [f64; 4] = [
+        7.784_695_709_041_462e-3,
+        3.224_671_290_700_398e-1,
+        2.445_134_137_142_996,
+        3.754_408_661_907_416,
+    ];
+    const LOW: f64 = 0.02425;
+
+    let tail = |q: f64| {
+        (((((C[0] * q + C[1]) * q + C[2]) * q + C[3]) * q + C[4]) * q + C[5])
+            / ((((D[0] * q + D[1]) * q + D[2]) * q + D[3]) * q + 1.0)
+    };
+
+    if p < LOW {
+        tail((-2.0 * p.ln()).sqrt())
+    } else if p > 1.0 - LOW {
+        -tail((-2.0 * (1.0 - p).ln()).sqrt())
+    } else {
+        let q = p - 0.5;
+        let r = q * q;
+        (((((A[0] * r + A[1]) * r + A[2]) * r + A[3]) * r + A[4]) * r + A[5]) * q
+            / (((((B[0] * r + B[1]) * r + B[2]) * r + B[3]) * r + B[4]) * r + 1.0)
+    }
+}
+
+/// The value at quantile `q` (in `[0, 1]`) of sorted values, by the nearest-rank method (the same
+/// definition as `Histogram::value_at_quantile`). Returns NaN if there are no values.
+pub fn quantile(sorted: &[f64], q: f64) -> f64 {
+    if sorted.is_empty() {
+        return f64::NAN;
+    }
+
+    let rank = (q.clamp(0.0, 1.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// How to remove outliers before summarizing.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Trim {
+    /// Keep everything.
+    #[default]
+    None,
+
+    /// Drop the given fraction of values from each end.
+    Fraction(f64),
+
+    /// Drop values more than `k` interquartile ranges below the first quartile or above the third
+    /// (Tukey's fences; `k = 1.5` is customary).
+    Iqr(f64),
+}
+
+impl Trim {
+    /// The default `k` for `Trim::Iqr`.
+    pub const DEFAULT_IQR_K: f64 = 1.5;
+
+    /// Parse a trimming method: `none`, `pct:<p>` (drop p% from each end) or `iqr[:<k>]`.
+    pub fn parse(s: &str) -> std::result::Result<Self, String> {
+        let mut parts = s.splitn(2, ':');
+        let bad = || {
+            format!(
+                "Not a valid trimming method (expected none, pct:<p> or iqr[:<k>]): {}",
+                s
+            )
+        };
+        let number = |n: &str| n.parse::<f64>().ok().filter(|n| *n >= 0.0).ok_or_else(bad);
+
+        match (parts.next().unwrap(), parts.next()) {
+            ("none", None) => Ok(Trim::None),
+            ("pct", Some(p)) => {
+                let p = number(p)?;
+                if p >= 50.0 {
+                    return Err(bad());
+                }
+                Ok(Trim::Fraction(p / 100.0))
+            }
+            ("iqr", None) => Ok(Trim::Iqr(Self::DEFAULT_IQR_K)),
+            ("iqr", Some(k)) => Ok(Trim::Iqr(number(k)?)),
+            _ => Err(bad()),
+        }
+    }
+
+    /// The trimming method given by an optional command line argument (checked with `is_trim`),
+    /// or the default if it was not given.
+    pub fn from_arg(arg: Option<&str>) -> Self {
+        arg.map(|s| Trim::parse(s).unwrap()).unwrap_or_default()
+    }
+
+    /// Remove the outliers from sorted values.
+    pub fn apply(self, sorted: &mut Vec<f64>) {
+        match self {
+            Trim::None => {}
+
+            Trim::Fraction(fraction) => {
+                let n = (sorted.len() as f64 * fraction).floor() as usize;
+                sorted.truncate(sorted.len() - n);
+                sorted.drain(..n);
+            }
+
+            Trim::Iqr(k) => {
+                let (q1, q3) = (quantile(sorted, 0.25), quantile(sorted, 0.75));
+                let (lo, hi) = (q1 - k * (q3 - q1), q3 + k * (q3 - q1));
+                sorted.retain(|&v| lo <= v && v <= hi);
+            }
+        }
+    }
+}
+
+/// A clap validator for trimming methods.
+pub fn is_trim(arg: String) -> std::result::Result<(), String> {
+    Trim::parse(&arg).map(|_| ())
+}
+
+/// Summary statistics of a set of measurements.
+#[derive(Debug, Clone)]
+pub struct Summary {
+    /// The number of measurements, before trimming.
+    pub samples: usize,
+
+    /// The number of measurements removed as outliers.
+    pub trimmed: usize,
+
+    /// Over the remaining measurements.
+    pub stats: Welford,
+    pub min: f64,
+    pub max: f64,
+
+    /// The level of `ci`.
+    pub level: f64,
+
+    /// The confidence interval for the mean.
+    pub ci: (f64, f64),
+
+    /// The `REPORTED_PERCENTILES`.
+    pub percentiles: Vec<f64>,
+}
+
+impl Summary {
+    /// The default level of the confidence interval.
+    pub const DEFAULT_LEVEL: f64 = 0.95;
+
+    /// Summarize the measurements, after removing outliers.
+    pub fn new(values: impl IntoIterator<Item = f64>, trim: Trim) -> Self {
+        let mut sorted: Vec<f64> = values.into_iter().collect();
+        sorted.sort_unstable_by(f64::total_cmp);
+
+        let samples = sorted.len();
+        trim.apply(&mut sorted);
+
+        let mut stats = Welford::new();
+        sorted.iter().for_each(|&v| stats.record(v));
+
+        Summary {
+            samples,
+            trimmed: samples - sorted.len(),
+            stats,
+            min: sorted.first().cloned().unwrap_or(f64::NAN),
+            max: sorted.last().cloned().unwrap_or(f64::NAN),
+            level: Self::DEFAULT_LEVEL,
+            ci: stats.confidence_interval(Self::DEFAULT_LEVEL),
+            percentiles: REPORTED_PERCENTILES
+                .iter()
+                .map(|&(_, _, q)| quantile(&sorted, q))
+                .collect(),
+        }
+    }
+
+    /// Write the summary to `out`, in the style of `Histogram::report`.
+    pub fn report(
+        &self,
+        out: &mut Output,
+        phase: &str,
+        clock: ClockSource,
+        units: Units,
+    ) -> io::Result<()> {
+        let record =
+            |metric, value: f64| Record::new(phase, metric, value).clock(clock).units(units);
+
+        let avg = self.stats.mean();
+        let sd = self.stats.sd();
+
+        out.text(format_args!("{} ({}):", phase, units.as_str()))?;
+        out.emit(
+            format_args!("samples: {} ({} trimmed)", self.samples, self.trimmed),
+            &[
+                Record::new(phase, "samples", self.samples),
+                Record::new(phase, "trimmed", self.trimmed),
+            ],
+        )?;
+        out.emit(format_args!("avg: {}", avg), &[record("avg", avg)])?;
+        out.emit(
+            format_args!("sd: {} ({}%)", sd, sd / avg * 100.),
+            &[
+                record("sd", sd),
+                Record::new(phase, "sd_relative", sd / avg * 100.).units(Units::Percent),
+            ],
+        )?;
+        out.emit(
+            format_args!("{}% CI: [{}, {}]", self.level * 100., self.ci.0, self.ci.1),
+            &[record("ci_low", self.ci.0), record("ci_high", self.ci.1)],
+        )?;
+        out.emit(
+            format_args!("min: {}", self.min),
+            &[record("min", self.min)],
+        )?;
+
+        for (&(name, metric, _), &value) in REPORTED_PERCENTILES.iter().zip(&self.percentiles) {
+            out.emit(
+                format_args!("{}: {}", name, value),
+                &[record(metric, value)],
+            )?;
+        }
+
+        out.emit(
+            format_args!("max: {}", self.max),
+            &[record("max", self.max)],
+        )
+    }
+}
@@ -0,0 +1,296 @@
+//! Serialized reads of the TSC, for timing short sections of code.
+//!
+//! A bare `rdtsc` is not serialized: the CPU may execute it before earlier instructions have
+//! finished, or start later instructions before it, so the code being measured can leak out of (or
+//! into) the timed section. The `Timer`s here fence the TSC reads in the ways recommended by Intel's
+//! "How to Benchmark Code Execution Times" white paper, at the cost of a higher overhead.
+//!
+//! A `Stopwatch` measures the overhead of back-to-back reads of its timer when it is created, and
+//! can subtract it from every sample, so that the samples only count the code being measured.
+
+use std::{arch::asm, arch::x86_64::__cpuid, fmt, io};
+
+use crate::output::{ClockSource, Output, Record, Units};
+
+/// The number of empty start/stop pairs timed to measure the overhead of a timer.
+const OVERHEAD_SAMPLES: usize = 100_000;
+
+/// `rdtsc`, with no fencing at all.
+#[inline(always)]
+pub fn rdtsc() -> u64 {
+    let hi: u32;
+    let lo: u32;
+
+    unsafe {
+        asm!("rdtsc", out("eax") lo, out("edx") hi, options(nostack));
+    }
+
+    lo as u64 | ((hi as u64) << 32)
+}
+
+/// `lfence; rdtsc; lfence`: the TSC is read after all earlier instructions have completed, and
+/// before any later instruction starts.
+#[inline(always)]
+pub fn rdtsc_lfence() -> u64 {
+    let hi: u32;
+    let lo: u32;
+
+    unsafe {
+        asm!(
+            "lfence
+             rdtsc
+             lfence",
+             out("eax") lo,
+             out("edx") hi,
+             options(nostack),
+        );
+    }
+
+    lo as u64 | ((hi as u64) << 32)
+}
+
+/// `rdtscp; lfence`: the TSC is read after all earlier instructions have completed, and before any
+/// later instruction starts. Also returns the CPU the TSC was read on, as the kernel stores it in
+/// `IA32_TSC_AUX`.
+#[inline(always)]
+pub fn rdtscp() -> (u64, usize) {
+    let hi: u32;
+    let lo: u32;
+    let aux: u32;
+
+    unsafe {
+        asm!(
+            "rdtscp
+             lfence",
+             out("eax") lo,
+             out("edx") hi,
+             out("ecx") aux,
+             options(nostack),
+        );
+    }
+
+    // The low 12 bits are the CPU, and the rest the NUMA node.
+    (lo as u64 | ((hi as u64) << 32), (aux & 0xfff) as usize)
+}
+
+/// `cpuid; rdtsc`: the start of a fully serialized section. `cpuid` waits for everything before it,
+/// including stores, to complete.
+#[inline(always)]
+pub fn cpuid_start() -> u64 {
+    __cpuid(0);
+    rdtsc()
+}
+
+/// `rdtscp; cpuid`: the end of a fully serialized section. `rdtscp` waits for the section to
+/// complete, and `cpuid` keeps anything after it from starting before the TSC is read.
+#[inline(always)]
+pub fn cpuid_stop() -> u64 {
+    let (tsc, _) = rdtscp();
+    __cpuid(0);
+    tsc
+}
+
+/// How to read the TSC at the start and end of a timed section.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Timer {
+    /// Plain `rdtsc`: cheapest, but not serialized.
+    #[default]
+    Rdtsc,
+
+    /// `lfence; rdtsc; lfence`.
+    Lfence,
+
+    /// `rdtscp; lfence`.
+    Rdtscp,
+
+    /// `cpuid; rdtsc` to start and `rdtscp; cpuid` to stop: the most expensive, but also
+    /// serializes stores.
+    Cpuid,
+}
+
+impl Timer {
+    /// Parse a timer: `rdtsc`, `lfence`, `rdtscp` or `cpuid`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "rdtsc" => Ok(Timer::Rdtsc),
+            "lfence" => Ok(Timer::Lfence),
+            "rdtscp" => Ok(Timer::Rdtscp),
+            "cpuid" => Ok(Timer::Cpuid),
+            _ => Err(format!(
+                "Not a valid timer (expected rdtsc, lfence, rdtscp or cpuid): {}",
+                s
+            )),
+        }
+    }
+
+    /// The timer given by an optional command line argument (checked with `is_timer`), or the
+    /// default if it was not given.
+    pub fn from_arg(arg: Option<&str>) -> Self {
+        arg.map(|s| Timer::parse(s).unwrap()).unwrap_or_default()
+    }
+
+    /// Read the TSC at the start of a timed section.
+    #[inline(always)]
+    pub fn start(self) -> u64 {
+        match self {
+            Timer::Rdtsc => rdtsc(),
+            Timer::Lfence => rdtsc_lfence(),
+            Timer::Rdtscp => rdtscp().0,
+            Timer::Cpuid => cpuid_start(),
+        }
+    }
+
+    /// Read the TSC at the end of a timed section.
+    #[inline(always)]
+    pub fn stop(self) -> u64 {
+        match self {
+            Timer::Rdtsc => rdtsc(),
+            Timer::Lfence => rdtsc_lfence(),
+            Timer::Rdtscp => rdtscp().0,
+            Timer::Cpuid => cpuid_stop(),
+        }
+    }
+
+    /// Measure the cost of the timer itself: the median number of cycles between `start` and
+    /// `stop` with nothing in between.
+    pub fn overhead(self) -> u64 {
+        let mut samples: Vec<u64> = (0..OVERHEAD_SAMPLES)
+            .map(|_| {
+                let start = self.start();
+                self.stop().saturating_sub(start)
+            })
+            .collect();
+
+        samples.sort_unstable();
+        samples[samples.len() / 2]
+    }
+}
+
+impl fmt::Display for Timer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Timer::Rdtsc => "rdtsc",
+            Timer::Lfence => "lfence",
+            Timer::Rdtscp => "rdtscp",
+            Timer::Cpuid => "cpuid",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A clap validator for timers.
+pub fn is_timer(arg: String) -> Result<(), String> {
+    Timer::parse(&arg).map(|_| ())
+}
+
+/// A timer, together with its measured overhead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stopwatch {
+    timer: Timer,
+    overhead: u64,
+    subtract: bool,
+
+    /// Whether the timer was chosen on the command line, rather than left at the defaults.
+    chosen: bool,
+}
+
+impl Stopwatch {
+    /// Measure the overhead of the given timer. Samples include the overhead unless
+    /// `subtract_overhead` is set.
+    pub fn new(timer: Timer) -> Self {
+        Stopwatch {
+            timer,
+            overhead: timer.overhead(),
+            subtract: false,
+            chosen: true,
+        }
+    }
+
+    /// The stopwatch given by the optional `--timer` (checked with `is_timer`) and
+    /// `--subtract_overhead` command line arguments. It only counts as chosen if either was given.
+    pub fn from_args(timer: Option<&str>, subtract: bool) -> Self {
+        Stopwatch {
+            chosen: timer.is_some() || subtract,
+            ..Stopwatch::new(Timer::from_arg(timer)).subtract_overhead(subtract)
+        }
+    }
+
+    /// Whether to subtract the overhead from samples.
+    pub fn subtract_overhead(mut self, subtract: bool) -> Self {
+        self.subtract = subtract;
+        self
+    }
+
+    pub fn timer(&self) -> Timer {
+        self.timer
+    }
+
+    /// The overhead in cycles.
+    pub fn overhead(&self) -> u64 {
+        self.overhead
+    }
+
+    /// Start timing.
+    #[inline(always)]
+    pub fn start(&self) -> u64 {
+        self.timer.start()
+    }
+
+    /// Stop timing, and return the cycles elapsed since `start`, less the overhead if requested.
+    /// Samples shorter than the overhead become 0, as do samples during which the hypervisor moved
+    /// the guest's TSC back (see `stop_signed`).
+    #[inline(always)]
+    pub fn stop(&self, start: u64) -> u64 {
+        let elapsed = self.timer.stop().saturating_sub(start);
+        if self.subtract {
+            elapsed.saturating_sub(self.overhead)
+        } else {
+            elapsed
+        }
+    }
+
+    /// Like `stop`, but the result may be negative, e.g. if the hypervisor moved the guest's TSC
+    /// back in the meantime.
+    #[inline(always)]
+    pub fn stop_signed(&self, start: u64) -> i64 {
+        let elapsed = self.timer.stop().wrapping_sub(start) as i64;
+        if self.subtract {
+            elapsed - self.overhead as i64
+        } else {
+            elapsed
+        }
+    }
+
+    /// Time `f`.
+    #[inline(always)]
+    pub fn time(&self, f: impl FnOnce()) -> u64 {
+        let start = self.start();
+        f();
+        self.stop(start)
+    }
+
+    /// Write the timer and its overhead to `out`, but only if the timer was chosen (see
+    /// `from_args`), so that the output of a benchmark run with the defaults does not change.
+    pub fn report_if_chosen(&self, out: &mut Output) -> io::Result<()> {
+        if self.chosen {
+            self.report(out)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Write the timer and its overhead to `out`.
+    pub fn report(&self, out: &mut Output) -> io::Result<()> {
+        out.emit(
+            format_args!(
+                "Timer: {} (overhead: {} cycles{})",
+                self.timer,
+                self.overhead,
+                if self.subtract { ", subtracted" } else { "" }
+            ),
+            &[Record::new("timer", "overhead", self.overhead)
+                .clock(ClockSource::Rdtsc)
+                .units(Units::Cycles)],
+        )
+    }
+}
@@ -0,0 +1,360 @@
+//! CPU topology, from `/sys/devices/system/cpu` and `sched_getaffinity`, and helpers for pinning
+//! threads to CPUs.
+//!
+//! Only the CPUs that are online and that this process is allowed to run on (e.g. by a cgroup
+//! cpuset or `taskset`) are considered. A `Placement` assigns threads to those CPUs, e.g. one
+//! thread per physical core or all threads on one NUMA node, and `crate::set_cpu` pins a thread to
+//! the `CpuSet` it was given.
+
+use std::{collections::BTreeSet, fmt, iter::FromIterator, path::Path};
+
+use crate::{error, Error, Result};
+
+const CPU_PATH: &str = "/sys/devices/system/cpu";
+const NODE_PATH: &str = "/sys/devices/system/node";
+
+/// A set of logical CPUs.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct CpuSet(BTreeSet<usize>);
+
+impl CpuSet {
+    /// The empty set.
+    pub fn new() -> Self {
+        CpuSet(BTreeSet::new())
+    }
+
+    /// Parse a list in the kernel's format, like `0-3,8,10-11`.
+    pub fn parse(s: &str) -> std::result::Result<Self, String> {
+        parse_list(s).map(|cpus| cpus.into_iter().collect())
+    }
+
+    pub fn insert(&mut self, cpu: usize) {
+        self.0.insert(cpu);
+    }
+
+    pub fn contains(&self, cpu: usize) -> bool {
+        self.0.contains(&cpu)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The CPUs in increasing order.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.0.iter().cloned()
+    }
+
+    /// The lowest CPU in the set, if any.
+    pub fn first(&self) -> Option<usize> {
+        self.0.iter().next().cloned()
+    }
+
+    /// The CPUs in both sets.
+    pub fn intersection(&self, other: &CpuSet) -> CpuSet {
+        CpuSet(self.0.intersection(&other.0).cloned().collect())
+    }
+
+    /// The set as a `libc::cpu_set_t`. CPUs that do not fit are dropped.
+    pub(crate) fn to_libc(&self) -> libc::cpu_set_t {
+        let max = 8 * std::mem::size_of::<libc::cpu_set_t>();
+        unsafe {
+            let mut cpuset: libc::cpu_set_t = std::mem::zeroed();
+            for cpu in self.iter().filter(|&cpu| cpu < max) {
+                libc::CPU_SET(cpu, &mut cpuset);
+            }
+            cpuset
+        }
+    }
+}
+
+impl From<usize> for CpuSet {
+    fn from(cpu: usize) -> Self {
+        CpuSet(std::iter::once(cpu).collect())
+    }
+}
+
+impl FromIterator<usize> for CpuSet {
+    fn from_iter<I: IntoIterator<Item = usize>>(iter: I) -> Self {
+        CpuSet(iter.into_iter().collect())
+    }
+}
+
+/// Formats the set in the kernel's list format, like `0-3,8`.
+impl fmt::Display for CpuSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut cpus = self.iter().peekable();
+        let mut first = true;
+
+        while let Some(lo) = cpus.next() {
+            let mut hi = lo;
+            while cpus.peek() == Some(&(hi + 1)) {
+                hi = cpus.next().unwrap();
+            }
+
+            if !first {
+                write!(f, ",")?;
+            }
+            first = false;
+
+            if lo == hi {
+                write!(f, "{}", lo)?;
+            } else {
+                write!(f, "{}-{}", lo, hi)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Parse a list of CPUs or nodes in the kernel's format, like `0-3,8,10-11`.
+pub(crate) fn parse_list(s: &str) -> std::result::Result<Vec<usize>, String> {
+    let mut items = vec![];
+
+    for range in s.trim().split(',').filter(|range| !range.is_empty()) {
+        let bad = || format!("Not a valid list: {}", s);
+        let (lo, hi): (usize, usize) = match range.split_once('-') {
+            Some((lo, hi)) => (
+                lo.parse().map_err(|_| bad())?,
+                hi.parse().map_err(|_| bad())?,
+            ),
+            None => {
+                let item = range.parse().map_err(|_| bad())?;
+                (item, item)
+            }
+        };
+        if lo > hi {
+            return Err(bad());
+        }
+        items.extend(lo..=hi);
+    }
+
+    Ok(items)
+}
+
+/// Read a list file from sysfs.
+fn read_list(path: impl AsRef<Path>) -> Result<Vec<usize>> {
+    let path = path.as_ref();
+    parse_list(&error::read_to_string(path)?).map_err(|reason| Error::malformed(path, reason))
+}
+
+/// The CPUs the calling thread is allowed to run on (`sched_getaffinity`).
+pub fn affinity() -> Result<CpuSet> {
+    let mut cpuset: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    let res = unsafe {
+        libc::sched_getaffinity(
+            /* self */ 0,
+            std::mem::size_of::<libc::cpu_set_t>(),
+            &mut cpuset,
+        )
+    };
+    if res != 0 {
+        return Err(Error::last_syscall("sched_getaffinity"));
+    }
+
+    let max = 8 * std::mem::size_of::<libc::cpu_set_t>();
+    Ok((0..max)
+        .filter(|&cpu| unsafe { libc::CPU_ISSET(cpu, &cpuset) })
+        .collect())
+}
+
+/// Where a logical CPU sits.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cpu {
+    pub id: usize,
+
+    /// The physical core (`core_id`, unique only within a package).
+    pub core: usize,
+
+    /// The package, i.e. socket (`physical_package_id`).
+    pub package: usize,
+
+    /// The NUMA node (0 if the kernel has no NUMA support).
+    pub node: usize,
+
+    /// The SMT siblings of this CPU, including itself (`thread_siblings_list`).
+    pub siblings: CpuSet,
+}
+
+/// The usable CPUs of the machine: those that are online and in this thread's affinity mask.
+#[derive(Debug, Clone)]
+pub struct Topology {
+    /// In increasing order of ID.
+    cpus: Vec<Cpu>,
+}
+
+impl Topology {
+    /// Read the topology from sysfs.
+    pub fn read() -> Result<Self> {
+        let online: CpuSet = read_list(Path::new(CPU_PATH).join("online"))?
+            .into_iter()
+            .collect();
+        let usable = online.intersection(&affinity()?);
+
+        // Which node is each CPU on?
+        let mut nodes = vec![];
+        match read_list(Path::new(NODE_PATH).join("online")) {
+            Ok(online_nodes) => {
+                for node in online_nodes {
+                    let path = Path::new(NODE_PATH).join(format!("node{}/cpulist", node));
+                    nodes.push((node, read_list(path)?));
+                }
+            }
+            Err(ref e) if e.is_missing() => {}
+            Err(e) => return Err(e),
+        }
+
+        let cpus = usable
+            .iter()
+            .map(|id| {
+                let topology = Path::new(CPU_PATH).join(format!("cpu{}/topology", id));
+                let read = |name: &str| {
+                    let path = topology.join(name);
+                    error::parse::<usize>(&path, Some(error::read_to_string(&path)?.trim()))
+                };
+
+                Ok(Cpu {
+                    id,
+                    core: read("core_id")?,
+                    package: read("physical_package_id")?,
+                    node: nodes
+                        .iter()
+                        .find(|(_, cpus)| cpus.contains(&id))
+                        .map_or(0, |&(node, _)| node),
+                    siblings: read_list(topology.join("thread_siblings_list"))?
+                        .into_iter()
+                        .collect(),
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Topology { cpus })
+    }
+
+    /// The usable CPUs.
+    pub fn cpus(&self) -> &[Cpu] {
+        &self.cpus
+    }
+
+    /// The usable CPUs, as a set.
+    pub fn cpuset(&self) -> CpuSet {
+        self.cpus.iter().map(|cpu| cpu.id).collect()
+    }
+
+    /// The physical cores, as the sets of usable SMT siblings on each, in order of their first CPU.
+    pub fn cores(&self) -> Vec<CpuSet> {
+        let usable = self.cpuset();
+        let mut cores: Vec<CpuSet> = vec![];
+
+        for cpu in self.cpus.iter() {
+            if !cores.iter().any(|core| core.contains(cpu.id)) {
+                cores.push(cpu.siblings.intersection(&usable));
+            }
+        }
+
+        cores
+    }
+
+    /// The packages with usable CPUs.
+    pub fn packages(&self) -> Vec<usize> {
+        let packages: BTreeSet<_> = self.cpus.iter().map(|cpu| cpu.package).collect();
+        packages.into_iter().collect()
+    }
+
+    /// The NUMA nodes with usable CPUs.
+    pub fn nodes(&self) -> Vec<usize> {
+        let nodes: BTreeSet<_> = self.cpus.iter().map(|cpu| cpu.node).collect();
+        nodes.into_iter().collect()
+    }
+
+    /// The usable CPUs on the given NUMA node.
+    pub fn node_cpus(&self, node: usize) -> CpuSet {
+        self.cpus
+            .iter()
+            .filter(|cpu| cpu.node == node)
+            .map(|cpu| cpu.id)
+            .collect()
+    }
+
+    /// The CPUs to pin `n` threads to, one CPU per thread, according to the given placement. If
+    /// there are more threads than CPUs to choose from, the CPUs are reused round-robin.
+    pub fn place(&self, placement: Placement, n: usize) -> Result<Vec<CpuSet>> {
+        let choices: Vec<usize> = match placement {
+            Placement::Cpus => self.cpus.iter().map(|cpu| cpu.id).collect(),
+            Placement::Cores => self.cores().iter().filter_map(CpuSet::first).collect(),
+            Placement::Node(node) => self.node_cpus(node).iter().collect(),
+        };
+
+        // There are no usable CPUs on the node (or, in theory, at all).
+        if choices.is_empty() {
+            return Err(Error::Missing {
+                path: match placement {
+                    Placement::Node(node) => Path::new(NODE_PATH).join(format!("node{}", node)),
+                    _ => Path::new(CPU_PATH).join("online"),
+                },
+            });
+        }
+
+        Ok((0..n).map(|i| choices[i % choices.len()].into()).collect())
+    }
+}
+
+/// How to assign threads to CPUs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Placement {
+    /// One thread per usable logical CPU.
+    #[default]
+    Cpus,
+
+    /// One thread per physical core (on the first SMT sibling), so that threads do not share a
+    /// core.
+    Cores,
+
+    /// All threads on the CPUs of the given NUMA node.
+    Node(usize),
+}
+
+impl Placement {
+    /// Parse a placement: `cpus`, `cores` or `node:<n>`.
+    pub fn parse(s: &str) -> std::result::Result<Self, String> {
+        let mut parts = s.splitn(2, ':');
+        match (parts.next().unwrap(), parts.next()) {
+            ("cpus", None) => Ok(Placement::Cpus),
+            ("cores", None) => Ok(Placement::Cores),
+            ("node", Some(node)) => node
+                .parse()
+                .map(Placement::Node)
+                .map_err(|_| format!("Not a valid node: {}", node)),
+            _ => Err(format!(
+                "Not a valid placement (expected cpus, cores or node:<n>): {}",
+                s
+            )),
+        }
+    }
+    /// The placement given by an optional command line argument (checked with `is_placement`), or
+    /// the default if it was not given.
+    pub fn from_arg(arg: Option<&str>) -> Self {
+        arg.map(|s| Placement::parse(s).unwrap())
+            .unwrap_or_default()
+    }
+}
+
+impl fmt::Display for Placement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Placement::Cpus => write!(f, "cpus"),
+            Placement::Cores => write!(f, "cores"),
+            Placement::Node(node) => write!(f, "node:{}", node),
+        }
+    }
+}
+
+/// A clap validator for placements.
+pub fn is_placement(arg: String) -> std::result::Result<(), String> {
+    Placement::parse(&arg).map(|_| ())
+}
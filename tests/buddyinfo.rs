@@ -0,0 +1,107 @@
+use paperexp::buddyinfo::{parse_buddyinfo, parse_pagetypeinfo, ContigInfo, FreeAreas};
+
+const BUDDYINFO: &str = include_str!("fixtures/buddyinfo");
+const PAGETYPEINFO: &str = include_str!("fixtures/pagetypeinfo");
+
+fn zone(free: &[u64]) -> FreeAreas {
+    FreeAreas {
+        node: 0,
+        zone: "Normal".into(),
+        migrate_type: None,
+        free: free.to_vec(),
+    }
+}
+
+#[test]
+fn parses_buddyinfo() {
+    let zones = parse_buddyinfo(BUDDYINFO).unwrap();
+
+    let names: Vec<_> = zones.iter().map(|z| (z.node, z.zone.as_str())).collect();
+    assert_eq!(
+        names,
+        vec![(0, "DMA"), (0, "DMA32"), (0, "Normal"), (1, "Normal")]
+    );
+
+    assert!(zones.iter().all(|z| z.free.len() == 11));
+    assert!(zones.iter().all(|z| z.migrate_type.is_none()));
+    assert_eq!(zones[0].free, vec![0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 3]);
+    assert_eq!(zones[0].free_pages(), 256 + 512 + 3 * 1024);
+}
+
+#[test]
+fn rejects_malformed_buddyinfo() {
+    assert!(parse_buddyinfo("Node 0, zone Normal 1 2 x\n").is_err());
+    assert!(parse_buddyinfo("garbage\n").is_err());
+}
+
+#[test]
+fn parses_pagetypeinfo() {
+    let info = parse_pagetypeinfo(PAGETYPEINFO).unwrap();
+
+    assert_eq!(info.pageblock_order, 9);
+
+    assert_eq!(info.free.len(), 10);
+    let movable = &info.free[6];
+    assert_eq!(movable.node, 0);
+    assert_eq!(movable.zone, "Normal");
+    assert_eq!(movable.migrate_type.as_deref(), Some("Movable"));
+    assert_eq!(
+        movable.free,
+        vec![2329, 6128, 2565, 1575, 360, 318, 120, 47, 26, 14, 3]
+    );
+
+    assert_eq!(info.blocks.len(), 2);
+    assert_eq!(info.blocks[1].zone, "Normal");
+    assert_eq!(info.blocks[1].counts[1], ("Movable".into(), 1458));
+    assert_eq!(info.blocks[1].counts.len(), 5);
+}
+
+#[test]
+fn contig_info() {
+    assert_eq!(
+        zone(&[4, 2, 1]).contig_info(1),
+        ContigInfo {
+            free_pages: 4 + 4 + 4,
+            free_blocks_total: 7,
+            free_blocks_suitable: 2 + 2,
+        }
+    );
+}
+
+#[test]
+fn unusable_index() {
+    let z = zone(&[4, 2, 0]);
+    assert_eq!(z.unusable_index(0), 0.0);
+    assert_eq!(z.unusable_index(1), 0.5);
+    assert_eq!(z.unusable_index(2), 1.0);
+
+    // No free memory.
+    assert_eq!(zone(&[0, 0]).unusable_index(1), 0.0);
+
+    // Rounds down to thousandths, like the kernel.
+    let zones = parse_buddyinfo(BUDDYINFO).unwrap();
+    assert_eq!(zones[0].unusable_index(9), 0.066);
+}
+
+#[test]
+fn fragmentation_index() {
+    let z = zone(&[4, 2, 0]);
+
+    // The allocation would succeed.
+    assert_eq!(z.fragmentation_index(1), -1.0);
+
+    // 8 free pages in 6 blocks, none of which can hold 4 pages.
+    assert_eq!(z.fragmentation_index(2), 0.5);
+
+    // No free memory.
+    assert_eq!(zone(&[0, 0]).fragmentation_index(1), 0.0);
+
+    // Plenty of free memory, all of it in small blocks: 1000 - (1000 + 2048 * 1000 / 512) / 1536.
+    let zones = parse_buddyinfo(BUDDYINFO).unwrap();
+    assert_eq!(zones[3].fragmentation_index(9), 0.997);
+    assert_eq!(zones[3].unusable_index(9), 1.0);
+
+    let f = zones[3].fragmentation(9);
+    assert_eq!((f.node, f.zone.as_str(), f.order), (1, "Normal", 9));
+    assert_eq!(f.free_pages, 2048);
+}
@@ -0,0 +1,178 @@
+use rand::{rngs::SmallRng, SeedableRng};
+
+use paperexp::keys::{KeyChooser, KeyDistribution, Op, OpMix};
+
+#[test]
+fn parses_key_distributions() {
+    assert_eq!(
+        KeyDistribution::parse("sequential"),
+        Ok(KeyDistribution::Sequential)
+    );
+    assert_eq!(
+        KeyDistribution::parse("uniform"),
+        Ok(KeyDistribution::Uniform)
+    );
+    assert_eq!(
+        KeyDistribution::parse("zipf"),
+        Ok(KeyDistribution::Zipfian { theta: 0.99 })
+    );
+    assert_eq!(
+        KeyDistribution::parse("latest:0.5"),
+        Ok(KeyDistribution::Latest { theta: 0.5 })
+    );
+    assert_eq!(
+        KeyDistribution::parse("hotspot:0.2:0.8"),
+        Ok(KeyDistribution::Hotspot {
+            hot_fraction: 0.2,
+            hot_ops: 0.8
+        })
+    );
+
+    for bad in &[
+        "",
+        "zipfian",
+        "uniform:1",
+        "sequential:1",
+        "zipf:",
+        "zipf:1.0",
+        "zipf:0",
+        "zipf:0.5:1",
+        "latest:x",
+        "hotspot",
+        "hotspot:0.2",
+        "hotspot:1.5:0.5",
+        "hotspot:0.2:-0.1",
+        "hotspot:0.2:0.8:1",
+    ] {
+        assert!(KeyDistribution::parse(bad).is_err(), "{}", bad);
+    }
+}
+
+#[test]
+fn parses_op_mixes() {
+    assert_eq!(
+        OpMix::parse("read=0.95, update=0.05"),
+        Ok(OpMix {
+            read: 0.95,
+            update: 0.05,
+            insert: 0.0,
+            delete: 0.0,
+        })
+    );
+
+    // Zero weights are allowed as long as some weight is positive.
+    assert_eq!(
+        OpMix::parse("read=0,insert=2"),
+        Ok(OpMix {
+            read: 0.0,
+            update: 0.0,
+            insert: 2.0,
+            delete: 0.0,
+        })
+    );
+
+    for bad in &[
+        "",
+        "read",
+        "read=",
+        "read=x",
+        "read=-1",
+        "scan=1",
+        "read=0",
+        "read=0,update=0",
+    ] {
+        assert!(OpMix::parse(bad).is_err(), "{}", bad);
+    }
+}
+
+#[test]
+fn skips_zero_weight_ops() {
+    let mut rng = SmallRng::seed_from_u64(0);
+
+    for &(mix, only) in &[
+        ("read=1,update=0,insert=0,delete=0", Op::Read),
+        ("read=0,update=1", Op::Update),
+        ("insert=1,delete=0", Op::Insert),
+        ("read=0,delete=3", Op::Delete),
+    ] {
+        let mix = OpMix::parse(mix).unwrap();
+        for _ in 0..10_000 {
+            assert_eq!(mix.sample(&mut rng), only);
+        }
+    }
+
+    let mix = OpMix::parse("read=1,update=0,insert=1").unwrap();
+    let mut inserts = 0;
+    for _ in 0..10_000 {
+        match mix.sample(&mut rng) {
+            Op::Read => {}
+            Op::Insert => inserts += 1,
+            op => panic!("{:?}", op),
+        }
+    }
+    assert!(inserts > 4_000 && inserts < 6_000, "{}", inserts);
+}
+
+#[test]
+fn keys_stay_in_range() {
+    for dist in &[
+        KeyDistribution::Sequential,
+        KeyDistribution::Uniform,
+        KeyDistribution::Zipfian { theta: 0.99 },
+        KeyDistribution::Hotspot {
+            hot_fraction: 0.2,
+            hot_ops: 0.8,
+        },
+        KeyDistribution::Hotspot {
+            hot_fraction: 0.0,
+            hot_ops: 1.0,
+        },
+        KeyDistribution::Hotspot {
+            hot_fraction: 1.0,
+            hot_ops: 0.0,
+        },
+        KeyDistribution::Latest { theta: 0.5 },
+    ] {
+        let mut keys = KeyChooser::new(dist.clone(), 1);
+
+        // Grow the key space as inserts would, including the smallest one.
+        for n in (1..=50).chain(1000..1010) {
+            for _ in 0..200 {
+                let key = keys.next(n);
+                assert!(key < n, "{:?}: {} >= {}", dist, key, n);
+            }
+        }
+    }
+}
+
+#[test]
+fn skews_keys() {
+    let n = 1000;
+    let count = |dist, pred: &dyn Fn(usize) -> bool| {
+        let mut keys = KeyChooser::new(dist, 2);
+        (0..10_000).filter(|_| pred(keys.next(n))).count()
+    };
+
+    // 80% of the operations go to the lowest 20% of the keys.
+    let hot = count(
+        KeyDistribution::Hotspot {
+            hot_fraction: 0.2,
+            hot_ops: 0.8,
+        },
+        &|key| key < 200,
+    );
+    assert!(hot > 7_500 && hot < 8_500, "{}", hot);
+
+    // The newest keys are the most popular.
+    let newest = count(KeyDistribution::Latest { theta: 0.99 }, &|key| {
+        key >= n - 10
+    });
+    let oldest = count(KeyDistribution::Latest { theta: 0.99 }, &|key| key < 10);
+    assert!(newest > 10 * oldest, "{} vs {}", newest, oldest);
+
+    let mut keys = KeyChooser::new(KeyDistribution::Sequential, 0);
+    assert_eq!(
+        (0..5).map(|_| keys.next(3)).collect::<Vec<_>>(),
+        vec![0, 1, 2, 0, 1]
+    );
+}
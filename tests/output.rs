@@ -0,0 +1,213 @@
+use std::{
+    io::{self, Write},
+    sync::{Arc, Mutex},
+};
+
+use paperexp::{
+    clock::TscClock,
+    output::{ClockSource, Format, Output, Record, Units, Value},
+};
+
+/// A writer whose contents can be read back after the `Output` is done with it.
+#[derive(Clone, Default)]
+struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Buffer {
+    fn contents(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+/// Write `records` in the given format and return the output.
+fn write(format: Format, records: &[Record]) -> String {
+    let buffer = Buffer::default();
+    let mut out = Output::new(format, Box::new(buffer.clone()));
+    for record in records {
+        out.record(record).unwrap();
+    }
+    drop(out);
+    buffer.contents()
+}
+
+#[test]
+fn parses_formats() {
+    assert_eq!(Format::from_arg(None), Format::Text);
+    assert_eq!(Format::from_arg(Some("jsonl")), Format::Jsonl);
+    assert_eq!(Format::from_arg(Some("csv")), Format::Csv);
+    assert_eq!(Format::from_name("json"), None);
+}
+
+#[test]
+fn writes_jsonl() {
+    let out = write(
+        Format::Jsonl,
+        &[
+            Record::new("fill", "latency", 42u64)
+                .iteration(3)
+                .clock(ClockSource::Rdtsc)
+                .units(Units::Cycles),
+            Record::new("fill", "avg", f64::NAN),
+        ],
+    );
+
+    assert_eq!(
+        out,
+        "{\"phase\":\"fill\",\"iteration\":3,\"metric\":\"latency\",\"value\":42,\
+         \"clock\":\"rdtsc\",\"units\":\"cycles\"}\n\
+         {\"phase\":\"fill\",\"iteration\":null,\"metric\":\"avg\",\"value\":null,\
+         \"clock\":\"none\",\"units\":\"count\"}\n"
+    );
+}
+
+#[test]
+fn escapes_json_strings() {
+    let out = write(
+        Format::Jsonl,
+        &[Record::new("a\"b\\c\nd\te\u{1}é", "m", 1u64)],
+    );
+
+    assert!(
+        out.starts_with("{\"phase\":\"a\\\"b\\\\c\\nd\\te\\u0001é\",\"iteration\":null,"),
+        "{}",
+        out
+    );
+}
+
+#[test]
+fn writes_csv() {
+    let out = write(
+        Format::Csv,
+        &[
+            Record::new("fill", "latency", 42u64)
+                .iteration(3)
+                .clock(ClockSource::Rdtsc)
+                .units(Units::Cycles),
+            Record::new("a,b", "say \"hi\"", -1i64),
+            Record::new("line\nbreak", "inf", f64::INFINITY),
+        ],
+    );
+
+    assert_eq!(
+        out,
+        "phase,iteration,metric,value,clock,units\n\
+         fill,3,latency,42,rdtsc,cycles\n\
+         \"a,b\",,\"say \"\"hi\"\"\",-1,none,count\n\
+         \"line\nbreak\",,inf,,none,count\n"
+    );
+}
+
+#[test]
+fn writes_text() {
+    let buffer = Buffer::default();
+    let mut out = Output::new(Format::Text, Box::new(buffer.clone()));
+    out.emit(
+        format_args!("avg: {}", 1.5),
+        &[Record::new("fill", "avg", 1.5)],
+    )
+    .unwrap();
+    out.record(
+        &Record::new("fill", "latency", 42u64)
+            .iteration(3)
+            .units(Units::Cycles),
+    )
+    .unwrap();
+    out.text(format_args!("NEXT!")).unwrap();
+    drop(out);
+
+    assert_eq!(
+        buffer.contents(),
+        "avg: 1.5\nfill 3 latency: 42 cycles\nNEXT!\n"
+    );
+
+    // Text-only lines are dropped in the other formats.
+    let buffer = Buffer::default();
+    let mut out = Output::new(Format::Csv, Box::new(buffer.clone()));
+    out.text(format_args!("NEXT!")).unwrap();
+    drop(out);
+    assert_eq!(buffer.contents(), "");
+}
+
+#[test]
+fn shares_csv_header() {
+    let buffer = Buffer::default();
+    let mut out = Output::new(Format::Csv, Box::new(buffer.clone()));
+    let mut shared = out.share();
+    assert_eq!(shared.format(), Format::Csv);
+
+    shared.record(&Record::new("sampler", "a", 1u64)).unwrap();
+    out.record(&Record::new("fill", "b", 2u64)).unwrap();
+    let thread = std::thread::spawn(move || {
+        shared.record(&Record::new("sampler", "c", 3u64)).unwrap();
+    });
+    thread.join().unwrap();
+    drop(out);
+
+    assert_eq!(
+        buffer.contents(),
+        "phase,iteration,metric,value,clock,units\n\
+         sampler,,a,1,none,count\n\
+         fill,,b,2,none,count\n\
+         sampler,,c,3,none,count\n"
+    );
+}
+
+/// A latency in `rdtsc` cycles.
+fn cycles(value: impl Into<Value>) -> Record<'static> {
+    Record::new("touch", "latency", value)
+        .clock(ClockSource::Rdtsc)
+        .units(Units::Cycles)
+}
+
+#[test]
+fn converts_cycles() {
+    let buffer = Buffer::default();
+    let mut out = Output::new(Format::Csv, Box::new(buffer.clone()));
+    out.convert_cycles(TscClock::from_mhz(2000.0));
+    let mut shared = out.share();
+
+    out.record(&cycles(3001u64)).unwrap();
+    out.record(&cycles(-10i64)).unwrap();
+    shared.record(&cycles(5.0)).unwrap();
+
+    // Only rdtsc cycle counts are converted.
+    out.record(&Record::new("touch", "faults", 7u64).clock(ClockSource::Rdtsc))
+        .unwrap();
+    out.record(&Record::new("touch", "elapsed", 9u64).units(Units::Cycles))
+        .unwrap();
+    drop((out, shared));
+
+    assert_eq!(
+        buffer.contents(),
+        "phase,iteration,metric,value,clock,units\n\
+         touch,,latency,1501,rdtsc,ns\n\
+         touch,,latency,-5,rdtsc,ns\n\
+         touch,,latency,2.5,rdtsc,ns\n\
+         touch,,faults,7,rdtsc,count\n\
+         touch,,elapsed,9,none,cycles\n"
+    );
+
+    // In text mode, the ad-hoc text can't be converted, so converted records are written instead.
+    let buffer = Buffer::default();
+    let mut out = Output::new(Format::Text, Box::new(buffer.clone()));
+    out.convert_cycles(TscClock::from_mhz(1000.0));
+    out.emit(format_args!("{} cycles", 42), &[cycles(42u64)])
+        .unwrap();
+    out.emit(
+        format_args!("7 faults"),
+        &[Record::new("touch", "faults", 7u64)],
+    )
+    .unwrap();
+    drop(out);
+
+    assert_eq!(buffer.contents(), "touch latency: 42 ns\n7 faults\n");
+}
@@ -0,0 +1,54 @@
+use paperexp::pagemap::{parse_maps, Mapping};
+
+const MAPS: &str = include_str!("fixtures/maps");
+
+#[test]
+fn parses_maps() {
+    let maps = parse_maps(MAPS).unwrap();
+
+    let paths: Vec<_> = maps.iter().map(|m| m.path.as_deref()).collect();
+    assert_eq!(
+        paths,
+        vec![
+            Some("/usr/bin/cat"),
+            Some("[heap]"),
+            None,
+            Some("/tmp/my file (deleted)"),
+            Some("/memfd:region (deleted)"),
+            Some("[stack]"),
+        ]
+    );
+
+    assert_eq!(
+        maps[3],
+        Mapping {
+            start: 0x7f12_3420_0000,
+            end: 0x7f12_3440_0000,
+            perms: "rw-s".into(),
+            path: Some("/tmp/my file (deleted)".into()),
+        }
+    );
+    assert_eq!(maps[2].len(), 2 << 20);
+    assert_eq!(maps[5].perms, "rw-p");
+}
+
+#[test]
+fn rejects_bad_maps() {
+    assert_eq!(parse_maps("").unwrap(), vec![]);
+
+    for bad in &[
+        "7f1234000000 rw-p 00000000 00:00 0",
+        "7f1234000000-xyz rw-p 00000000 00:00 0",
+        "garbage",
+    ] {
+        assert!(parse_maps(bad).is_err(), "{}", bad);
+    }
+}
+
+#[test]
+fn reads_own_maps() {
+    let maps = paperexp::pagemap::maps(None).unwrap();
+    assert!(maps
+        .iter()
+        .any(|m| m.path.as_deref() == Some("[stack]") && !m.is_empty()));
+}
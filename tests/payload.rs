@@ -0,0 +1,149 @@
+use std::io::Write;
+
+use flate2::{write::DeflateEncoder, Compression};
+
+use paperexp::payload::{Pattern, PayloadGenerator, SizeDistribution};
+
+/// The size of `data` divided by its compressed size.
+fn compression_ratio(data: &[u8]) -> f64 {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data).unwrap();
+    data.len() as f64 / encoder.finish().unwrap().len() as f64
+}
+
+#[test]
+fn parses_patterns() {
+    assert_eq!(Pattern::parse("zeros"), Ok(Pattern::Zeros));
+    assert_eq!(Pattern::parse("counter"), Ok(Pattern::Counter));
+    assert_eq!(Pattern::parse("random"), Ok(Pattern::Random));
+    assert_eq!(Pattern::parse("text:2.5"), Ok(Pattern::Text { ratio: 2.5 }));
+    assert_eq!(
+        Pattern::parse("file:/tmp/a:b"),
+        Ok(Pattern::File("/tmp/a:b".into()))
+    );
+
+    for bad in &[
+        "", "zero", "zeros:1", "text", "text:", "text:0.5", "text:x", "file:", "file",
+    ] {
+        assert!(Pattern::parse(bad).is_err(), "{}", bad);
+    }
+}
+
+#[test]
+fn parses_size_distributions() {
+    assert_eq!(
+        SizeDistribution::parse("fixed", 100),
+        Ok(SizeDistribution::Fixed(100))
+    );
+    assert_eq!(
+        SizeDistribution::parse("uniform:10:20", 100),
+        Ok(SizeDistribution::Uniform { min: 10, max: 20 })
+    );
+    assert_eq!(
+        SizeDistribution::parse("zipf:1:64:0.5", 100),
+        Ok(SizeDistribution::Zipf {
+            min: 1,
+            max: 64,
+            theta: 0.5
+        })
+    );
+
+    assert!(SizeDistribution::parse("fixed", 0).is_err());
+    for bad in &[
+        "fixed:10",
+        "uniform:10",
+        "uniform:0:10",
+        "uniform:20:10",
+        "uniform:a:10",
+        "zipf:1:64",
+        "zipf:1:64:1.0",
+        "zipf:1:64:0",
+        "normal:1:2",
+    ] {
+        assert!(SizeDistribution::parse(bad, 100).is_err(), "{}", bad);
+    }
+}
+
+#[test]
+fn sizes_stay_in_range() {
+    for dist in &[
+        SizeDistribution::Uniform { min: 10, max: 20 },
+        SizeDistribution::Zipf {
+            min: 10,
+            max: 20,
+            theta: 0.99,
+        },
+    ] {
+        let mut gen = PayloadGenerator::new(Pattern::Random, dist.clone(), 1).unwrap();
+        let n = 10_000;
+        let mut total = 0;
+        for key in 0..n {
+            let len = gen.next(key).len();
+            assert!((10..=20).contains(&len), "{:?}: {}", dist, len);
+            total += len;
+        }
+
+        let mean = total as f64 / n as f64;
+        assert!(
+            (mean - gen.mean_size()).abs() < 0.5,
+            "{:?}: {} vs {}",
+            dist,
+            mean,
+            gen.mean_size()
+        );
+    }
+
+    // Zipf favors small values.
+    let mut gen = PayloadGenerator::new(
+        Pattern::Zeros,
+        SizeDistribution::Zipf {
+            min: 10,
+            max: 20,
+            theta: 0.99,
+        },
+        1,
+    )
+    .unwrap();
+    assert!(gen.mean_size() < 15.0);
+    let small = (0..1000).filter(|&key| gen.next(key).len() == 10).count();
+    assert!(small > 200, "{}", small);
+}
+
+#[test]
+fn fills_values() {
+    let mut gen = PayloadGenerator::zeros(16);
+    assert_eq!(gen.next(3), &[0; 16][..]);
+
+    let mut gen = PayloadGenerator::new(Pattern::Counter, SizeDistribution::Fixed(12), 0).unwrap();
+    assert_eq!(gen.next(0x0102), &[2, 1, 0, 0, 0, 0, 0, 0, 2, 1, 0, 0][..]);
+
+    // The same seed produces the same values.
+    let values = |seed| {
+        let mut gen =
+            PayloadGenerator::new(Pattern::Random, SizeDistribution::Fixed(8), seed).unwrap();
+        (0..4).map(|key| gen.next(key).to_vec()).collect::<Vec<_>>()
+    };
+    assert_eq!(values(7), values(7));
+    assert_ne!(values(7), values(8));
+}
+
+#[test]
+fn text_compresses_by_ratio() {
+    let size = 16 << 10;
+
+    for &ratio in &[1.0, 2.0, 4.0, 10.0] {
+        let mut gen =
+            PayloadGenerator::new(Pattern::Text { ratio }, SizeDistribution::Fixed(size), 3)
+                .unwrap();
+        let achieved = compression_ratio(gen.next(0));
+        assert!(
+            achieved > ratio * 0.85 && achieved < ratio * 1.15,
+            "{}: {}",
+            ratio,
+            achieved
+        );
+    }
+
+    let mut gen = PayloadGenerator::new(Pattern::Random, SizeDistribution::Fixed(size), 3).unwrap();
+    assert!(compression_ratio(gen.next(0)) < 1.05);
+}
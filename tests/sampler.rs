@@ -0,0 +1,35 @@
+use std::{
+    io::{self, Write},
+    time::Duration,
+};
+
+use paperexp::{
+    output::{Format, Output},
+    sampler::Sampler,
+};
+
+/// A writer that always fails, like a closed pipe.
+struct Broken;
+
+impl Write for Broken {
+    fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::BrokenPipe, "broken"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::BrokenPipe, "broken"))
+    }
+}
+
+#[test]
+fn stops_when_output_fails() {
+    let sampler = Sampler::start(
+        vec![],
+        Duration::from_millis(1),
+        Output::new(Format::Text, Box::new(Broken)),
+    );
+
+    // Give the sampler time to fail and end on its own; stopping it must still work.
+    std::thread::sleep(Duration::from_millis(20));
+    sampler.stop();
+}
@@ -0,0 +1,112 @@
+use std::time::Duration;
+
+use paperexp::{
+    keys::KeyDistribution,
+    script::{Amount, Expr, Script, Step, StepOp},
+};
+
+#[test]
+fn evaluates_exprs() {
+    let eval = |s: &str, n: usize| Expr::parse(s).unwrap().eval(n);
+
+    assert_eq!(eval("7", 100), 7);
+    assert_eq!(eval("N", 100), 100);
+    assert_eq!(eval("N/3", 100), 33);
+    assert_eq!(eval("2*N", 100), 200);
+    assert_eq!(eval("3*N/2", 101), 151);
+    assert_eq!(eval("N+N/2", 100), 150);
+    assert_eq!(eval(" N + 5 ", 100), 105);
+
+    assert!(Expr::parse("5").unwrap().is_constant());
+    assert!(!Expr::parse("5+N").unwrap().is_constant());
+    assert_eq!(Expr::parse("N").unwrap(), Expr::n());
+    assert_eq!(Expr::parse("4").unwrap(), Expr::constant(4));
+}
+
+#[test]
+fn rejects_malformed_exprs() {
+    for s in &[
+        "", "M", "N/0", "N*2", "x*N", "N/x", "N+", "-1", "2**N", "NN",
+    ] {
+        assert!(Expr::parse(s).is_err(), "{:?} should not parse", s);
+    }
+}
+
+#[test]
+fn parses_scripts() {
+    let script = Script::parse(
+        "# The default workload\n\
+         insert keys=0..N\n\
+         \n\
+         delete keys=0..N count=N/3 dist=zipf:0.5 # a comment\n\
+         insert name=reinsert keys=N..N+N/2 pause=1.5s\n\
+         get keys=0..10 duration=2s\n",
+    )
+    .unwrap();
+
+    assert_eq!(
+        script.steps,
+        vec![
+            Step::new(StepOp::Insert),
+            Step {
+                dist: KeyDistribution::Zipfian { theta: 0.5 },
+                amount: Amount::Count(Expr::parse("N/3").unwrap()),
+                ..Step::new(StepOp::Delete)
+            },
+            Step {
+                name: "reinsert".into(),
+                keys: (Expr::n(), Expr::parse("N+N/2").unwrap()),
+                pause: Duration::from_millis(1500),
+                ..Step::new(StepOp::Insert)
+            },
+            Step {
+                keys: (Expr::constant(0), Expr::constant(10)),
+                amount: Amount::Duration(Duration::from_secs(2)),
+                ..Step::new(StepOp::Get)
+            },
+        ]
+    );
+
+    assert_eq!(Script::parse("# nothing\n\n").unwrap().steps, vec![]);
+
+    // Summaries are printed for scripts given by the user.
+    assert!(script.text_summaries);
+}
+
+#[test]
+fn rejects_malformed_scripts() {
+    for src in &[
+        "put keys=0..N",
+        "insert keys=0",
+        "insert keys=0..M",
+        "insert keys=5..5",
+        "insert keys=10..2",
+        "insert count",
+        "insert count=x",
+        "insert color=red",
+        "insert duration=10",
+        "insert pause=-1s",
+        "insert dist=normal",
+    ] {
+        assert!(Script::parse(src).is_err(), "{:?} should not parse", src);
+    }
+
+    let err = Script::parse("insert\n\nget keys=3..1").unwrap_err();
+    assert!(err.starts_with("line 3:"), "{}", err);
+}
+
+#[test]
+fn checks_key_ranges() {
+    let script = Script::parse("insert keys=N/2..N").unwrap();
+    assert!(script.check(10).is_ok());
+    assert!(script.check(1).is_ok());
+    assert!(script.check(0).is_err());
+
+    let script = Script::parse(
+        "insert
+delete keys=N..N/2",
+    )
+    .unwrap();
+    assert!(script.check(0).is_err());
+    assert!(script.check(10).is_err());
+}
@@ -0,0 +1,205 @@
+use paperexp::stats::{quantile, z_score, Histogram, Summary, Trim, Welford};
+
+/// The value that a histogram with the given precision reports for `value`: the top of its bucket.
+fn bucket_top(precision: u32, value: u64) -> u64 {
+    let mut hist = Histogram::with_precision(precision);
+    hist.record(value);
+    hist.record(u64::MAX);
+    hist.value_at_quantile(0.5)
+}
+
+#[test]
+fn histogram_buckets() {
+    let precision = Histogram::DEFAULT_PRECISION;
+
+    // Exact below 2^precision.
+    for value in 0..256 {
+        assert_eq!(bucket_top(precision, value), value);
+    }
+
+    // Above that, every power of two is split into 128 buckets.
+    assert_eq!(bucket_top(precision, 256), 257);
+    assert_eq!(bucket_top(precision, 257), 257);
+    assert_eq!(bucket_top(precision, 258), 259);
+    assert_eq!(bucket_top(precision, 511), 511);
+    assert_eq!(bucket_top(precision, 512), 515);
+    assert_eq!(bucket_top(precision, 1 << 40), (1 << 40) + (1 << 33) - 1);
+
+    // The relative error is bounded.
+    for shift in 8..63 {
+        for &value in &[1u64 << shift, (1 << shift) + 12345, (2 << shift) - 1] {
+            let top = bucket_top(precision, value);
+            assert!(
+                value <= top && top - value <= value >> 7,
+                "{} -> {}",
+                value,
+                top
+            );
+        }
+    }
+
+    // One bit of precision: buckets are powers of two.
+    assert_eq!(bucket_top(1, 1), 1);
+    assert_eq!(bucket_top(1, 2), 3);
+    assert_eq!(bucket_top(1, 5), 7);
+    assert_eq!(bucket_top(1, 1 << 63), u64::MAX);
+}
+
+#[test]
+fn histogram_extremes() {
+    let mut hist = Histogram::new();
+    assert_eq!(hist.count(), 0);
+    assert_eq!(hist.min(), 0);
+    assert_eq!(hist.value_at_quantile(0.5), 0);
+
+    hist.record(u64::MAX);
+    hist.record(0);
+    assert_eq!(hist.min(), 0);
+    assert_eq!(hist.max(), u64::MAX);
+    assert_eq!(hist.value_at_quantile(0.0), 0);
+    assert_eq!(hist.value_at_quantile(0.5), 0);
+    assert_eq!(hist.value_at_quantile(1.0), u64::MAX);
+}
+
+#[test]
+fn histogram_quantiles() {
+    let mut hist = Histogram::new();
+    for value in 1..=100 {
+        hist.record(value);
+    }
+
+    assert_eq!(hist.count(), 100);
+    assert_eq!(hist.mean(), 50.5);
+    assert!((hist.sd() - 28.866_070).abs() < 1e-6);
+    assert_eq!(hist.value_at_quantile(0.0), 1);
+    assert_eq!(hist.value_at_quantile(0.5), 50);
+    assert_eq!(hist.value_at_quantile(0.9), 90);
+    assert_eq!(hist.value_at_quantile(1.0), 100);
+}
+
+#[test]
+fn histogram_merge() {
+    let (mut a, mut b, mut all) = (Histogram::new(), Histogram::new(), Histogram::new());
+    for value in 0..1000u64 {
+        let value = value * value;
+        if value % 3 == 0 {
+            a.record(value);
+        } else {
+            b.record_n(value, 2);
+        }
+        all.record_n(value, if value % 3 == 0 { 1 } else { 2 });
+    }
+
+    a.merge(&b);
+    assert_eq!(a.count(), all.count());
+    assert_eq!(a.min(), all.min());
+    assert_eq!(a.max(), all.max());
+    assert_eq!(a.mean(), all.mean());
+    assert_eq!(a.sd(), all.sd());
+    for &q in &[0.0, 0.1, 0.5, 0.99, 1.0] {
+        assert_eq!(a.value_at_quantile(q), all.value_at_quantile(q));
+    }
+
+    // Merging an empty histogram changes nothing.
+    a.merge(&Histogram::new());
+    assert_eq!(a.min(), all.min());
+    assert_eq!(a.count(), all.count());
+}
+
+#[test]
+#[should_panic]
+fn histogram_merge_different_precisions() {
+    Histogram::with_precision(4).merge(&Histogram::with_precision(5));
+}
+
+#[test]
+fn quantiles() {
+    let values: Vec<f64> = (1..=10).map(|v| v as f64).collect();
+
+    assert_eq!(quantile(&values, 0.0), 1.0);
+    assert_eq!(quantile(&values, 0.1), 1.0);
+    assert_eq!(quantile(&values, 0.11), 2.0);
+    assert_eq!(quantile(&values, 0.5), 5.0);
+    assert_eq!(quantile(&values, 0.95), 10.0);
+    assert_eq!(quantile(&values, 1.0), 10.0);
+    assert_eq!(quantile(&values, 2.0), 10.0);
+    assert!(quantile(&[], 0.5).is_nan());
+}
+
+#[test]
+fn welford() {
+    let (mut a, mut b, mut all) = (Welford::new(), Welford::new(), Welford::new());
+    for i in 0..100 {
+        // Large values with a small spread, where the naive method would cancel.
+        let v = 1e9 + i as f64;
+        if i % 2 == 0 {
+            a.record(v);
+        } else {
+            b.record(v);
+        }
+        all.record(v);
+    }
+    a.merge(&b);
+
+    assert_eq!(a.count(), 100);
+    assert!((a.mean() - (1e9 + 49.5)).abs() < 1e-6);
+    assert!((a.variance() - 841.666_667).abs() < 1e-3);
+    assert!((a.variance() - all.variance()).abs() < 1e-6);
+    assert!(Welford::new().mean().is_nan());
+}
+
+#[test]
+fn z_scores() {
+    assert!((z_score(0.95) - 1.959_964).abs() < 1e-6);
+    assert!((z_score(0.99) - 2.575_829).abs() < 1e-6);
+    assert!((z_score(0.999) - 3.290_527).abs() < 1e-6);
+    assert!((z_score(0.5) - 0.674_490).abs() < 1e-6);
+}
+
+#[test]
+fn parses_trims() {
+    assert_eq!(Trim::parse("none"), Ok(Trim::None));
+    assert_eq!(Trim::parse("pct:5"), Ok(Trim::Fraction(0.05)));
+    assert_eq!(Trim::parse("iqr"), Ok(Trim::Iqr(1.5)));
+    assert_eq!(Trim::parse("iqr:3"), Ok(Trim::Iqr(3.0)));
+
+    for s in &[
+        "", "pct", "pct:50", "pct:-1", "iqr:-1", "iqr:x", "none:1", "mad",
+    ] {
+        assert!(Trim::parse(s).is_err(), "{:?} should not parse", s);
+    }
+}
+
+#[test]
+fn trims() {
+    let trimmed = |trim: Trim, values: &[f64]| {
+        let mut values = values.to_vec();
+        trim.apply(&mut values);
+        values
+    };
+    let range = |lo: u32, hi: u32| (lo..=hi).map(f64::from).collect::<Vec<_>>();
+
+    assert_eq!(trimmed(Trim::None, &range(1, 20)), range(1, 20));
+    assert_eq!(trimmed(Trim::Fraction(0.1), &range(1, 20)), range(3, 18));
+    assert_eq!(trimmed(Trim::Fraction(0.1), &range(1, 9)), range(1, 9));
+    assert_eq!(trimmed(Trim::Fraction(0.1), &[]), vec![]);
+
+    // Quartiles 3 and 8, so the fences are -4.5 and 15.5.
+    let mut outlier = range(1, 9);
+    outlier.push(100.0);
+    assert_eq!(trimmed(Trim::Iqr(1.5), &outlier), range(1, 9));
+    assert_eq!(trimmed(Trim::Iqr(20.0), &outlier), outlier);
+}
+
+#[test]
+fn summaries() {
+    let summary = Summary::new((1..=20).rev().map(f64::from), Trim::Fraction(0.1));
+
+    assert_eq!(summary.samples, 20);
+    assert_eq!(summary.trimmed, 4);
+    assert_eq!(summary.min, 3.0);
+    assert_eq!(summary.max, 18.0);
+    assert_eq!(summary.stats.mean(), 10.5);
+    assert!(summary.ci.0 < 10.5 && 10.5 < summary.ci.1);
+    assert_eq!(summary.percentiles[0], 10.0);
+}